@@ -78,12 +78,17 @@ enum
 
 // MPI_Infos
 #define MPI_INFO_NULL 0
+#define MPI_MAX_INFO_KEY 36
+#define MPI_MAX_INFO_VAL 256
 
 // Misc constants (compatible with OpenMPI)
 #define MPI_ANY_SOURCE -1
 #define MPI_ANY_TAG -1
 #define MPI_UNDEFINED -32766
 
+// Requests
+#define MPI_REQUEST_NULL -1
+
 // Misc limits
 #define MPI_MAX_PROCESSOR_NAME 256
 #define MPI_CART_MAX_DIMENSIONS 2
@@ -104,6 +109,12 @@ enum
 #define MPI_STATUS_IGNORE ((MPI_Status*)(0))
 #define MPI_STATUSES_IGNORE ((MPI_Status*)(0))
 
+// Attribute keyvals
+#define MPI_KEYVAL_INVALID -1
+#define MPI_TAG_UB 100
+#define MPI_UNIVERSE_SIZE 101
+#define MPI_APPNUM 102
+
 // Window attributes
 #define MPI_WIN_BASE 1
 #define MPI_WIN_SIZE 2
@@ -153,12 +164,27 @@ typedef int MPI_Win;
 typedef ptrdiff_t MPI_Aint;
 typedef int MPI_Fint;
 typedef long MPI_Offset;
+typedef long long MPI_Count;
 
 /*
  * User-defined functions
  */
 typedef void(MPI_User_function)(void*, void*, int*, MPI_Datatype*);
 
+typedef int(MPI_Comm_copy_attr_function)(MPI_Comm, int, void*, void*, void*, int*);
+typedef int(MPI_Comm_delete_attr_function)(MPI_Comm, int, void*, void*);
+typedef int(MPI_Type_copy_attr_function)(MPI_Datatype, int, void*, void*, void*, int*);
+typedef int(MPI_Type_delete_attr_function)(MPI_Datatype, int, void*, void*);
+
+// Predefined attribute callbacks
+// Like MPI_IN_PLACE these are special function pointers that the embedder recognizes.
+#define MPI_COMM_NULL_COPY_FN ((MPI_Comm_copy_attr_function*)0)
+#define MPI_COMM_DUP_FN ((MPI_Comm_copy_attr_function*)-1)
+#define MPI_COMM_NULL_DELETE_FN ((MPI_Comm_delete_attr_function*)0)
+#define MPI_TYPE_NULL_COPY_FN ((MPI_Type_copy_attr_function*)0)
+#define MPI_TYPE_DUP_FN ((MPI_Type_copy_attr_function*)-1)
+#define MPI_TYPE_NULL_DELETE_FN ((MPI_Type_delete_attr_function*)0)
+
 /*
  * User-facing functions
  */
@@ -188,6 +214,13 @@ int MPI_Allreduce(const void* sendbuf,
                   MPI_Op op,
                   MPI_Comm comm);
 
+int MPI_Allreduce_c(const void* sendbuf,
+                    void* recvbuf,
+                    MPI_Count count,
+                    MPI_Datatype datatype,
+                    MPI_Op op,
+                    MPI_Comm comm);
+
 int MPI_Alltoall(const void* sendbuf,
                  int sendcount,
                  MPI_Datatype sendtype,
@@ -216,6 +249,12 @@ int MPI_Bcast(void* buffer,
               int root,
               MPI_Comm comm);
 
+int MPI_Bcast_c(void* buffer,
+                MPI_Count count,
+                MPI_Datatype datatype,
+                int root,
+                MPI_Comm comm);
+
 int MPI_Cart_create(MPI_Comm old_comm,
                     int ndims,
                     const int dims[],
@@ -243,21 +282,37 @@ int MPI_Comm_compare(MPI_Comm comm1, MPI_Comm comm2, int *result);
 
 int MPI_Comm_create(MPI_Comm comm, MPI_Group group, MPI_Comm* newcomm);
 
+int MPI_Comm_create_keyval(MPI_Comm_copy_attr_function* comm_copy_attr_fn,
+                           MPI_Comm_delete_attr_function* comm_delete_attr_fn,
+                           int* comm_keyval,
+                           void* extra_state);
+
 int MPI_Comm_create_group(MPI_Comm comm,
                           MPI_Group group,
                           int tag,
                           MPI_Comm* newcomm);
 
+int MPI_Comm_delete_attr(MPI_Comm comm, int comm_keyval);
+
 int MPI_Comm_dup(MPI_Comm comm, MPI_Comm* newcomm);
 
 MPI_Comm MPI_Comm_f2c(MPI_Fint comm);
 
 int MPI_Comm_free(MPI_Comm* comm);
 
+int MPI_Comm_free_keyval(int* comm_keyval);
+
+int MPI_Comm_get_attr(MPI_Comm comm,
+                      int comm_keyval,
+                      void* attribute_val,
+                      int* flag);
+
 int MPI_Comm_group(MPI_Comm comm, MPI_Group* group);
 
 int MPI_Comm_rank(MPI_Comm comm, int* rank);
 
+int MPI_Comm_set_attr(MPI_Comm comm, int comm_keyval, void* attribute_val);
+
 int MPI_Comm_size(MPI_Comm comm, int* size);
 
 int MPI_Comm_split(MPI_Comm comm, int color, int key, MPI_Comm* newcomm);
@@ -306,6 +361,10 @@ int MPI_Get_count(const MPI_Status* status,
                   MPI_Datatype datatype,
                   int* count);
 
+int MPI_Get_count_c(const MPI_Status* status,
+                    MPI_Datatype datatype,
+                    MPI_Count* count);
+
 int MPI_Get_processor_name(char* name, int* resultlen);
 
 int MPI_Get_version(int* version, int* subversion);
@@ -321,6 +380,26 @@ int MPI_Group_range_excl(MPI_Group group, int n, int ranges[][3], MPI_Group *new
 
 int MPI_Group_range_incl(MPI_Group group, int n, int ranges[][3], MPI_Group *newgroup);
 
+int MPI_Info_create(MPI_Info* info);
+
+int MPI_Info_delete(MPI_Info info, const char* key);
+
+int MPI_Info_dup(MPI_Info info, MPI_Info* newinfo);
+
+int MPI_Info_free(MPI_Info* info);
+
+int MPI_Info_get(MPI_Info info,
+                 const char* key,
+                 int valuelen,
+                 char* value,
+                 int* flag);
+
+int MPI_Info_get_nkeys(MPI_Info info, int* nkeys);
+
+int MPI_Info_get_nthkey(MPI_Info info, int n, char* key);
+
+int MPI_Info_set(MPI_Info info, const char* key, const char* value);
+
 int MPI_Init(int* argc, char*** argv);
 
 int MPI_Initialized(int* flag);
@@ -335,6 +414,14 @@ int MPI_Irecv(void* buf,
               MPI_Comm comm,
               MPI_Request* request);
 
+int MPI_Irecv_c(void* buf,
+                MPI_Count count,
+                MPI_Datatype datatype,
+                int source,
+                int tag,
+                MPI_Comm comm,
+                MPI_Request* request);
+
 int MPI_Isend(const void* buf,
               int count,
               MPI_Datatype datatype,
@@ -343,10 +430,44 @@ int MPI_Isend(const void* buf,
               MPI_Comm comm,
               MPI_Request* request);
 
+int MPI_Isend_c(const void* buf,
+                MPI_Count count,
+                MPI_Datatype datatype,
+                int dest,
+                int tag,
+                MPI_Comm comm,
+                MPI_Request* request);
+
 int MPI_Op_create(MPI_User_function* user_fn, int commute, MPI_Op* op);
 
 int MPI_Op_free(MPI_Op* op);
 
+int MPI_Pack(const void* inbuf,
+             int incount,
+             MPI_Datatype datatype,
+             void* outbuf,
+             int outsize,
+             int* position,
+             MPI_Comm comm);
+
+int MPI_Pack_external(const char datarep[],
+                      const void* inbuf,
+                      int incount,
+                      MPI_Datatype datatype,
+                      void* outbuf,
+                      MPI_Aint outsize,
+                      MPI_Aint* position);
+
+int MPI_Pack_external_size(const char datarep[],
+                           int incount,
+                           MPI_Datatype datatype,
+                           MPI_Aint* size);
+
+int MPI_Pack_size(int incount,
+                  MPI_Datatype datatype,
+                  MPI_Comm comm,
+                  int* size);
+
 int MPI_Probe(int source, int tag, MPI_Comm comm, MPI_Status* status);
 
 int MPI_Put(const void* origin_addr,
@@ -368,6 +489,14 @@ int MPI_Recv(void* buf,
              MPI_Comm comm,
              MPI_Status* status);
 
+int MPI_Recv_c(void* buf,
+               MPI_Count count,
+               MPI_Datatype datatype,
+               int source,
+               int tag,
+               MPI_Comm comm,
+               MPI_Status* status);
+
 int MPI_Reduce(const void* sendbuf,
                void* recvbuf,
                int count,
@@ -376,6 +505,14 @@ int MPI_Reduce(const void* sendbuf,
                int root,
                MPI_Comm comm);
 
+int MPI_Reduce_c(const void* sendbuf,
+                 void* recvbuf,
+                 MPI_Count count,
+                 MPI_Datatype datatype,
+                 MPI_Op op,
+                 int root,
+                 MPI_Comm comm);
+
 int MPI_Reduce_scatter(const void* sendbuf,
                        void* recvbuf,
                        const int* recvcounts,
@@ -415,6 +552,13 @@ int MPI_Send(const void* buf,
              int tag,
              MPI_Comm comm);
 
+int MPI_Send_c(const void* buf,
+               MPI_Count count,
+               MPI_Datatype datatype,
+               int dest,
+               int tag,
+               MPI_Comm comm);
+
 int MPI_Sendrecv(const void* sendbuf,
                  int sendcount,
                  MPI_Datatype sendtype,
@@ -428,16 +572,56 @@ int MPI_Sendrecv(const void* sendbuf,
                  MPI_Comm comm,
                  MPI_Status* status);
 
+int MPI_Testany(int count,
+                MPI_Request array_of_requests[],
+                int* index,
+                int* flag,
+                MPI_Status* status);
+
 int MPI_Type_commit(MPI_Datatype* type);
 
 int MPI_Type_contiguous(int count,
                         MPI_Datatype oldtype,
                         MPI_Datatype* newtype);
 
+int MPI_Type_create_keyval(MPI_Type_copy_attr_function* type_copy_attr_fn,
+                           MPI_Type_delete_attr_function* type_delete_attr_fn,
+                           int* type_keyval,
+                           void* extra_state);
+
+int MPI_Type_delete_attr(MPI_Datatype datatype, int type_keyval);
+
 int MPI_Type_free(MPI_Datatype* datatype);
 
+int MPI_Type_free_keyval(int* type_keyval);
+
+int MPI_Type_get_attr(MPI_Datatype datatype,
+                      int type_keyval,
+                      void* attribute_val,
+                      int* flag);
+
+int MPI_Type_set_attr(MPI_Datatype datatype, int type_keyval, void* attribute_val);
+
 int MPI_Type_size(MPI_Datatype type, int* size);
 
+int MPI_Type_size_c(MPI_Datatype type, MPI_Count* size);
+
+int MPI_Unpack(const void* inbuf,
+               int insize,
+               int* position,
+               void* outbuf,
+               int outcount,
+               MPI_Datatype datatype,
+               MPI_Comm comm);
+
+int MPI_Unpack_external(const char datarep[],
+                        const void* inbuf,
+                        MPI_Aint insize,
+                        MPI_Aint* position,
+                        void* outbuf,
+                        int outcount,
+                        MPI_Datatype datatype);
+
 int MPI_Wait(MPI_Request* request, MPI_Status* status);
 
 int MPI_Waitall(int count,
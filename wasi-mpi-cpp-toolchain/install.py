@@ -119,6 +119,29 @@ def download_to_install_directory(
         os.path.join(install_directory, "share", "wasi-sysroot", "include"),
     )
 
+    build_exceptions_library(install_directory)
+
+
+def build_exceptions_library(install_directory: str):
+    """Compiles the guest side of the C++ exception support into the sysroot as libwasi-mpi-exceptions.a"""
+    sysroot = os.path.join(install_directory, "share", "wasi-sysroot")
+    object_path = os.path.join(install_directory, "wasi-mpi-exceptions.o")
+
+    Popen([
+        os.path.join(install_directory, "bin", "clang++"),
+        "--sysroot=" + sysroot,
+        "-O2",
+        "-c", os.path.join(".", "src", "exceptions.cpp"),
+        "-o", object_path,
+    ]).communicate()
+    Popen([
+        os.path.join(install_directory, "bin", "llvm-ar"),
+        "rcs",
+        os.path.join(sysroot, "lib", "wasm32-wasi", "libwasi-mpi-exceptions.a"),
+        object_path,
+    ]).communicate()
+    os.remove(object_path)
+
 
 def main():
     if len(sys.argv) <= 1:
@@ -0,0 +1,63 @@
+// Guest side of the C++ exception support of WASI MPI, linked as libwasi-mpi-exceptions.a.
+//
+// Modules compiled with `-fexceptions -mllvm -enable-emscripten-cxx-exceptions` check `__THREW__` after
+// every call that may throw. The embedder sets it through `setThrew` when it catches an exception, and
+// asks `__wasi_mpi_can_catch` whether a catch clause matches the thrown type. `stackSave` and
+// `stackRestore` let it reset the stack pointer of the frames it unwinds. Everything else, e.g.
+// `__cxa_throw` and `__cxa_begin_catch`, is implemented by the embedder.
+#include <stdint.h>
+#include <typeinfo>
+
+extern "C" {
+
+__attribute__((weak)) uintptr_t __THREW__ = 0;
+__attribute__((weak)) int __threwValue = 0;
+
+__attribute__((weak, export_name("setThrew")))
+void setThrew(uintptr_t threw, int value) {
+    if (__THREW__ == 0) {
+        __THREW__ = threw;
+        __threwValue = value;
+    }
+}
+
+}
+
+// The stack pointer is a wasm global that C++ can't name, so these are written in assembly
+__asm__(
+    ".globaltype __stack_pointer, i32\n"
+
+    ".globl stackSave\n"
+    ".export_name stackSave, stackSave\n"
+    "stackSave:\n"
+    "    .functype stackSave () -> (i32)\n"
+    "    global.get __stack_pointer\n"
+    "    end_function\n"
+
+    ".globl stackRestore\n"
+    ".export_name stackRestore, stackRestore\n"
+    "stackRestore:\n"
+    "    .functype stackRestore (i32) -> ()\n"
+    "    local.get 0\n"
+    "    global.set __stack_pointer\n"
+    "    end_function\n"
+);
+
+namespace __cxxabiv1 {
+
+// Mirrors the declaration in libc++abi's private_typeinfo.h, the base of every type_info that can be
+// thrown. It is only called through its vtable, which libc++abi provides.
+class __shim_type_info : public std::type_info {
+public:
+    virtual ~__shim_type_info();
+    virtual void noop1() const;
+    virtual void noop2() const;
+    virtual bool can_catch(const __shim_type_info* thrown_type, void*& adjusted_ptr) const = 0;
+};
+
+}
+
+extern "C" __attribute__((export_name("__wasi_mpi_can_catch")))
+int __wasi_mpi_can_catch(const __cxxabiv1::__shim_type_info* catch_type, const __cxxabiv1::__shim_type_info* thrown_type, void** adjusted_ptr) {
+    return catch_type->can_catch(thrown_type, *adjusted_ptr) ? 1 : 0;
+}
@@ -0,0 +1,80 @@
+// Measures the overhead of translating guest MPI handles to host handles compared to calling
+// mpi_sys directly with host handles. Run as a single MPI process:
+//
+//     cargo bench --no-run && mpirun -np 1 target/release/deps/translation-*
+use std::ptr::null_mut;
+use std::time::Instant;
+
+use mpi_sys;
+
+use wasm_mpi_rs::{Env, MpiComm, MpiDatatype, MPI_COMM_WORLD, MPI_DOUBLE};
+
+const ITERATIONS: u32 = 10_000_000;
+
+
+fn report(name: &str, time_ns: u128, baseline_ns: u128) {
+    let per_call = time_ns as f64 / ITERATIONS as f64;
+    let overhead = (time_ns as f64 - baseline_ns as f64) / ITERATIONS as f64;
+    println!("{:<32} {:>8.2} ns/call {:>+8.2} ns/call", name, per_call, overhead);
+}
+
+
+fn main() {
+    unsafe {
+        mpi_sys::MPI_Init(null_mut(), null_mut());
+    }
+
+    let env = Env::new();
+    let mut rank: i32 = -1;
+    let mut size: i32 = -1;
+
+    // Baseline: host handles are already known
+    let time_before = Instant::now();
+    for _ in 0..ITERATIONS {
+        unsafe {
+            mpi_sys::MPI_Comm_rank(mpi_sys::RSMPI_COMM_WORLD, &mut rank);
+        }
+    }
+    let native = (Instant::now() - time_before).as_nanos();
+
+    // One translated handle, like MPI_Comm_rank in funcs.rs
+    let time_before = Instant::now();
+    for _ in 0..ITERATIONS {
+        let MpiComm(host_comm) = env.mpi_comms.get(MPI_COMM_WORLD).expect("invalid comm");
+        unsafe {
+            mpi_sys::MPI_Comm_rank(host_comm, &mut rank);
+        }
+    }
+    let translated_comm = (Instant::now() - time_before).as_nanos();
+
+    // Two translated handles, like the datatype and communicator lookups of MPI_Send
+    let time_before = Instant::now();
+    for _ in 0..ITERATIONS {
+        let MpiDatatype(host_datatype) = env.mpi_datatypes.get(MPI_DOUBLE).expect("invalid datatype");
+        let MpiComm(host_comm) = env.mpi_comms.get(MPI_COMM_WORLD).expect("invalid comm");
+        unsafe {
+            mpi_sys::MPI_Type_size(host_datatype, &mut size);
+            mpi_sys::MPI_Comm_rank(host_comm, &mut rank);
+        }
+    }
+    let translated_send = (Instant::now() - time_before).as_nanos();
+
+    let time_before = Instant::now();
+    for _ in 0..ITERATIONS {
+        unsafe {
+            mpi_sys::MPI_Type_size(mpi_sys::RSMPI_DOUBLE, &mut size);
+            mpi_sys::MPI_Comm_rank(mpi_sys::RSMPI_COMM_WORLD, &mut rank);
+        }
+    }
+    let native_send = (Instant::now() - time_before).as_nanos();
+
+    println!("{:<32} {:>16} {:>16}", "", "time", "overhead");
+    report("native MPI_Comm_rank", native, native);
+    report("translated MPI_Comm_rank", translated_comm, native);
+    report("native comm + datatype", native_send, native_send);
+    report("translated comm + datatype", translated_send, native_send);
+
+    unsafe {
+        mpi_sys::MPI_Finalize();
+    }
+}
@@ -9,7 +9,7 @@ run_hpcg_benchmark_native () {
 
 
 run_hpcg_benchmark_wasm () {
-    mpirun --allow-run-as-root --oversubscribe -np $1 ../target/release/embedder ../examples/xhpcg.wasm >& $2
+    mpirun --allow-run-as-root --oversubscribe -np $1 ../target/release/embedder --output-ranks 0 ../examples/xhpcg.wasm >& $2
     sleep 1
 }
 
@@ -19,7 +19,7 @@ run_imb_benchmark_native () {
 }
 
 run_imb_benchmark_wasm () {
-    mpirun --allow-run-as-root --oversubscribe -np $1 ../target/release/embedder ../examples/imb.wasm -npmin $1 Sendrecv Allreduce Reduce Allgather Gather Scatter Alltoall Bcast 1>$2 2>error
+    mpirun --allow-run-as-root --oversubscribe -np $1 ../target/release/embedder --output-ranks 0 ../examples/imb.wasm -npmin $1 Sendrecv Allreduce Reduce Allgather Gather Scatter Alltoall Bcast 1>$2 2>error
     sleep 1
 }
 
@@ -29,7 +29,7 @@ run_is_benchmark_native () {
 }
 
 run_is_benchmark_wasm () {
-    mpirun --allow-run-as-root --oversubscribe -np $1 ../target/release/embedder ../examples/is.C.x.wasm 1>$2 2>error
+    mpirun --allow-run-as-root --oversubscribe -np $1 ../target/release/embedder --output-ranks 0 ../examples/is.C.x.wasm 1>$2 2>error
     sleep 1
 }
 
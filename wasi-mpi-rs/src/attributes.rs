@@ -0,0 +1,305 @@
+use std::collections::{BTreeMap, HashMap};
+
+use libc::c_void;
+use wasmer::{Val, WasmPtr};
+
+use crate::Env;
+use crate::consts::{MPI_APPNUM, MPI_DUP_FN, MPI_NULL_COPY_FN, MPI_NULL_DELETE_FN, MPI_SUCCESS, MPI_TAG_UB, MPI_UNIVERSE_SIZE};
+
+/// User-defined keyvals are numbered starting from here so that they never collide with the
+/// predefined attribute keyvals in consts.rs
+const FIRST_USER_KEYVAL: i32 = 1024;
+
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum MpiObjectKind {
+    Comm,
+    Datatype,
+}
+
+
+/// A keyval created by the guest. The callbacks are indices into the guest's function table and
+/// `extra_state` is a guest pointer that is passed back to them verbatim.
+#[derive(Clone, Copy)]
+pub struct MpiKeyval {
+    pub kind: MpiObjectKind,
+    pub copy_fn: i32,
+    pub delete_fn: i32,
+    pub extra_state: i32,
+    pub freed: bool,
+}
+
+
+/// Attributes cached on guest handles. Attribute values are guest pointers (`void*`), so they are
+/// stored here instead of with the host MPI library which could not invoke the guest's callbacks.
+pub struct MpiAttributes {
+    next_keyval: i32,
+    keyvals: HashMap<i32, MpiKeyval>,
+    values: HashMap<(MpiObjectKind, i32), BTreeMap<i32, i32>>,
+    predefined_values: HashMap<i32, i32>,
+}
+impl MpiAttributes {
+    pub fn new() -> MpiAttributes {
+        MpiAttributes{
+            next_keyval: FIRST_USER_KEYVAL,
+            keyvals: HashMap::new(),
+            values: HashMap::new(),
+            predefined_values: HashMap::new(),
+        }
+    }
+
+    pub fn create_keyval(&mut self, kind: MpiObjectKind, copy_fn: i32, delete_fn: i32, extra_state: i32) -> i32 {
+        let keyval_id = self.next_keyval;
+        self.next_keyval += 1;
+
+        self.keyvals.insert(keyval_id, MpiKeyval{
+            kind,
+            copy_fn,
+            delete_fn,
+            extra_state,
+            freed: false,
+        });
+        keyval_id
+    }
+
+    /// Marks a keyval as freed. Attributes that are still cached with it keep their callbacks until
+    /// they are deleted, as required by the MPI standard.
+    pub fn free_keyval(&mut self, keyval_id: i32) -> () {
+        if let Some(keyval) = self.keyvals.get_mut(&keyval_id) {
+            keyval.freed = true;
+        }
+    }
+
+    pub fn keyval(&self, keyval_id: i32) -> Option<MpiKeyval> {
+        self.keyvals.get(&keyval_id).copied()
+    }
+
+    pub fn get(&self, kind: MpiObjectKind, object_id: i32, keyval_id: i32) -> Option<i32> {
+        self.values.get(&(kind, object_id))?.get(&keyval_id).copied()
+    }
+
+    pub fn set(&mut self, kind: MpiObjectKind, object_id: i32, keyval_id: i32, value: i32) -> Option<i32> {
+        self.values.entry((kind, object_id)).or_insert_with(BTreeMap::new).insert(keyval_id, value)
+    }
+
+    pub fn delete(&mut self, kind: MpiObjectKind, object_id: i32, keyval_id: i32) -> Option<i32> {
+        self.values.get_mut(&(kind, object_id))?.remove(&keyval_id)
+    }
+
+    /// Returns all attributes cached on an object in the order of their keyvals.
+    pub fn attributes(&self, kind: MpiObjectKind, object_id: i32) -> Vec<(i32, i32)> {
+        match self.values.get(&(kind, object_id)) {
+            Some(values) => values.iter().map(|(keyval, value)| (*keyval, *value)).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+
+/// Maps the predefined attribute keyvals of the guest to the ones of the host MPI library.
+pub fn host_predefined_keyval(keyval_id: i32) -> Option<i32> {
+    match keyval_id {
+        MPI_TAG_UB => Some(mpi_sys::MPI_TAG_UB as i32),
+        MPI_UNIVERSE_SIZE => Some(mpi_sys::MPI_UNIVERSE_SIZE as i32),
+        MPI_APPNUM => Some(mpi_sys::MPI_APPNUM as i32),
+        _ => None,
+    }
+}
+
+
+/// Looks up a predefined attribute on a host communicator and copies its value into guest memory.
+/// Returns the guest pointer to the value, or `None` if the host does not set the attribute.
+pub fn get_predefined_attr(env: &Env, host_comm: mpi_sys::MPI_Comm, keyval_id: i32, host_keyval: i32) -> Option<i32> {
+    let mut host_value: *mut i32 = std::ptr::null_mut();
+    let mut flag: i32 = 0;
+    unsafe {
+        mpi_sys::MPI_Comm_get_attr(host_comm, host_keyval, &mut host_value as *mut *mut i32 as *mut c_void, &mut flag);
+    }
+    if flag == 0 {
+        return None;
+    }
+    let value = unsafe { *host_value };
+
+    let mut mpi_attributes = env.mpi_attributes.write().unwrap();
+    let guest_value = match mpi_attributes.predefined_values.get(&keyval_id) {
+        Some(guest_value) => *guest_value,
+        None => {
+            let malloc = env.malloc.get_ref().expect("Export malloc() to use predefined attributes");
+            let guest_value = malloc.call(4).expect("error calling malloc() for predefined attribute").offset() as i32;
+            mpi_attributes.predefined_values.insert(keyval_id, guest_value);
+            guest_value
+        }
+    };
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in get_predefined_attr");
+    let value_ptr: WasmPtr<i32> = WasmPtr::new(guest_value as u32);
+    value_ptr.deref(memory).expect("out-of-bounds ptr in get_predefined_attr").set(value);
+    Some(guest_value)
+}
+
+
+/// Calls a function from the guest's `__indirect_function_table` that returns an `int`.
+pub fn call_guest_function(env: &Env, function_index: i32, params: &[Val]) -> i32 {
+    let table = env.function_table.get_ref().expect("Export __indirect_function_table to use attribute callbacks");
+    let function = match table.get(function_index as u32) {
+        Some(Val::FuncRef(Some(function))) => function,
+        _ => panic!("invalid attribute callback {}", function_index),
+    };
+
+    let results = function.call(params).expect("error calling attribute callback");
+    results[0].unwrap_i32()
+}
+
+
+/// Invokes the delete callback of `keyval_id` for an attribute that is removed from an object.
+pub fn call_delete_fn(env: &Env, keyval: &MpiKeyval, object_id: i32, keyval_id: i32, value: i32) -> i32 {
+    if keyval.delete_fn == MPI_NULL_DELETE_FN {
+        return MPI_SUCCESS;
+    }
+
+    call_guest_function(env, keyval.delete_fn, &[
+        Val::I32(object_id),
+        Val::I32(keyval_id),
+        Val::I32(value),
+        Val::I32(keyval.extra_state),
+    ])
+}
+
+
+/// Invokes the copy callback of `keyval_id` when an object is duplicated. Returns the value for the
+/// new object, or `None` if the attribute should not be copied. A failing callback fails the
+/// duplication with its error code.
+pub fn call_copy_fn(env: &Env, keyval: &MpiKeyval, object_id: i32, keyval_id: i32, value: i32) -> Result<Option<i32>, i32> {
+    match keyval.copy_fn {
+        MPI_NULL_COPY_FN => Ok(None),
+        MPI_DUP_FN => Ok(Some(value)),
+        copy_fn => {
+            // The callback writes its results through pointers, so they need to live in guest memory
+            let malloc = env.malloc.get_ref().expect("Export malloc() to use attribute copy callbacks");
+            let free = env.free.get_ref().expect("Export free() to use attribute copy callbacks");
+            let out_ptr = malloc.call(8).expect("error calling malloc() for attribute copy callback");
+            let flag_ptr: WasmPtr<i32> = WasmPtr::new(out_ptr.offset() + 4);
+
+            let memory = env.memory.get_ref().expect("uninitialized memory in call_copy_fn");
+            flag_ptr.deref(memory).expect("out-of-bounds ptr in call_copy_fn").set(0);
+
+            let ret = call_guest_function(env, copy_fn, &[
+                Val::I32(object_id),
+                Val::I32(keyval_id),
+                Val::I32(keyval.extra_state),
+                Val::I32(value),
+                Val::I32(out_ptr.offset() as i32),
+                Val::I32(flag_ptr.offset() as i32),
+            ]);
+
+            let out_value_ptr: WasmPtr<i32> = WasmPtr::new(out_ptr.offset());
+            let new_value = out_value_ptr.deref(memory).expect("out-of-bounds ptr in call_copy_fn").get();
+            let flag = flag_ptr.deref(memory).expect("out-of-bounds ptr in call_copy_fn").get();
+            free.call(out_ptr).expect("error calling free() for attribute copy callback");
+
+            if ret != MPI_SUCCESS {
+                return Err(ret);
+            }
+            Ok(if flag != 0 { Some(new_value) } else { None })
+        }
+    }
+}
+
+
+/// Runs the delete callbacks of every attribute cached on an object that is being freed.
+pub fn delete_all_attrs(env: &Env, kind: MpiObjectKind, object_id: i32) -> i32 {
+    let attributes = env.mpi_attributes.read().unwrap().attributes(kind, object_id);
+
+    for (keyval_id, value) in attributes {
+        let keyval = env.mpi_attributes.read().unwrap().keyval(keyval_id).expect("invalid keyval");
+        let ret = call_delete_fn(env, &keyval, object_id, keyval_id, value);
+        if ret != MPI_SUCCESS {
+            return ret;
+        }
+        env.mpi_attributes.write().unwrap().delete(kind, object_id, keyval_id);
+    }
+    MPI_SUCCESS
+}
+
+
+pub fn create_keyval(env: &Env, kind: MpiObjectKind, copy_fn: i32, delete_fn: i32, keyval: WasmPtr<i32>, extra_state: i32) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in create_keyval");
+    let keyval_ptr = keyval.deref(memory).expect("out-of-bounds ptr in create_keyval");
+
+    let keyval_id = env.mpi_attributes.write().unwrap().create_keyval(kind, copy_fn, delete_fn, extra_state);
+    keyval_ptr.set(keyval_id);
+    MPI_SUCCESS
+}
+
+
+pub fn free_keyval(env: &Env, kind: MpiObjectKind, keyval: WasmPtr<i32>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in free_keyval");
+    let keyval_ptr = keyval.deref(memory).expect("out-of-bounds ptr in free_keyval");
+    let keyval_id = keyval_ptr.get();
+
+    let mut mpi_attributes = env.mpi_attributes.write().unwrap();
+    match mpi_attributes.keyval(keyval_id) {
+        Some(keyval) if keyval.kind == kind && !keyval.freed => mpi_attributes.free_keyval(keyval_id),
+        _ => panic!("invalid keyval {}", keyval_id),
+    }
+
+    keyval_ptr.set(crate::consts::MPI_KEYVAL_INVALID);
+    MPI_SUCCESS
+}
+
+
+pub fn set_attr(env: &Env, kind: MpiObjectKind, object_id: i32, keyval_id: i32, value: i32) -> i32 {
+    let keyval = env.mpi_attributes.read().unwrap().keyval(keyval_id);
+    let keyval = match keyval {
+        Some(keyval) if keyval.kind == kind && !keyval.freed => keyval,
+        _ => panic!("invalid keyval {}", keyval_id),
+    };
+
+    // Replacing an attribute deletes the old value first
+    let old_value = env.mpi_attributes.read().unwrap().get(kind, object_id, keyval_id);
+    if let Some(old_value) = old_value {
+        let ret = call_delete_fn(env, &keyval, object_id, keyval_id, old_value);
+        if ret != MPI_SUCCESS {
+            return ret;
+        }
+    }
+
+    env.mpi_attributes.write().unwrap().set(kind, object_id, keyval_id, value);
+    MPI_SUCCESS
+}
+
+
+pub fn get_attr(env: &Env, kind: MpiObjectKind, object_id: i32, keyval_id: i32, attribute_val: WasmPtr<WasmPtr<u8>>, flag: WasmPtr<i32>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in get_attr");
+    let attribute_val_ptr = attribute_val.deref(memory).expect("out-of-bounds ptr in get_attr");
+    let flag_ptr = flag.deref(memory).expect("out-of-bounds ptr in get_attr");
+
+    match env.mpi_attributes.read().unwrap().get(kind, object_id, keyval_id) {
+        Some(value) => {
+            attribute_val_ptr.set(WasmPtr::new(value as u32));
+            flag_ptr.set(1);
+        }
+        None => flag_ptr.set(0),
+    }
+    MPI_SUCCESS
+}
+
+
+pub fn delete_attr(env: &Env, kind: MpiObjectKind, object_id: i32, keyval_id: i32) -> i32 {
+    let keyval = env.mpi_attributes.read().unwrap().keyval(keyval_id);
+    let keyval = match keyval {
+        Some(keyval) if keyval.kind == kind => keyval,
+        _ => panic!("invalid keyval {}", keyval_id),
+    };
+
+    let value = env.mpi_attributes.read().unwrap().get(kind, object_id, keyval_id);
+    let value = value.unwrap_or_else(|| panic!("no attribute for keyval {}", keyval_id));
+
+    let ret = call_delete_fn(env, &keyval, object_id, keyval_id, value);
+    if ret != MPI_SUCCESS {
+        return ret;
+    }
+
+    env.mpi_attributes.write().unwrap().delete(kind, object_id, keyval_id);
+    MPI_SUCCESS
+}
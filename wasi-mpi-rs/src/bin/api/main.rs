@@ -38,8 +38,13 @@ struct Job {
     pub path: String,
     pub argv: Vec<String>,
     pub world_size: i32,
+    #[serde(default)]
+    pub limits: JobLimits,
     pub state: JobState,
     pub callback: String,
+    /// Why the job failed, as reported by the first failing rank
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
 }
 impl Job {
     fn new(job_post: JobPost, uuid: Uuid, callback: String) -> Self {Job{
@@ -47,23 +52,57 @@ impl Job {
         path: job_post.path,
         argv: job_post.argv,
         world_size: job_post.world_size,
+        limits: job_post.limits,
         state: JobState::Submitted,
         callback: callback,
+        reason: None,
     }}
 }
 
 
+/// Resource limits of every rank of a job, passed on to the embedder
+#[derive(Clone, Default, Deserialize, Serialize)]
+struct JobLimits {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_limit_mb: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fuel: Option<u64>,
+    /// Wall-clock limit in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+impl JobLimits {
+    fn embedder_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(memory_limit_mb) = self.memory_limit_mb {
+            args.extend(vec!["--memory-limit-mb".to_string(), memory_limit_mb.to_string()]);
+        }
+        if let Some(fuel) = self.fuel {
+            args.extend(vec!["--fuel".to_string(), fuel.to_string()]);
+        }
+        if let Some(timeout) = self.timeout {
+            args.extend(vec!["--timeout".to_string(), timeout.to_string()]);
+        }
+        args
+    }
+}
+
+
 #[derive(Clone, Deserialize, Serialize)]
 struct JobPost {
     pub path: String,
     pub argv: Vec<String>,
     pub world_size: i32,
+    #[serde(default)]
+    pub limits: JobLimits,
 }
 
 
 #[derive(Clone, Deserialize, Serialize)]
 struct JobCallback {
     pub state: JobState,
+    #[serde(default)]
+    pub reason: Option<String>,
 }
 
 
@@ -92,11 +131,15 @@ fn mpi_handler(job: &Job) -> Result<()> {
     unsafe {
         let command = CString::new("target/release/embedder").unwrap();
 
+        let limit_args = job.limits.embedder_args();
         let mut argv = vec![
             "--callback",
             job.callback.as_str(),
-            job.path.as_str(),
         ];
+        argv.extend(limit_args.iter().map(
+            |x| x.as_str()
+        ));
+        argv.push(job.path.as_str());
         argv.extend(job.argv.iter().map(
             |x| x.as_str()
         ));
@@ -186,7 +229,12 @@ async fn put_jobs_callback(uuid: web::Path<Uuid>, job_callback: web::Json<JobCal
         if j.state == job_callback.state {
             HttpResponse::NoContent().body("")
         } else {
-            if j.state == JobState::Failed || j.state == JobState::Completed {
+            if j.state == JobState::Completed && job_callback.state == JobState::Failed {
+                // Every rank reports its own state, a job only completed if no rank failed
+                j.state = JobState::Failed;
+                j.reason = job_callback.reason.clone();
+                HttpResponse::NoContent().body("")
+            } else if j.state == JobState::Failed || j.state == JobState::Completed {
                 HttpResponse::BadRequest().json("Job state is final")
             } else {
                 if job_callback.state == JobState::Failed || job_callback.state == JobState::Completed {
@@ -194,6 +242,7 @@ async fn put_jobs_callback(uuid: web::Path<Uuid>, job_callback: web::Json<JobCal
                 }
 
                 j.state = job_callback.state;
+                j.reason = job_callback.reason.clone();
                 HttpResponse::NoContent().body("")
             }
         }
@@ -0,0 +1,194 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Error, Result};
+use wasmer::{CompilerConfig, CpuFeature, Engine, Target, Triple};
+use wasmer::wasmparser::Operator;
+#[cfg(feature = "cranelift")]
+use wasmer_compiler_cranelift::Cranelift;
+#[cfg(feature = "llvm")]
+use wasmer_compiler_llvm::LLVM;
+#[cfg(feature = "singlepass")]
+use wasmer_compiler_singlepass::Singlepass;
+#[cfg(feature = "dylib")]
+use wasmer_engine_dylib::{Dylib, DylibArtifact};
+#[cfg(feature = "universal")]
+use wasmer_engine_universal::{Universal, UniversalArtifact};
+use wasmer_middlewares::Metering;
+
+// The defaults are the first backends that are enabled, preferring LLVM and Dylib
+#[cfg(feature = "llvm")]
+pub const DEFAULT_COMPILER: &str = "llvm";
+#[cfg(all(not(feature = "llvm"), feature = "cranelift"))]
+pub const DEFAULT_COMPILER: &str = "cranelift";
+#[cfg(all(not(feature = "llvm"), not(feature = "cranelift")))]
+pub const DEFAULT_COMPILER: &str = "singlepass";
+
+#[cfg(feature = "dylib")]
+pub const DEFAULT_ENGINE: &str = "dylib";
+#[cfg(not(feature = "dylib"))]
+pub const DEFAULT_ENGINE: &str = "universal";
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompilerKind {
+    Cranelift,
+    LLVM,
+    Singlepass,
+}
+impl CompilerKind {
+    pub const VARIANTS: &'static [&'static str] = &["cranelift", "llvm", "singlepass"];
+
+    fn config(self) -> Result<Box<dyn CompilerConfig>> {
+        match self {
+            #[cfg(feature = "cranelift")]
+            CompilerKind::Cranelift => Ok(Box::new(Cranelift::new())),
+            #[cfg(feature = "llvm")]
+            CompilerKind::LLVM => Ok(Box::new(LLVM::new())),
+            #[cfg(feature = "singlepass")]
+            CompilerKind::Singlepass => Ok(Box::new(Singlepass::new())),
+            #[allow(unreachable_patterns)]
+            compiler => bail!("the embedder was built without the {} compiler, enable the `{}` feature", compiler, compiler),
+        }
+    }
+}
+impl FromStr for CompilerKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cranelift" => Ok(CompilerKind::Cranelift),
+            "llvm" => Ok(CompilerKind::LLVM),
+            "singlepass" => Ok(CompilerKind::Singlepass),
+            _ => Err(anyhow!("unknown compiler {}", s)),
+        }
+    }
+}
+impl fmt::Display for CompilerKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CompilerKind::Cranelift => "cranelift",
+            CompilerKind::LLVM => "llvm",
+            CompilerKind::Singlepass => "singlepass",
+        };
+        f.write_str(name)
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EngineKind {
+    Dylib,
+    Universal,
+}
+impl EngineKind {
+    pub const VARIANTS: &'static [&'static str] = &["dylib", "universal"];
+
+    /// Returns the engine that produced a precompiled artifact, or `None` if the bytes are not an
+    /// artifact of any enabled engine (e.g. a .wasm binary)
+    pub fn of_artifact(bytes: &[u8]) -> Option<EngineKind> {
+        #[cfg(feature = "universal")]
+        if UniversalArtifact::is_deserializable(bytes) {
+            return Some(EngineKind::Universal);
+        }
+        #[cfg(feature = "dylib")]
+        if DylibArtifact::is_deserializable(bytes) {
+            return Some(EngineKind::Dylib);
+        }
+        None
+    }
+
+    /// Returns an engine without a compiler that can only load precompiled artifacts
+    pub fn headless_engine(self) -> Result<Box<dyn Engine + Send + Sync>> {
+        match self {
+            #[cfg(feature = "dylib")]
+            EngineKind::Dylib => Ok(Box::new(Dylib::headless().engine())),
+            #[cfg(feature = "universal")]
+            EngineKind::Universal => Ok(Box::new(Universal::headless().engine())),
+            #[allow(unreachable_patterns)]
+            engine => bail!("the embedder was built without the {} engine, enable the `{}` feature", engine, engine),
+        }
+    }
+}
+impl FromStr for EngineKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dylib" => Ok(EngineKind::Dylib),
+            "universal" => Ok(EngineKind::Universal),
+            _ => Err(anyhow!("unknown engine {}", s)),
+        }
+    }
+}
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            EngineKind::Dylib => "dylib",
+            EngineKind::Universal => "universal",
+        };
+        f.write_str(name)
+    }
+}
+
+
+/// A combination of compiler and engine. Artifacts produced by one backend can't be loaded by
+/// another, so the backend is part of every cache key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backend {
+    pub compiler: CompilerKind,
+    pub engine: EngineKind,
+    /// Instrument the module with the metering middleware, to limit its fuel or time it out
+    pub metering: bool,
+}
+impl Backend {
+    /// Returns an engine that compiles modules for `target`
+    pub fn engine(&self, target: Target) -> Result<Box<dyn Engine + Send + Sync>> {
+        let mut compiler_config = self.compiler.config()?;
+        if self.metering {
+            // The actual fuel is set per instance, so that artifacts can be shared by all limits
+            compiler_config.push_middleware(Arc::new(Metering::new(u64::MAX, |_: &Operator| 1)));
+        }
+
+        match self.engine {
+            #[cfg(feature = "dylib")]
+            EngineKind::Dylib => Ok(Box::new(Dylib::new(compiler_config).target(target).engine())),
+            #[cfg(feature = "universal")]
+            EngineKind::Universal => Ok(Box::new(Universal::new(compiler_config).target(target).engine())),
+            #[allow(unreachable_patterns)]
+            engine => bail!("the embedder was built without the {} engine, enable the `{}` feature", engine, engine),
+        }
+    }
+}
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.compiler, self.engine)?;
+        if self.metering {
+            f.write_str("-metered")?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Builds a compilation target from a target triple and a list of CPU features. The host's triple
+/// and features are used if they are not given.
+pub fn target(triple: Option<&str>, cpu_features: &[String]) -> Result<Target> {
+    let triple = match triple {
+        Some(triple) => triple.parse::<Triple>().map_err(|e| anyhow!("invalid target {}: {}", triple, e))?,
+        None => Triple::host(),
+    };
+
+    let cpu_features = if cpu_features.is_empty() {
+        CpuFeature::for_host()
+    } else {
+        let mut features = CpuFeature::set();
+        for feature in cpu_features {
+            features.insert(feature.parse::<CpuFeature>().map_err(|e| anyhow!("invalid CPU feature {}: {}", feature, e))?);
+        }
+        features
+    };
+
+    Ok(Target::new(triple, cpu_features))
+}
@@ -0,0 +1,334 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+use wasmer::{Module, Store, Target};
+use uuid::Uuid;
+use wasmer_cache::Hash;
+
+use crate::backend::{Backend, EngineKind};
+
+/// Overrides the default cache directory, `--cache-dir` takes precedence
+pub const CACHE_DIR_ENV: &str = "WASI_MPI_CACHE_DIR";
+pub const DEFAULT_CACHE_SIZE_MB: &str = "10240";
+
+const ARTIFACT_EXTENSION: &str = "wasmu";
+const METADATA_EXTENSION: &str = "json";
+const TMP_EXTENSION: &str = "tmp";
+/// Temporary files older than this are leftovers of interrupted writes. Younger ones may still be
+/// written by another rank or node sharing the cache directory.
+const STALE_TMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+
+/// Returns the cache directory from the flag, `WASI_MPI_CACHE_DIR`, `$XDG_CACHE_HOME/wasi-mpi` or
+/// `~/.cache/wasi-mpi`, in that order
+pub fn cache_dir(flag: Option<&Path>) -> PathBuf {
+    if let Some(dir) = flag {
+        return dir.to_path_buf();
+    }
+    if let Some(dir) = env::var_os(CACHE_DIR_ENV) {
+        return PathBuf::from(dir);
+    }
+    match (env::var_os("XDG_CACHE_HOME"), env::var_os("HOME")) {
+        (Some(xdg_cache_home), _) => PathBuf::from(xdg_cache_home).join("wasi-mpi"),
+        (None, Some(home)) => PathBuf::from(home).join(".cache").join("wasi-mpi"),
+        (None, None) => PathBuf::from("./cache"),
+    }
+}
+
+
+/// Written next to every artifact, describes what it was compiled from and for
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CacheEntry {
+    pub module_path: String,
+    pub compiler: String,
+    pub engine: String,
+    pub wasmer_version: String,
+    pub target: String,
+    pub cpu_features: Vec<String>,
+    pub size: u64,
+    pub checksum: String,
+    pub created: DateTime<Utc>,
+    pub last_used: DateTime<Utc>,
+}
+
+
+/// Module cache that is safe to share between the ranks of a job and between nodes. Artifacts are
+/// written atomically, their checksum is checked before they are loaded, and the least recently
+/// used artifacts are removed once the cache exceeds its size limit.
+pub struct ModuleCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+impl ModuleCache {
+    pub fn new(dir: PathBuf, max_size: u64) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(ModuleCache{ dir, max_size })
+    }
+
+    /// Artifacts can only be reused by the same backend and wasmer version on compatible CPUs
+    pub fn key(wasm_bytes: &[u8], backend: &Backend, target: &Target) -> String {
+        let mut cpu_features: Vec<String> = target.cpu_features().iter().map(|feature| feature.to_string()).collect();
+        cpu_features.sort();
+
+        let config = format!("{}-{}-{}-{}", backend, wasmer::VERSION, target.triple(), cpu_features.join(","));
+        let config_hash = Hash::generate(config.as_bytes()).to_string();
+        format!("{}-{}", Hash::generate(wasm_bytes).to_string(), &config_hash[..16.min(config_hash.len())])
+    }
+
+    fn artifact_path(&self, key: &str) -> PathBuf {
+        self.dir.join(key).with_extension(ARTIFACT_EXTENSION)
+    }
+
+    fn metadata_path(&self, key: &str) -> PathBuf {
+        self.dir.join(key).with_extension(METADATA_EXTENSION)
+    }
+
+    fn read_entry(&self, key: &str) -> Result<CacheEntry> {
+        Ok(serde_json::from_slice(&fs::read(self.metadata_path(key))?)?)
+    }
+
+    fn write_entry(&self, key: &str, entry: &CacheEntry) -> Result<()> {
+        write_atomic(&self.metadata_path(key), &serde_json::to_vec_pretty(entry)?)
+    }
+
+    /// Reads an artifact and checks it against the checksum in its metadata
+    fn read_artifact(&self, key: &str, entry: &CacheEntry) -> Result<Vec<u8>> {
+        let artifact = fs::read(self.artifact_path(key))?;
+        if Hash::generate(&artifact).to_string() != entry.checksum {
+            bail!("checksum mismatch for cached module {}", key);
+        }
+        Ok(artifact)
+    }
+
+    pub unsafe fn load(&self, store: &Store, key: &str) -> Result<Module> {
+        let mut entry = self.read_entry(key)?;
+        let artifact = self.read_artifact(key, &entry)?;
+        let module = Module::deserialize(store, &artifact)?;
+
+        // Failing to record the use only affects the eviction order
+        entry.last_used = Utc::now();
+        self.write_entry(key, &entry).ok();
+        Ok(module)
+    }
+
+    pub fn store(&self, key: &str, module_path: &str, backend: &Backend, target: &Target, module: &Module) -> Result<()> {
+        let artifact = module.serialize()?;
+        let now = Utc::now();
+        let entry = CacheEntry{
+            module_path: module_path.to_string(),
+            compiler: backend.compiler.to_string(),
+            engine: backend.engine.to_string(),
+            wasmer_version: wasmer::VERSION.to_string(),
+            target: target.triple().to_string(),
+            cpu_features: target.cpu_features().iter().map(|feature| feature.to_string()).collect(),
+            size: artifact.len() as u64,
+            checksum: Hash::generate(&artifact).to_string(),
+            created: now,
+            last_used: now,
+        };
+
+        // The artifact goes first, so an entry with metadata is always complete
+        write_atomic(&self.artifact_path(key), &artifact)?;
+        self.write_entry(key, &entry)?;
+        self.evict()
+    }
+
+    /// Returns all entries with valid metadata, least recently used first
+    pub fn entries(&self) -> Result<Vec<(String, CacheEntry)>> {
+        let mut entries = Vec::new();
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(METADATA_EXTENSION) {
+                continue;
+            }
+            let key = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(key) => key.to_string(),
+                None => continue,
+            };
+            if let Ok(entry) = self.read_entry(&key) {
+                entries.push((key, entry));
+            }
+        }
+        entries.sort_by_key(|(_, entry)| entry.last_used);
+        Ok(entries)
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        // Metadata first, so a concurrent load never finds metadata without an artifact
+        remove_if_exists(&self.metadata_path(key))?;
+        remove_if_exists(&self.artifact_path(key))
+    }
+
+    /// Removes the files of the cache, including leftovers of interrupted writes. Other files are
+    /// left alone, in case the cache directory was mistyped.
+    pub fn clear(&self) -> Result<()> {
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            if path.is_file() && is_cache_file(&path) {
+                remove_if_exists(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the checksum of an entry and that the host can load it
+    pub fn verify(&self, key: &str, entry: &CacheEntry) -> Result<()> {
+        if entry.wasmer_version != wasmer::VERSION {
+            bail!("compiled by wasmer {}, this embedder uses {}", entry.wasmer_version, wasmer::VERSION);
+        }
+
+        let artifact = self.read_artifact(key, entry)?;
+        let engine = entry.engine.parse::<EngineKind>()?.headless_engine()?;
+        let store = Store::new(&*engine);
+        unsafe {
+            Module::deserialize(&store, &artifact)?;
+        }
+        Ok(())
+    }
+
+    /// Removes temporary files of writes that were interrupted long enough ago
+    fn remove_stale_tmp_files(&self) -> Result<()> {
+        let now = SystemTime::now();
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            let path = dir_entry.path();
+            if !is_tmp_file(&path) {
+                continue;
+            }
+            let modified = match dir_entry.metadata().and_then(|metadata| metadata.modified()) {
+                Ok(modified) => modified,
+                Err(_) => continue,
+            };
+            if now.duration_since(modified).unwrap_or_default() > STALE_TMP_AGE {
+                remove_if_exists(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn evict(&self) -> Result<()> {
+        self.remove_stale_tmp_files()?;
+
+        let entries = self.entries()?;
+        let mut total_size: u64 = entries.iter().map(|(_, entry)| entry.size).sum();
+        for (key, entry) in entries {
+            if total_size <= self.max_size {
+                break;
+            }
+            self.remove(&key)?;
+            total_size -= entry.size;
+        }
+        Ok(())
+    }
+}
+
+
+/// Whether a file was written by the cache
+fn is_cache_file(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ARTIFACT_EXTENSION) | Some(METADATA_EXTENSION) => true,
+        _ => is_tmp_file(path),
+    }
+}
+
+
+/// Whether a file is a temporary file of `write_atomic`, named `<file>.<uuid>.tmp`
+fn is_tmp_file(path: &Path) -> bool {
+    if path.extension().and_then(|ext| ext.to_str()) != Some(TMP_EXTENSION) {
+        return false;
+    }
+    let uuid = path.file_stem().map(Path::new).and_then(|stem| stem.extension()).and_then(|ext| ext.to_str());
+    uuid.map_or(false, |uuid| Uuid::parse_str(uuid).is_ok())
+}
+
+
+/// Writes to a temporary file first and renames it, so readers never see a partially written file.
+/// The temporary name is random, as ranks on different nodes can share the cache directory and
+/// their process ids collide.
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut tmp_name = path.file_name().expect("cache path without file name").to_os_string();
+    tmp_name.push(format!(".{}.{}", Uuid::new_v4(), TMP_EXTENSION));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    fs::write(&tmp_path, contents)?;
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        fs::remove_file(&tmp_path).ok();
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+
+#[derive(StructOpt)]
+#[structopt(name = "embedder cache", about = "Manage the cache of compiled modules")]
+pub struct CacheOpt {
+    #[structopt(long, parse(from_os_str))]
+    pub cache_dir: Option<PathBuf>,
+    #[structopt(subcommand)]
+    pub command: CacheCommand,
+}
+
+
+#[derive(StructOpt)]
+pub enum CacheCommand {
+    /// List cached modules, least recently used first
+    List,
+    /// Remove all cached modules
+    Clear,
+    /// Check that cached modules are intact and can be loaded
+    Verify {
+        /// Remove modules that fail the check
+        #[structopt(long)]
+        remove: bool,
+    },
+}
+
+
+pub fn run_command(opt: &CacheOpt) -> Result<()> {
+    let dir = cache_dir(opt.cache_dir.as_deref());
+    // Nothing is stored, so the size limit doesn't matter
+    let cache = ModuleCache::new(dir.clone(), u64::MAX)?;
+
+    match opt.command {
+        CacheCommand::List => {
+            let entries = cache.entries()?;
+            println!("{} ({} modules, {} MB)", dir.display(), entries.len(), entries.iter().map(|(_, entry)| entry.size).sum::<u64>() / (1024 * 1024));
+            for (key, entry) in entries {
+                println!("{}  {:>8} KB  {}-{}  {}  last used {}  {}",
+                         key, entry.size / 1024, entry.compiler, entry.engine, entry.target,
+                         entry.last_used.format("%Y-%m-%d %H:%M"), entry.module_path);
+            }
+        }
+        CacheCommand::Clear => cache.clear()?,
+        CacheCommand::Verify { remove } => {
+            let mut failed = 0;
+            for (key, entry) in cache.entries()? {
+                if let Err(e) = cache.verify(&key, &entry) {
+                    println!("{}: {}", key, e);
+                    failed += 1;
+                    if remove {
+                        cache.remove(&key)?;
+                    }
+                }
+            }
+            if failed > 0 && !remove {
+                bail!("{} cached modules failed verification, remove them with --remove", failed);
+            }
+        }
+    }
+    Ok(())
+}
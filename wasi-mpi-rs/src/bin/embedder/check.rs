@@ -0,0 +1,61 @@
+use std::fs;
+
+use anyhow::{bail, Result};
+use structopt::StructOpt;
+use wasmer::{Module, Store, Target};
+use wasmer_wasi::WasiState;
+
+use wasm_mpi_rs::{self, Env};
+
+use crate::backend::{Backend, CompilerKind, EngineKind, DEFAULT_COMPILER, DEFAULT_ENGINE};
+
+
+#[derive(StructOpt)]
+#[structopt(name = "embedder check", about = "Check that the imports of a WASI-MPI module can be resolved")]
+pub struct CheckOpt {
+    #[structopt(long, default_value = DEFAULT_COMPILER, possible_values = CompilerKind::VARIANTS)]
+    pub compiler: CompilerKind,
+    #[structopt(long, default_value = DEFAULT_ENGINE, possible_values = EngineKind::VARIANTS)]
+    pub engine: EngineKind,
+    #[structopt(name = "MODULE_PATH")]
+    pub module_path: String,
+}
+
+
+/// Resolves the imports of a module like the run mode does and lists every import that can't be
+/// satisfied, without instantiating or running the module
+pub fn check(opt: &CheckOpt) -> Result<()> {
+    let wasm_bytes = fs::read(&opt.module_path)?;
+
+    let (store, module) = if let Some(artifact_engine) = EngineKind::of_artifact(&wasm_bytes) {
+        let engine = artifact_engine.headless_engine()?;
+        let store = Store::new(&*engine);
+        let module = unsafe { Module::deserialize(&store, &wasm_bytes)? };
+        (store, module)
+    } else {
+        let backend = Backend{ compiler: opt.compiler, engine: opt.engine, metering: false };
+        let engine = backend.engine(Target::default())?;
+        let store = Store::new(&*engine);
+        let module = Module::from_binary(&store, &wasm_bytes)?;
+        (store, module)
+    };
+
+    let mut wasi_env = WasiState::new(&opt.module_path).finalize()?;
+    let mut import_object = wasi_env.import_object(&module)?;
+    let env = Env::new();
+    let mut mpi_imports = wasm_mpi_rs::imports(&store, &env);
+    wasm_mpi_rs::register_exception_imports(&store, &env, &module, &mut mpi_imports);
+    import_object.register(wasm_mpi_rs::IMPORT_NAMESPACE, mpi_imports);
+
+    let issues = wasm_mpi_rs::check_imports(&store, &module, &import_object);
+    for issue in &issues {
+        println!("{}", issue);
+    }
+
+    let import_count = module.imports().count();
+    if !issues.is_empty() {
+        bail!("{} of {} imports of {} can't be resolved", issues.len(), import_count, opt.module_path);
+    }
+    println!("All {} imports of {} can be resolved", import_count, opt.module_path);
+    Ok(())
+}
@@ -0,0 +1,187 @@
+use std::fmt;
+use std::process;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Error, Result};
+use libc::c_void;
+use mpi_sys;
+use wasmer::{Module, Store};
+
+/// Artifacts are broadcast in pieces as MPI counts are `int`
+const BCAST_CHUNK_SIZE: usize = 1 << 30;
+
+
+/// Which ranks compile the module. With `Node` and `World` one rank compiles (or loads it from the
+/// cache) and broadcasts the artifact to the other ranks of its node or of the whole job.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompileOn {
+    Each,
+    Node,
+    World,
+}
+impl CompileOn {
+    pub const VARIANTS: &'static [&'static str] = &["each", "node", "world"];
+}
+impl FromStr for CompileOn {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "each" => Ok(CompileOn::Each),
+            "node" => Ok(CompileOn::Node),
+            "world" => Ok(CompileOn::World),
+            _ => Err(anyhow!("unknown compile mode {}", s)),
+        }
+    }
+}
+impl fmt::Display for CompileOn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CompileOn::Each => "each",
+            CompileOn::Node => "node",
+            CompileOn::World => "world",
+        };
+        f.write_str(name)
+    }
+}
+
+
+/// Initializes the host MPI unless the embedder or the module already did
+pub fn init_mpi() {
+    let mut initialized = 0;
+    unsafe {
+        mpi_sys::MPI_Initialized(&mut initialized);
+    }
+    if initialized == 0 {
+        wasm_mpi_rs::init_host_mpi();
+    }
+}
+
+
+/// Finalizes the host MPI if it was initialized and the module did not finalize it
+pub fn finalize_mpi() {
+    if mpi_running() {
+        unsafe {
+            mpi_sys::MPI_Finalize();
+        }
+    }
+}
+
+
+fn mpi_running() -> bool {
+    let mut initialized = 0;
+    let mut finalized = 0;
+    unsafe {
+        mpi_sys::MPI_Initialized(&mut initialized);
+        mpi_sys::MPI_Finalized(&mut finalized);
+    }
+    initialized != 0 && finalized == 0
+}
+
+
+/// Returns the rank in `MPI_COMM_WORLD` while MPI is initialized
+pub fn world_rank() -> Option<i32> {
+    if !mpi_running() {
+        return None;
+    }
+
+    let mut rank = -1;
+    unsafe {
+        mpi_sys::MPI_Comm_rank(mpi_sys::RSMPI_COMM_WORLD, &mut rank);
+    }
+    Some(rank)
+}
+
+
+/// Initializes MPI unless it is running already and returns the rank in `MPI_COMM_WORLD`, e.g. to
+/// set up rank-specific paths before the module runs. The module's `MPI_Init` then returns right away.
+pub fn init_world_rank() -> i32 {
+    init_mpi();
+    world_rank().unwrap_or(0)
+}
+
+
+/// Aborts all ranks of the job if MPI is initialized, does nothing otherwise. Other threads than the
+/// main thread can only call `MPI_Abort` if the host MPI provides `MPI_THREAD_MULTIPLE`, without it
+/// they end the process and leave it to the launcher to end the job.
+pub fn abort_mpi(error_code: i32) {
+    if !mpi_running() {
+        return;
+    }
+
+    let mut is_main = 0;
+    let mut provided = 0;
+    unsafe {
+        mpi_sys::MPI_Is_thread_main(&mut is_main);
+        mpi_sys::MPI_Query_thread(&mut provided);
+    }
+    if is_main == 0 && provided < mpi_sys::MPI_THREAD_MULTIPLE as i32 {
+        process::exit(error_code);
+    }
+    unsafe {
+        mpi_sys::MPI_Abort(mpi_sys::RSMPI_COMM_WORLD, error_code);
+    }
+}
+
+
+/// Calls `load` on rank 0 of the node or of the job and broadcasts the serialized module to the
+/// other ranks, which deserialize it into `store`. Must be called by all ranks.
+pub fn load_module<F>(store: &Store, compile_on: CompileOn, load: F) -> Result<Module> where F: FnOnce() -> Result<Module> {
+    if compile_on == CompileOn::Each {
+        return load();
+    }
+
+    init_mpi();
+
+    let mut comm = unsafe {
+        match compile_on {
+            CompileOn::Node => {
+                let mut node_comm = mpi_sys::RSMPI_COMM_NULL;
+                mpi_sys::MPI_Comm_split_type(mpi_sys::RSMPI_COMM_WORLD, mpi_sys::MPI_COMM_TYPE_SHARED as i32, 0, mpi_sys::RSMPI_INFO_NULL, &mut node_comm);
+                node_comm
+            }
+            _ => mpi_sys::RSMPI_COMM_WORLD,
+        }
+    };
+
+    let mut rank = -1;
+    unsafe {
+        mpi_sys::MPI_Comm_rank(comm, &mut rank);
+    }
+
+    // An empty artifact tells the other ranks that rank 0 failed, so they don't wait forever
+    let (module, mut artifact) = if rank == 0 {
+        match load().and_then(|module| Ok((module.serialize()?, module))) {
+            Ok((artifact, module)) => (Ok(module), artifact),
+            Err(e) => (Err(e), Vec::new()),
+        }
+    } else {
+        (Err(anyhow!("module was not loaded on this rank")), Vec::new())
+    };
+
+    let mut len = artifact.len() as u64;
+    unsafe {
+        mpi_sys::MPI_Bcast(&mut len as *mut u64 as *mut c_void, 1, mpi_sys::RSMPI_UINT64_T, 0, comm);
+    }
+    artifact.resize(len as usize, 0);
+
+    for chunk in artifact.chunks_mut(BCAST_CHUNK_SIZE) {
+        unsafe {
+            mpi_sys::MPI_Bcast(chunk.as_mut_ptr() as *mut c_void, chunk.len() as i32, mpi_sys::RSMPI_UINT8_T, 0, comm);
+        }
+    }
+
+    if compile_on == CompileOn::Node {
+        unsafe {
+            mpi_sys::MPI_Comm_free(&mut comm);
+        }
+    }
+
+    if rank == 0 {
+        return module;
+    }
+    if artifact.is_empty() {
+        bail!("loading the module failed on rank 0 of the {}", if compile_on == CompileOn::Node { "node" } else { "job" });
+    }
+    Ok(unsafe { Module::deserialize(store, &artifact)? })
+}
@@ -0,0 +1,56 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use anyhow::Result;
+use structopt::StructOpt;
+use wasmer::{Module, Store};
+
+use crate::backend::{self, Backend, CompilerKind, EngineKind, DEFAULT_COMPILER, DEFAULT_ENGINE};
+
+
+#[derive(StructOpt)]
+#[structopt(name = "embedder compile", about = "Compile a WASI-MPI module ahead of time")]
+pub struct CompileOpt {
+    #[structopt(short, long)]
+    pub timings: bool,
+    #[structopt(long, default_value = DEFAULT_COMPILER, possible_values = CompilerKind::VARIANTS)]
+    pub compiler: CompilerKind,
+    #[structopt(long, default_value = DEFAULT_ENGINE, possible_values = EngineKind::VARIANTS)]
+    pub engine: EngineKind,
+    /// Target triple of the machines that will run the module, e.g. x86_64-unknown-linux-gnu
+    #[structopt(long)]
+    pub target: Option<String>,
+    /// Comma-separated CPU features of the target, e.g. sse2,avx,avx2,avx512f. Defaults to the
+    /// features of this machine.
+    #[structopt(long, use_delimiter = true)]
+    pub cpu_features: Vec<String>,
+    /// Instrument the module so that `embedder --fuel` and `--timeout` can trap it
+    #[structopt(long)]
+    pub metering: bool,
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: PathBuf,
+    #[structopt(name = "MODULE_PATH")]
+    pub module_path: String,
+}
+
+
+/// Compiles a module into an artifact that `embedder` can run directly without a compiler
+pub fn compile(opt: &CompileOpt) -> Result<()> {
+    let target = backend::target(opt.target.as_deref(), &opt.cpu_features)?;
+    let backend = Backend{ compiler: opt.compiler, engine: opt.engine, metering: opt.metering };
+    let engine = backend.engine(target)?;
+    let store = Store::new(&*engine);
+
+    let wasm_bytes = fs::read(&opt.module_path)?;
+
+    let time_before = Instant::now();
+    let module = Module::from_binary(&store, &wasm_bytes)?;
+    let time_after = Instant::now();
+    if opt.timings {
+        println!("Compile took {}ms", (time_after - time_before).as_millis())
+    }
+
+    fs::write(&opt.output, module.serialize()?)?;
+    Ok(())
+}
@@ -0,0 +1,573 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::CStr;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use libc::{c_char, c_void};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+use wasm_mpi_rs::{guest_return_addresses, resolve_frames, CallAction, MpiCall, MpiComm, MpiGroup, ReturnValue, Tool, MPI_ANY_SOURCE, MPI_ANY_TAG};
+
+use crate::collective;
+use crate::symbols::Symbolizer;
+
+/// The address of rank 0's side channel is broadcast in a buffer of this size
+const ADDRESS_LEN: usize = 256;
+const CONNECT_ATTEMPTS: u32 = 10;
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+
+/// Finds ranks that are blocked in MPI calls waiting on each other
+#[derive(StructOpt)]
+pub struct DeadlockOpt {
+    /// Report ranks that are blocked in MPI calls for this many seconds and wait on each other
+    #[structopt(long)]
+    pub deadlock_timeout: Option<u64>,
+    /// Abort the job once a deadlock was reported
+    #[structopt(long)]
+    pub deadlock_abort: bool,
+}
+
+
+/// A blocking MPI call of a rank as sent over the side channel. Peers are ranks in `MPI_COMM_WORLD`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Operation {
+    /// Distinguishes successive calls of a rank
+    id: u64,
+    call: String,
+    description: String,
+    /// The ranks the call waits for
+    peers: Vec<i32>,
+    /// Any one of the peers can complete the call, e.g. for `MPI_ANY_SOURCE`
+    any: bool,
+    /// The call waits for the peers to enter the same collective
+    collective: bool,
+    blocked_for: f64,
+    backtrace: Vec<String>,
+}
+
+
+/// Sent by a rank whenever its state changes, `None` once it left the reported call
+#[derive(Debug, Deserialize, Serialize)]
+struct Report {
+    rank: i32,
+    operation: Option<Operation>,
+}
+
+
+/// A blocking call that is in progress
+struct BlockingCall {
+    start: Instant,
+    operation: Operation,
+    /// Return addresses of the guest stack, only resolved if the call is reported
+    frames: Vec<usize>,
+}
+
+
+/// A nonblocking operation that `MPI_Wait` blocks on
+#[derive(Clone)]
+struct PendingRequest {
+    description: String,
+    peers: Vec<i32>,
+    any: bool,
+}
+
+
+#[derive(Default)]
+struct MonitorState {
+    current: Option<BlockingCall>,
+    next_id: u64,
+    requests: HashMap<i32, PendingRequest>,
+    /// The requests passed to the current `MPI_Waitany` or `MPI_Testany`
+    any_requests: Vec<i32>,
+    /// World ranks of the members of the module's communicators, by guest handle
+    comm_members: HashMap<i32, Vec<i32>>,
+}
+
+
+/// Keeps track of the blocking MPI call a rank is in, with the guest stack that made it
+#[derive(Default)]
+pub struct CallMonitor {
+    state: Mutex<MonitorState>,
+}
+impl CallMonitor {
+    fn members(&self, call: &MpiCall) -> Option<Vec<i32>> {
+        let comm = call.comm()?;
+        if let Some(members) = self.state.lock().unwrap().comm_members.get(&comm) {
+            return Some(members.clone());
+        }
+        let members = world_members(call.host_comm()?);
+        self.state.lock().unwrap().comm_members.insert(comm, members.clone());
+        Some(members)
+    }
+
+    /// Describes what a call waits for, `None` for calls that don't block on other ranks
+    fn describe(&self, call: &MpiCall) -> Option<PendingRequest> {
+        let members = self.members(call)?;
+        let comm = call.comm()?;
+        let world_rank = |rank: i32| members.get(rank as usize).copied();
+
+        if call.signature().collective {
+            let rank = collective::world_rank().unwrap_or(-1);
+            return Some(PendingRequest{
+                description: format!("{} on communicator {} of {} ranks", call.name(), comm, members.len()),
+                peers: members.iter().copied().filter(|&member| member != rank).collect(),
+                any: false,
+            });
+        }
+
+        if let (Some(dest), Some(source)) = (call.dest(), call.source()) {
+            let dest = world_rank(dest)?;
+            let mut peers = vec![dest];
+            let source = match source {
+                MPI_ANY_SOURCE => "any rank".to_string(),
+                source => {
+                    let source = world_rank(source)?;
+                    peers.push(source);
+                    source.to_string()
+                }
+            };
+            let description = format!("{} to {} and from {} on communicator {}", call.name(), dest, source, comm);
+            return Some(PendingRequest{ description, peers, any: false });
+        }
+        let (direction, peer, tag) = match (call.dest(), call.source()) {
+            (Some(dest), _) => ("to", dest, call.send_tag()),
+            (None, Some(source)) => ("from", source, call.recv_tag()),
+            (None, None) => return None,
+        };
+        let tag = match tag {
+            Some(MPI_ANY_TAG) | None => "any tag".to_string(),
+            Some(tag) => format!("tag {}", tag),
+        };
+        let (peers, any, peer) = match peer {
+            MPI_ANY_SOURCE => (members.clone(), true, "any rank".to_string()),
+            peer => {
+                let peer = world_rank(peer)?;
+                (vec![peer], false, peer.to_string())
+            }
+        };
+        let description = format!("{} {} {} with {} on communicator {}", call.name(), direction, peer, tag, comm);
+        Some(PendingRequest{ description, peers, any })
+    }
+
+    fn enter(&self, call: &MpiCall) {
+        let name = call.name();
+        let request = match name {
+            "MPI_Wait" => call.request().and_then(|request| self.state.lock().unwrap().requests.remove(&request)).map(|request| {
+                PendingRequest{ description: format!("MPI_Wait for {}", request.description), ..request }
+            }),
+            // Completing any of the requests lets the rank continue
+            "MPI_Waitany" => {
+                let state = self.state.lock().unwrap();
+                let pending: Vec<_> = state.any_requests.iter().filter_map(|request| state.requests.get(request)).collect();
+                let mut peers: Vec<i32> = pending.iter().flat_map(|request| request.peers.iter().copied()).collect();
+                peers.sort_unstable();
+                peers.dedup();
+                match pending.is_empty() {
+                    true => None,
+                    false => Some(PendingRequest{
+                        description: format!("MPI_Waitany for {}", pending.iter().map(|request| request.description.as_str()).collect::<Vec<_>>().join(", ")),
+                        peers,
+                        any: true,
+                    }),
+                }
+            }
+            "MPI_Send" | "MPI_Send_c" | "MPI_Recv" | "MPI_Recv_c" | "MPI_Sendrecv" => self.describe(call),
+            _ if call.signature().collective => self.describe(call),
+            _ => None,
+        };
+        let request = match request {
+            Some(request) => request,
+            None => return,
+        };
+
+        let frames = guest_return_addresses();
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        let operation = Operation{
+            id: state.next_id,
+            call: name.to_string(),
+            description: request.description,
+            peers: request.peers,
+            any: request.any,
+            collective: call.signature().collective,
+            blocked_for: 0.0,
+            backtrace: Vec::new(),
+        };
+        state.current = Some(BlockingCall{ start: Instant::now(), operation, frames });
+    }
+
+    /// The operation of the call the rank is blocked in, if it is blocked for at least `threshold`
+    fn blocked(&self, threshold: Duration) -> Option<(Operation, Vec<usize>)> {
+        let state = self.state.lock().unwrap();
+        let call = state.current.as_ref()?;
+        let blocked_for = call.start.elapsed();
+        if blocked_for < threshold {
+            return None;
+        }
+        let operation = Operation{ blocked_for: blocked_for.as_secs_f64(), ..call.operation.clone() };
+        Some((operation, call.frames.clone()))
+    }
+}
+impl Tool for CallMonitor {
+    fn before(&self, call: &mut MpiCall) -> CallAction {
+        if call.name() == "MPI_Comm_free" {
+            if let Some(comm) = call.pointee(0) {
+                self.state.lock().unwrap().comm_members.remove(&comm);
+            }
+        }
+        if let Some(requests) = call.any_requests() {
+            self.state.lock().unwrap().any_requests = requests;
+        }
+        self.enter(call);
+        CallAction::Proceed
+    }
+
+    fn after(&self, call: &MpiCall, _ret: &mut ReturnValue) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.current = None;
+            let any_requests = std::mem::take(&mut state.any_requests);
+            if let Some(&request) = call.completed_index().and_then(|index| any_requests.get(index)) {
+                state.requests.remove(&request);
+            }
+        }
+
+        // Nonblocking operations are described when they start, as `MPI_Wait` only has the request
+        if matches!(call.name(), "MPI_Isend" | "MPI_Isend_c" | "MPI_Irecv" | "MPI_Irecv_c") {
+            if let (Some(pending), Some(request)) = (self.describe(call), call.request()) {
+                self.state.lock().unwrap().requests.insert(request, pending);
+            }
+        }
+    }
+}
+
+
+/// Returns the ranks in `MPI_COMM_WORLD` of the members of a communicator, by their rank in it
+fn world_members(MpiComm(host_comm): MpiComm) -> Vec<i32> {
+    let mut group = MpiGroup::default();
+    let mut world_group = MpiGroup::default();
+    let mut size = 0;
+    unsafe {
+        mpi_sys::MPI_Comm_size(host_comm, &mut size);
+        mpi_sys::MPI_Comm_group(host_comm, &mut group.0);
+        mpi_sys::MPI_Comm_group(mpi_sys::RSMPI_COMM_WORLD, &mut world_group.0);
+    }
+    let ranks: Vec<i32> = (0..size).collect();
+    let mut world_ranks = vec![-1; ranks.len()];
+    unsafe {
+        mpi_sys::MPI_Group_translate_ranks(group.0, size, ranks.as_ptr(), world_group.0, world_ranks.as_mut_ptr());
+        mpi_sys::MPI_Group_free(&mut group.0);
+        mpi_sys::MPI_Group_free(&mut world_group.0);
+    }
+    world_ranks
+}
+
+
+/// The ranks that `rank` waits for. Ranks in the same collective call are not waited for.
+fn waits_for(rank: i32, operation: &Operation, operations: &BTreeMap<i32, Operation>) -> Vec<i32> {
+    if !operation.collective {
+        return operation.peers.clone();
+    }
+    let members: BTreeSet<i32> = operation.peers.iter().copied().chain([rank]).collect();
+    operation.peers.iter().copied()
+        .filter(|peer| match operations.get(peer) {
+            Some(other) if other.collective && other.call == operation.call => {
+                other.peers.iter().copied().chain([*peer]).collect::<BTreeSet<i32>>() != members
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+
+/// Returns a cycle of ranks that wait on each other, if any. Ranks that are blocked are removed
+/// until all remaining ones only wait for remaining ones, i.e. none of them can ever continue.
+fn find_cycle(operations: &BTreeMap<i32, Operation>) -> Option<Vec<i32>> {
+    let edges: BTreeMap<i32, Vec<i32>> = operations.iter()
+        .map(|(&rank, operation)| (rank, waits_for(rank, operation, operations)))
+        .collect();
+
+    let mut stuck: BTreeSet<i32> = operations.keys().copied().collect();
+    loop {
+        let still_stuck: BTreeSet<i32> = stuck.iter().copied()
+            .filter(|rank| {
+                let targets = &edges[rank];
+                match operations[rank].any {
+                    true => !targets.is_empty() && targets.iter().all(|target| stuck.contains(target)),
+                    false => targets.iter().any(|target| stuck.contains(target)),
+                }
+            })
+            .collect();
+        if still_stuck.len() == stuck.len() {
+            break;
+        }
+        stuck = still_stuck;
+    }
+
+    // Every remaining rank waits for another remaining one, so following them leads into a cycle
+    let mut path = vec![*stuck.iter().next()?];
+    loop {
+        let last = *path.last().unwrap();
+        let next = *edges[&last].iter().find(|target| stuck.contains(target))?;
+        if let Some(start) = path.iter().position(|&rank| rank == next) {
+            return Some(path.split_off(start));
+        }
+        path.push(next);
+    }
+}
+
+
+fn print_deadlock(cycle: &[i32], operations: &BTreeMap<i32, Operation>) {
+    let ranks: Vec<String> = cycle.iter().chain(&cycle[..1]).map(|rank| rank.to_string()).collect();
+    eprintln!("deadlock: ranks {} are waiting on each other", ranks.join(" -> "));
+    for rank in cycle {
+        let operation = &operations[rank];
+        eprintln!("  rank {} in {} for {:.0}s", rank, operation.description, operation.blocked_for);
+        for (i, frame) in operation.backtrace.iter().enumerate() {
+            eprintln!("      {}: {}", i, frame);
+        }
+    }
+}
+
+
+/// Receives the reports of all ranks on rank 0 and looks for cycles among the blocked ranks. A
+/// cycle is only reported once it stayed the same for a poll interval, as reports can be in flight.
+fn coordinate(listener: TcpListener, poll_interval: Duration, abort: bool) {
+    let operations = Arc::new(Mutex::new(BTreeMap::<i32, Operation>::new()));
+
+    let accepted = operations.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let operations = accepted.clone();
+            thread::spawn(move || {
+                let mut rank = None;
+                for line in BufReader::new(stream).lines() {
+                    let report: Report = match line.ok().and_then(|line| serde_json::from_str(&line).ok()) {
+                        Some(report) => report,
+                        None => break,
+                    };
+                    rank = Some(report.rank);
+                    let mut operations = operations.lock().unwrap();
+                    match report.operation {
+                        Some(operation) => operations.insert(report.rank, operation),
+                        None => operations.remove(&report.rank),
+                    };
+                }
+                // The rank exited
+                if let Some(rank) = rank {
+                    operations.lock().unwrap().remove(&rank);
+                }
+            });
+        }
+    });
+
+    let mut candidate = None;
+    let mut reported = None;
+    loop {
+        thread::sleep(poll_interval);
+        let operations = operations.lock().unwrap().clone();
+        let cycle = find_cycle(&operations).map(|cycle| {
+            let calls: Vec<(i32, u64)> = cycle.iter().map(|rank| (*rank, operations[rank].id)).collect();
+            (cycle, calls)
+        });
+        let calls = cycle.as_ref().map(|(_, calls)| calls.clone());
+        if let (Some((cycle, _)), true) = (&cycle, calls == candidate && calls != reported) {
+            print_deadlock(cycle, &operations);
+            if abort {
+                eprintln!("deadlock: aborting the job");
+                collective::abort_mpi(1);
+                process::exit(1);
+            }
+            reported = calls.clone();
+        }
+        candidate = calls;
+    }
+}
+
+
+/// Sends the state of this rank to rank 0 whenever it gets blocked past the threshold or leaves
+/// the call it was reported in
+fn watch(monitor: Arc<CallMonitor>, symbolizer: Arc<Mutex<Symbolizer>>, mut stream: TcpStream, rank: i32, threshold: Duration, poll_interval: Duration) {
+    let mut reported = None;
+    loop {
+        thread::sleep(poll_interval);
+        let blocked = monitor.blocked(threshold);
+        let id = blocked.as_ref().map(|(operation, _)| operation.id);
+        if id == reported {
+            continue;
+        }
+
+        let operation = blocked.map(|(mut operation, frames)| {
+            let symbolizer = symbolizer.lock().unwrap();
+            operation.backtrace = resolve_frames(&frames).iter().map(|frame| symbolizer.frame(frame)).collect();
+            operation
+        });
+        let mut line = match serde_json::to_vec(&Report{ rank, operation }) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        line.push(b'\n');
+        // Rank 0 exited, so there is nobody to report to anymore
+        if stream.write_all(&line).is_err() {
+            return;
+        }
+        reported = id;
+    }
+}
+
+
+/// Sets up the side channel to rank 0 and starts the watchdog threads. Must be called by all ranks.
+pub fn start(opt: &DeadlockOpt, monitor: Arc<CallMonitor>, symbolizer: Arc<Mutex<Symbolizer>>) -> Result<()> {
+    let threshold = match opt.deadlock_timeout {
+        Some(timeout) => Duration::from_secs(timeout),
+        None => return Ok(()),
+    };
+    let poll_interval = (threshold / 4).max(MIN_POLL_INTERVAL).min(MAX_POLL_INTERVAL);
+    let rank = collective::init_world_rank();
+
+    let mut address = [0u8; ADDRESS_LEN];
+    let listener = if rank == 0 {
+        let listener = TcpListener::bind("0.0.0.0:0")?;
+        let host = hostname()?;
+        let host_address = format!("{}:{}", host, listener.local_addr()?.port());
+        if host_address.len() >= ADDRESS_LEN {
+            return Err(anyhow!("the host name {} is too long", host));
+        }
+        address[..host_address.len()].copy_from_slice(host_address.as_bytes());
+        Some(listener)
+    } else {
+        None
+    };
+    unsafe {
+        mpi_sys::MPI_Bcast(address.as_mut_ptr() as *mut c_void, ADDRESS_LEN as i32, mpi_sys::RSMPI_UINT8_T, 0, mpi_sys::RSMPI_COMM_WORLD);
+    }
+    let len = address.iter().position(|&byte| byte == 0).unwrap_or(ADDRESS_LEN);
+    let address = String::from_utf8(address[..len].to_vec())?;
+
+    if let Some(listener) = listener {
+        let abort = opt.deadlock_abort;
+        thread::spawn(move || coordinate(listener, poll_interval, abort));
+    }
+
+    let mut attempt = 0;
+    let stream = loop {
+        match TcpStream::connect(&address) {
+            Ok(stream) => break stream,
+            Err(e) if attempt + 1 >= CONNECT_ATTEMPTS => return Err(anyhow!("could not connect to rank 0 at {}: {}", address, e)),
+            Err(_) => {
+                attempt += 1;
+                thread::sleep(MIN_POLL_INTERVAL);
+            }
+        }
+    };
+    thread::spawn(move || watch(monitor, symbolizer, stream, rank, threshold, poll_interval));
+    Ok(())
+}
+
+
+fn hostname() -> Result<String> {
+    let mut name = [0 as c_char; ADDRESS_LEN];
+    if unsafe { libc::gethostname(name.as_mut_ptr(), name.len()) } != 0 {
+        return Err(anyhow!("could not get the host name"));
+    }
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    Ok(name.to_str()?.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operation(call: &str, peers: &[i32], any: bool, collective: bool) -> Operation {
+        Operation{
+            id: 1,
+            call: call.to_string(),
+            description: String::new(),
+            peers: peers.to_vec(),
+            any,
+            collective,
+            blocked_for: 0.0,
+            backtrace: Vec::new(),
+        }
+    }
+
+    fn p2p(peer: i32) -> Operation {
+        operation("MPI_Recv", &[peer], false, false)
+    }
+
+    /// A collective of `rank` on a communicator with `members`
+    fn collective(call: &str, rank: i32, members: &[i32]) -> Operation {
+        let peers: Vec<i32> = members.iter().copied().filter(|&member| member != rank).collect();
+        operation(call, &peers, false, true)
+    }
+
+    #[test]
+    fn point_to_point_cycle() {
+        let operations = BTreeMap::from([(0, p2p(1)), (1, p2p(2)), (2, p2p(0))]);
+        assert_eq!(find_cycle(&operations), Some(vec![0, 1, 2]));
+    }
+
+    #[test]
+    fn chain_to_a_running_rank_is_no_deadlock() {
+        let operations = BTreeMap::from([(0, p2p(1)), (1, p2p(2))]);
+        assert_eq!(find_cycle(&operations), None);
+    }
+
+    #[test]
+    fn any_source_waits_for_all_candidates() {
+        let any = operation("MPI_Recv", &[1, 2], true, false);
+        // Rank 2 is running and may still send to rank 0
+        let operations = BTreeMap::from([(0, any.clone()), (1, p2p(0))]);
+        assert_eq!(find_cycle(&operations), None);
+
+        let operations = BTreeMap::from([(0, any), (1, p2p(0)), (2, p2p(1))]);
+        assert_eq!(find_cycle(&operations), Some(vec![0, 1]));
+    }
+
+    #[test]
+    fn complete_collective_is_no_deadlock() {
+        let members = [0, 1, 2];
+        let operations: BTreeMap<i32, Operation> = members.iter()
+            .map(|&rank| (rank, collective("MPI_Barrier", rank, &members)))
+            .collect();
+        for (rank, operation) in &operations {
+            assert!(waits_for(*rank, operation, &operations).is_empty());
+        }
+        assert_eq!(find_cycle(&operations), None);
+    }
+
+    #[test]
+    fn collectives_with_different_members_wait_for_each_other() {
+        // Ranks 0 and 1 are in a barrier of all three ranks, rank 2 in one of a communicator with 3
+        let operations = BTreeMap::from([
+            (0, collective("MPI_Barrier", 0, &[0, 1, 2])),
+            (1, collective("MPI_Barrier", 1, &[0, 1, 2])),
+            (2, collective("MPI_Barrier", 2, &[2, 3])),
+            (3, p2p(0)),
+        ]);
+        assert_eq!(waits_for(0, &operations[&0], &operations), vec![2]);
+        assert_eq!(waits_for(2, &operations[&2], &operations), vec![3]);
+        assert_eq!(find_cycle(&operations), Some(vec![0, 2, 3]));
+    }
+
+    #[test]
+    fn different_collectives_on_the_same_members_wait_for_each_other() {
+        let operations = BTreeMap::from([
+            (0, collective("MPI_Barrier", 0, &[0, 1])),
+            (1, collective("MPI_Bcast", 1, &[0, 1])),
+        ]);
+        assert_eq!(find_cycle(&operations), Some(vec![0, 1]));
+    }
+}
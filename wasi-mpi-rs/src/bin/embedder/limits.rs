@@ -0,0 +1,241 @@
+use std::ptr::NonNull;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use loupe::{MemoryUsage, MemoryUsageTracker};
+use wasmer::{BaseTunables, Instance, MemoryError, MemoryType, Pages, TableType, Target, Tunables};
+use wasmer::vm::{Memory, MemoryStyle, Table, TableStyle, VMMemoryDefinition, VMTableDefinition};
+use wasmer_middlewares::metering::{self, MeteringPoints};
+
+/// The global that the metering middleware adds to every metered module
+const REMAINING_POINTS_EXPORT: &str = "wasmer_metering_remaining_points";
+/// How long a rank that timed out gets to trap before the job is aborted right away, e.g. because
+/// it is blocked in an MPI call and doesn't execute any metered code
+const TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+const POINTS_RESET_INTERVAL: Duration = Duration::from_millis(10);
+
+
+/// Resource limits of every rank of a job
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub memory_limit_mb: Option<u64>,
+    /// Number of WebAssembly operators a rank may execute
+    pub fuel: Option<u64>,
+    /// Wall-clock limit in seconds
+    pub timeout: Option<u64>,
+}
+impl Limits {
+    /// Fuel is counted and timeouts trap the instance by the metering middleware
+    pub fn metering(&self) -> bool {
+        self.fuel.is_some() || self.timeout.is_some()
+    }
+}
+
+
+/// Enforces the limits of a rank and records which of them it exceeded
+#[derive(Debug)]
+pub struct LimitTracker {
+    limits: Limits,
+    memory_exceeded: AtomicBool,
+    timed_out: AtomicBool,
+}
+impl LimitTracker {
+    pub fn new(limits: Limits) -> Arc<LimitTracker> {
+        Arc::new(LimitTracker{
+            limits,
+            memory_exceeded: AtomicBool::new(false),
+            timed_out: AtomicBool::new(false),
+        })
+    }
+
+    /// Returns tunables that cap the memories of the instance at the memory limit
+    pub fn tunables(self: &Arc<Self>, target: &Target) -> LimitingTunables {
+        LimitingTunables{
+            base: BaseTunables::for_target(target),
+            // Wasmer can't address more than 4 GiB anyway
+            limit: self.limits.memory_limit_mb.map(|mb| Pages((mb * 16).min(Pages::max_value().0 as u64) as u32)),
+            tracker: self.clone(),
+        }
+    }
+
+    /// Sets the fuel of a freshly created instance
+    pub fn apply(&self, module_path: &str, instance: &Instance) -> Result<()> {
+        if let Some(fuel) = self.limits.fuel {
+            if !is_metered(instance) {
+                bail!("{} was compiled without metering, compile it with `embedder compile --metering` to limit its fuel", module_path);
+            }
+            metering::set_remaining_points(instance, fuel);
+        }
+        Ok(())
+    }
+
+    /// Describes the limit that made the instance fail, if any
+    pub fn exceeded(&self, instance: &Instance) -> Option<String> {
+        if self.timed_out.load(Ordering::SeqCst) {
+            return Some(format!("wall-clock limit of {}s exceeded", self.limits.timeout.unwrap_or_default()));
+        }
+        if let Some(fuel) = self.limits.fuel {
+            if metering::get_remaining_points(instance) == MeteringPoints::Exhausted {
+                return Some(format!("fuel limit of {} exhausted", fuel));
+            }
+        }
+        if let Some(memory_limit_mb) = self.limits.memory_limit_mb {
+            if self.memory_exceeded.load(Ordering::SeqCst) {
+                return Some(format!("memory limit of {} MiB exceeded", memory_limit_mb));
+            }
+        }
+        None
+    }
+
+    /// Starts a thread that traps the instance once the timeout expired. If it doesn't trap within
+    /// a grace period, or can't be trapped as it is not metered, `on_expiry` is called with the
+    /// failure reason from the watchdog thread. The watchdog stops when the result is dropped.
+    pub fn start_watchdog<F>(self: &Arc<Self>, instance: &Instance, on_expiry: F) -> Option<Watchdog>
+    where F: FnOnce(String) + Send + 'static {
+        let timeout = self.limits.timeout?;
+        let (done, finished) = mpsc::channel::<()>();
+        let tracker = self.clone();
+        let instance = if is_metered(instance) { Some(instance.clone()) } else { None };
+
+        thread::spawn(move || {
+            if finished.recv_timeout(Duration::from_secs(timeout)) != Err(RecvTimeoutError::Timeout) {
+                return;
+            }
+            tracker.timed_out.store(true, Ordering::SeqCst);
+
+            if let Some(instance) = instance {
+                // The instance writes its remaining points back while it runs, so they are reset
+                // until the next metered block traps
+                let deadline = Instant::now() + TIMEOUT_GRACE_PERIOD;
+                while Instant::now() < deadline {
+                    metering::set_remaining_points(&instance, 0);
+                    if finished.recv_timeout(POINTS_RESET_INTERVAL) != Err(RecvTimeoutError::Timeout) {
+                        return;
+                    }
+                }
+            }
+            on_expiry(format!("wall-clock limit of {}s exceeded", timeout));
+        });
+
+        Some(Watchdog{ _done: done })
+    }
+}
+
+
+/// Stops the watchdog of a rank when dropped
+pub struct Watchdog {
+    _done: Sender<()>,
+}
+
+
+fn is_metered(instance: &Instance) -> bool {
+    instance.exports.get_global(REMAINING_POINTS_EXPORT).is_ok()
+}
+
+
+/// Creates memories like `BaseTunables`, but with their maximum capped at the memory limit
+pub struct LimitingTunables {
+    base: BaseTunables,
+    limit: Option<Pages>,
+    tracker: Arc<LimitTracker>,
+}
+impl LimitingTunables {
+    fn adjust(&self, ty: &MemoryType) -> MemoryType {
+        let mut ty = ty.clone();
+        if let Some(limit) = self.limit {
+            ty.maximum = Some(ty.maximum.map_or(limit, |maximum| maximum.min(limit)));
+        }
+        ty
+    }
+
+    fn validate(&self, ty: &MemoryType) -> Result<(), MemoryError> {
+        match self.limit {
+            Some(limit) if ty.minimum > limit => {
+                self.tracker.memory_exceeded.store(true, Ordering::SeqCst);
+                Err(MemoryError::MinimumMemoryTooLarge{ min_requested: ty.minimum, max_allowed: limit })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn wrap(&self, memory: Arc<dyn Memory>) -> Arc<dyn Memory> {
+        match self.limit {
+            Some(limit) => Arc::new(LimitedMemory{ memory, limit, tracker: self.tracker.clone() }),
+            None => memory,
+        }
+    }
+}
+impl Tunables for LimitingTunables {
+    fn memory_style(&self, memory: &MemoryType) -> MemoryStyle {
+        self.base.memory_style(&self.adjust(memory))
+    }
+
+    fn table_style(&self, table: &TableType) -> TableStyle {
+        self.base.table_style(table)
+    }
+
+    fn create_host_memory(&self, ty: &MemoryType, style: &MemoryStyle) -> Result<Arc<dyn Memory>, MemoryError> {
+        self.validate(ty)?;
+        Ok(self.wrap(self.base.create_host_memory(&self.adjust(ty), style)?))
+    }
+
+    unsafe fn create_vm_memory(&self, ty: &MemoryType, style: &MemoryStyle, vm_definition_location: NonNull<VMMemoryDefinition>) -> Result<Arc<dyn Memory>, MemoryError> {
+        self.validate(ty)?;
+        Ok(self.wrap(self.base.create_vm_memory(&self.adjust(ty), style, vm_definition_location)?))
+    }
+
+    fn create_host_table(&self, ty: &TableType, style: &TableStyle) -> Result<Arc<dyn Table>, String> {
+        self.base.create_host_table(ty, style)
+    }
+
+    unsafe fn create_vm_table(&self, ty: &TableType, style: &TableStyle, vm_definition_location: NonNull<VMTableDefinition>) -> Result<Arc<dyn Table>, String> {
+        self.base.create_vm_table(ty, style, vm_definition_location)
+    }
+}
+impl MemoryUsage for LimitingTunables {
+    fn size_of_val(&self, tracker: &mut dyn MemoryUsageTracker) -> usize {
+        std::mem::size_of_val(self) + self.base.size_of_val(tracker)
+    }
+}
+
+
+/// Records `memory.grow`s beyond the limit, which the module only sees as an allocation failure
+#[derive(Debug)]
+struct LimitedMemory {
+    memory: Arc<dyn Memory>,
+    limit: Pages,
+    tracker: Arc<LimitTracker>,
+}
+impl Memory for LimitedMemory {
+    fn ty(&self) -> MemoryType {
+        self.memory.ty()
+    }
+
+    fn style(&self) -> &MemoryStyle {
+        self.memory.style()
+    }
+
+    fn size(&self) -> Pages {
+        self.memory.size()
+    }
+
+    fn grow(&self, delta: Pages) -> Result<Pages, MemoryError> {
+        if self.memory.size().0 as u64 + delta.0 as u64 > self.limit.0 as u64 {
+            self.tracker.memory_exceeded.store(true, Ordering::SeqCst);
+        }
+        self.memory.grow(delta)
+    }
+
+    fn vmmemory(&self) -> NonNull<VMMemoryDefinition> {
+        self.memory.vmmemory()
+    }
+}
+impl MemoryUsage for LimitedMemory {
+    fn size_of_val(&self, tracker: &mut dyn MemoryUsageTracker) -> usize {
+        std::mem::size_of_val(self) + self.memory.size_of_val(tracker)
+    }
+}
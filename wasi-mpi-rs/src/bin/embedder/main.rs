@@ -0,0 +1,448 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use reqwest;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use structopt::{StructOpt};
+use wasmer::{ChainableNamedResolver, Instance, Module, Store, Target};
+use wasmer_wasi::{WasiError, WasiState};
+
+use wasm_mpi_rs;
+use wasm_mpi_rs::{Env, ImportIssue, ImportStubs, MpiChecker, ProfileOutput, Profiler, Replayer, Tracer};
+
+use backend::{Backend, CompilerKind, EngineKind, DEFAULT_COMPILER, DEFAULT_ENGINE};
+use cache::{CacheOpt, ModuleCache, DEFAULT_CACHE_SIZE_MB};
+use check::CheckOpt;
+use collective::CompileOn;
+use compile::CompileOpt;
+use deadlock::{CallMonitor, DeadlockOpt};
+use limits::{LimitTracker, Limits};
+use output::OutputOpt;
+use symbols::Symbolizer;
+use trace::TraceOpt;
+use wasi::WasiOpt;
+
+mod backend;
+mod cache;
+mod check;
+mod collective;
+mod compile;
+mod deadlock;
+mod limits;
+mod output;
+mod symbols;
+mod trace;
+mod wasi;
+
+
+#[derive(StructOpt)]
+#[structopt(name = "embedder", about = "Run WASI-MPI modules")]
+#[structopt(setting = structopt::clap::AppSettings::AllowLeadingHyphen)]
+struct Opt {
+    #[structopt(short, long)]
+    pub timings: bool,
+    #[structopt(flatten)]
+    pub wasi: WasiOpt,
+    #[structopt(flatten)]
+    pub output: OutputOpt,
+    #[structopt(flatten)]
+    pub deadlock: DeadlockOpt,
+    #[structopt(short, long)]
+    pub callback: Option<String>,
+    #[structopt(long, default_value = DEFAULT_COMPILER, possible_values = CompilerKind::VARIANTS)]
+    pub compiler: CompilerKind,
+    #[structopt(long, default_value = DEFAULT_ENGINE, possible_values = EngineKind::VARIANTS)]
+    pub engine: EngineKind,
+    /// Compile the module on every rank, or on one rank per node or per job and broadcast it
+    #[structopt(long, default_value = "each", possible_values = CompileOn::VARIANTS)]
+    pub compile_on: CompileOn,
+    /// Defaults to $WASI_MPI_CACHE_DIR, $XDG_CACHE_HOME/wasi-mpi or ~/.cache/wasi-mpi
+    #[structopt(long, parse(from_os_str))]
+    pub cache_dir: Option<PathBuf>,
+    /// Least recently used modules are removed from the cache beyond this size
+    #[structopt(long, default_value = DEFAULT_CACHE_SIZE_MB)]
+    pub cache_size_mb: u64,
+    /// Replace functions that can't be resolved with stubs that trap when they are called
+    #[structopt(long)]
+    pub allow_missing_imports: bool,
+    /// Keep the module's DWARF debug info to show source lines in trap backtraces
+    #[structopt(long)]
+    pub debug_info: bool,
+    /// Cap the memory of every rank, growing it beyond the limit fails
+    #[structopt(long)]
+    pub memory_limit_mb: Option<u64>,
+    /// Trap a rank once it executed this many WebAssembly operators
+    #[structopt(long)]
+    pub fuel: Option<u64>,
+    /// Trap a rank and abort the job after this many seconds
+    #[structopt(long)]
+    pub timeout: Option<u64>,
+    /// Check the MPI usage of the module, e.g. that collective calls match across ranks
+    #[structopt(long)]
+    pub mpi_check: bool,
+    /// Profile the MPI calls of the module and print a report of all ranks at MPI_Finalize
+    #[structopt(long)]
+    pub profile: bool,
+    /// Write the profile report as JSON to this file instead of printing it
+    #[structopt(long, parse(from_os_str))]
+    pub profile_json: Option<PathBuf>,
+    /// Write a Chrome trace of every rank to rank-N.json in this directory
+    #[structopt(long, parse(from_os_str))]
+    pub trace: Option<PathBuf>,
+    /// Record the matching of wildcard receives, Waitany/Testany and MPI_Wtime of every rank to
+    /// rank-N.log in this directory
+    #[structopt(long, parse(from_os_str), conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+    /// Replay the matching order recorded with --record from this directory
+    #[structopt(long, parse(from_os_str))]
+    pub replay: Option<PathBuf>,
+    #[structopt(name = "MODULE_PATH")]
+    pub module_path: String,
+    #[structopt(name = "MODULE_ARGS")]
+    pub module_args: Vec<String>,
+}
+impl Opt {
+    fn limits(&self) -> Limits {
+        Limits{ memory_limit_mb: self.memory_limit_mb, fuel: self.fuel, timeout: self.timeout }
+    }
+
+    fn profiler(&self) -> Option<Profiler> {
+        match (&self.profile_json, self.profile) {
+            (Some(path), _) => Some(Profiler::new(ProfileOutput::Json(path.clone()))),
+            (None, true) => Some(Profiler::new(ProfileOutput::Text)),
+            (None, false) => None,
+        }
+    }
+
+    fn replayer(&self) -> Result<Option<Replayer>> {
+        let rank_path = |dir: &Path| dir.join(format!("rank-{}.log", collective::init_world_rank()));
+        match (&self.record, &self.replay) {
+            (Some(dir), _) => {
+                fs::create_dir_all(dir)?;
+                Ok(Some(Replayer::record(&rank_path(dir))?))
+            }
+            (None, Some(dir)) => Ok(Some(Replayer::replay(&rank_path(dir))?)),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+
+#[derive(Copy, Clone, Deserialize, PartialEq, Serialize)]
+enum JobState {
+    Submitted,
+    Running,
+    Failed,
+    Completed,
+}
+
+
+#[derive(Clone, Deserialize, Serialize)]
+struct JobCallback {
+    pub state: JobState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+
+/// How the execution of a module ended
+enum Exit {
+    /// `_start` returned or the module called `proc_exit`
+    Code(u32),
+    Trap {
+        message: String,
+        /// Symbolized guest frames, innermost first
+        backtrace: Vec<String>,
+        last_call: Option<&'static str>,
+    },
+    /// The module exceeded one of its resource limits
+    Limit(String),
+}
+
+
+fn rank_prefix() -> String {
+    match collective::world_rank() {
+        Some(rank) => format!("[rank {}] ", rank),
+        None => String::new(),
+    }
+}
+
+
+fn report_state(http_client: &reqwest::blocking::Client, callback: &str, state: JobState, reason: Option<String>) {
+    let job_callback = JobCallback{ state, reason };
+    if let Err(e) = http_client.put(callback).json(&job_callback).send().and_then(|response| response.error_for_status()) {
+        eprintln!("{}Could not report the job state to {}: {}", rank_prefix(), callback, e);
+    }
+}
+
+
+fn print_stub_summary(stubs: &ImportStubs) {
+    if stubs.stubbed.is_empty() {
+        return;
+    }
+
+    eprintln!("{} unresolved imports were stubbed: {}", stubs.stubbed.len(), stubs.stubbed.join(", "));
+    for (name, calls) in stubs.calls() {
+        eprintln!("    {} was called {} times", name, calls);
+    }
+}
+
+
+/// Loads, instantiates and runs the module. Errors are failures before the module could be run.
+fn run(opt: &Opt, http_client: &reqwest::blocking::Client) -> Result<Exit> {
+    let wasm_bytes = fs::read(&opt.module_path)?;
+    let limits = LimitTracker::new(opt.limits());
+
+    // The clocks of the ranks are aligned before anything is recorded
+    let tracer = match &opt.trace {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            let path = trace::rank_trace_path(dir, collective::init_world_rank());
+            let tracer = Arc::new(Tracer::new());
+            tracer.synchronize();
+            Some((tracer, path))
+        }
+        None => None,
+    };
+    let trace_phase = |name: &str, start: Instant| {
+        if let Some((tracer, _)) = &tracer {
+            tracer.complete("phase", name, start, json!({ "module": opt.module_path }));
+        }
+    };
+
+    let load_start = Instant::now();
+    let (store, module, symbolizer) = if let Some(artifact_engine) = EngineKind::of_artifact(&wasm_bytes) {
+        // Precompiled by `embedder compile`, no compiler is needed
+        let engine = artifact_engine.headless_engine()?;
+        let store = Store::new_with_tunables(&*engine, limits.tunables(&Target::default()));
+        let module = unsafe { Module::deserialize(&store, &wasm_bytes)? };
+        (store, module, Symbolizer::empty())
+    } else {
+        let backend = Backend{ compiler: opt.compiler, engine: opt.engine, metering: opt.limits().metering() };
+        let target = Target::default();
+        let engine = backend.engine(target.clone())?;
+        let store = Store::new_with_tunables(&*engine, limits.tunables(&target));
+
+        let module = collective::load_module(&store, opt.compile_on, || {
+            let cache = ModuleCache::new(cache::cache_dir(opt.cache_dir.as_deref()), opt.cache_size_mb * 1024 * 1024)?;
+            let key = ModuleCache::key(&wasm_bytes, &backend, &target);
+            if let Ok(module) = unsafe { cache.load(&store, &key) } {
+                return Ok(module);
+            }
+
+            let time_before = Instant::now();
+            let module = Module::from_binary(&store, &wasm_bytes)?;
+            let time_after = Instant::now();
+            trace_phase("compile", time_before);
+            if opt.timings {
+                println!("Compile took {}ms", (time_after - time_before).as_millis())
+            }
+
+            // The module is still usable if it can't be cached
+            if let Err(e) = cache.store(&key, &opt.module_path, &backend, &target, &module) {
+                eprintln!("Could not cache {}: {}", opt.module_path, e);
+            }
+            Ok(module)
+        })?;
+
+        // A module without symbols still runs, its traps just show function indices
+        let symbolizer = Symbolizer::new(&wasm_bytes, opt.debug_info).unwrap_or_else(|e| {
+            eprintln!("Could not read the symbols of {}: {}", opt.module_path, e);
+            Symbolizer::empty()
+        });
+        (store, module, symbolizer)
+    };
+    let symbolizer = Arc::new(Mutex::new(symbolizer));
+    trace_phase("load module", load_start);
+    // Large modules would otherwise stay in memory on every rank for the whole run
+    drop(wasm_bytes);
+
+    let mut wasi_state = WasiState::new(&opt.module_path);
+    wasi_state.args(&opt.module_args);
+    wasi::configure(&opt.wasi, &mut wasi_state)?;
+    output::configure(&opt.output, &mut wasi_state)?;
+    if let Some((tracer, _)) = &tracer {
+        trace::configure(tracer, &mut wasi_state);
+    }
+
+    let mut wasi_env = wasi_state.finalize()?;
+    if let Some((tracer, _)) = &tracer {
+        trace::trace_stdio(tracer, &wasi_env)?;
+    }
+    let mut import_object = wasi_env.import_object(&module)?;
+
+    let mut env = Env::new();
+    // Blocked calls include the communication of the other tools
+    let call_monitor = opt.deadlock.deadlock_timeout.map(|_| Arc::new(CallMonitor::default()));
+    if let Some(call_monitor) = &call_monitor {
+        env.tools.push(call_monitor.clone());
+    }
+    // The checks communicate themselves, so they go first to stay out of the profile and trace
+    if opt.mpi_check {
+        env.tools.push(Arc::new(MpiChecker::new()));
+    }
+    let profiler = opt.profiler().map(Arc::new);
+    if let Some(profiler) = &profiler {
+        env.tools.push(profiler.clone());
+    }
+    if let Some((tracer, _)) = &tracer {
+        env.tools.push(tracer.clone());
+    }
+    // Replaying vetoes calls, which the other tools still have to see
+    let replayer = opt.replayer()?.map(Arc::new);
+    if let Some(replayer) = &replayer {
+        env.tools.push(replayer.clone());
+    }
+    let mut mpi_imports = wasm_mpi_rs::imports(&store, &env);
+    wasm_mpi_rs::register_exception_imports(&store, &env, &module, &mut mpi_imports);
+    import_object.register(wasm_mpi_rs::IMPORT_NAMESPACE, mpi_imports);
+
+    // Report every unresolved import at once instead of the first link error of the instantiation
+    let issues = wasm_mpi_rs::check_imports(&store, &module, &import_object);
+    let stubs = ImportStubs::new(&store, if opt.allow_missing_imports { &issues } else { &[] });
+    let issues: Vec<ImportIssue> = issues.into_iter()
+        .filter(|issue| !opt.allow_missing_imports || !ImportStubs::covers(issue))
+        .collect();
+    if !issues.is_empty() {
+        for issue in &issues {
+            eprintln!("{}", issue);
+        }
+        bail!("{} imports of {} can't be resolved", issues.len(), opt.module_path);
+    }
+
+    // Receives whose status the module ignores are recorded with a status allocated in the module
+    if opt.record.is_some() && !module.exports().any(|export| export.name() == "malloc") {
+        bail!("{} doesn't export malloc(), which --record needs. Link it with -Wl,--export=malloc.", opt.module_path);
+    }
+
+    let resolver = import_object.chain_back(stubs.imports.clone());
+    let instantiate_start = Instant::now();
+    let instance = Instance::new(&module, &resolver)?;
+    limits.apply(&opt.module_path, &instance)?;
+    trace_phase("instantiate", instantiate_start);
+    let start = instance.exports.get_function("_start")?;
+
+    if let Some(callback) = &opt.callback {
+        let job_callback = JobCallback{ state: JobState::Running, reason: None };
+        http_client.put(callback).json(&job_callback).send()?.error_for_status()?;
+    }
+
+    if let Some(call_monitor) = call_monitor {
+        deadlock::start(&opt.deadlock, call_monitor, symbolizer.clone())?;
+    }
+
+    let watchdog_client = http_client.clone();
+    let watchdog_callback = opt.callback.clone();
+    let watchdog = limits.start_watchdog(&instance, move |reason| {
+        // The rank could not be trapped, e.g. as it waits in an MPI call, so the job is ended here
+        eprintln!("{}{}", rank_prefix(), reason);
+        if let Some(callback) = &watchdog_callback {
+            report_state(&watchdog_client, callback, JobState::Failed, Some(reason));
+        }
+        collective::abort_mpi(1);
+        process::exit(1)
+    });
+    let run_start = Instant::now();
+    let result = start.call(&[]);
+    trace_phase("run", run_start);
+    drop(watchdog);
+    print_stub_summary(&stubs);
+
+    if let Some(replayer) = &replayer {
+        replayer.finish();
+    }
+    // Written before MPI_Abort, so traps can be looked at in the trace as well
+    if let Some((tracer, path)) = &tracer {
+        if let Err(e) = tracer.write(path) {
+            eprintln!("{}Could not write the trace to {}: {}", rank_prefix(), path.display(), e);
+        }
+    }
+
+    let exit = match result {
+        Ok(_) => Exit::Code(0),
+        Err(e) => match e.downcast::<WasiError>() {
+            Ok(WasiError::Exit(code)) => Exit::Code(code),
+            Ok(e) => return Err(e.into()),
+            Err(trap) => Exit::Trap{
+                message: trap.message(),
+                backtrace: {
+                    let symbolizer = symbolizer.lock().unwrap();
+                    trap.trace().iter().map(|frame| symbolizer.frame(frame)).collect()
+                },
+                last_call: env.calls.last_call(),
+            },
+        },
+    };
+
+    // Modules that exit without finalizing MPI are still reported
+    if let (Exit::Code(0), Some(profiler)) = (&exit, &profiler) {
+        profiler.report();
+    }
+
+    // A module that exceeded its memory limit can still handle the failed allocation and succeed
+    match (exit, limits.exceeded(&instance)) {
+        (Exit::Code(0), _) => Ok(Exit::Code(0)),
+        (_, Some(limit)) => Ok(Exit::Limit(limit)),
+        (exit, None) => Ok(exit),
+    }
+}
+
+
+fn main() -> Result<()> {
+    // Subcommands are dispatched by hand, as the module path of the run mode is positional
+    match env::args().nth(1).as_deref() {
+        Some("cache") => return cache::run_command(&CacheOpt::from_iter(env::args().skip(1))),
+        Some("check") => return check::check(&CheckOpt::from_iter(env::args().skip(1))),
+        Some("compile") => return compile::compile(&CompileOpt::from_iter(env::args().skip(1))),
+        Some("trace") => return trace::run_command(&TraceOpt::from_iter(env::args().skip(1))),
+        _ => {}
+    }
+
+    let opt = Opt::from_args();
+    let http_client = reqwest::blocking::Client::builder().no_proxy().build()?;
+
+    let (exit_code, reason) = match run(&opt, &http_client) {
+        Ok(Exit::Code(0)) => (0, None),
+        Ok(Exit::Code(code)) => (code as i32, Some(format!("exited with code {}", code))),
+        Ok(Exit::Trap{ message, backtrace, last_call }) => {
+            let prefix = rank_prefix();
+            eprintln!("{}trap: {}", prefix, message);
+            eprintln!("{}last MPI call: {}", prefix, last_call.unwrap_or("none"));
+            eprintln!("{}backtrace:", prefix);
+            for (i, frame) in backtrace.iter().enumerate() {
+                eprintln!("{}    {}: {}", prefix, i, frame);
+            }
+            (1, Some(format!("trap: {}", message)))
+        }
+        Ok(Exit::Limit(limit)) => {
+            eprintln!("{}{}", rank_prefix(), limit);
+            (1, Some(limit))
+        }
+        Err(e) => {
+            eprintln!("{}Error: {:?}", rank_prefix(), e);
+            (1, Some(e.to_string()))
+        }
+    };
+
+    if let Some(callback) = &opt.callback {
+        let state = if exit_code == 0 { JobState::Completed } else { JobState::Failed };
+        report_state(&http_client, callback, state, reason);
+    }
+
+    if exit_code == 0 {
+        // MPI might have been initialized by the embedder, e.g. to compile the module collectively or
+        // to expand `{rank}` in paths, so the module might not have finalized it
+        collective::finalize_mpi();
+    } else {
+        // Peer ranks could otherwise wait forever in collectives with this rank
+        collective::abort_mpi(exit_code);
+    }
+
+    process::exit(exit_code)
+}
@@ -0,0 +1,206 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use structopt::StructOpt;
+use wasmer_wasi::{FsError, VirtualFile, WasiStateBuilder};
+
+use crate::collective;
+
+
+/// Where the stdout and stderr of the module go. By default they are written straight to the
+/// embedder's, so the output of several ranks can interleave mid-line.
+#[derive(StructOpt)]
+pub struct OutputOpt {
+    /// Prefix every line the module writes with `[rank N]`
+    #[structopt(long)]
+    pub rank_prefix: bool,
+    /// Write the stdout and stderr of every rank to rank-N.out and rank-N.err in this directory
+    #[structopt(long, parse(from_os_str))]
+    pub output_dir: Option<PathBuf>,
+    /// Only keep the output of these ranks, e.g. 0 or 0,4-7
+    #[structopt(long, use_delimiter = true)]
+    pub output_ranks: Vec<RankRange>,
+}
+impl OutputOpt {
+    fn is_default(&self) -> bool {
+        !self.rank_prefix && self.output_dir.is_none() && self.output_ranks.is_empty()
+    }
+}
+
+
+/// A single rank or an inclusive range of ranks
+pub struct RankRange {
+    first: i32,
+    last: i32,
+}
+impl RankRange {
+    fn contains(&self, rank: i32) -> bool {
+        self.first <= rank && rank <= self.last
+    }
+}
+impl FromStr for RankRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid rank range {}, expected N or N-M", s);
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let first = first.parse::<i32>().map_err(|_| invalid())?;
+        let last = last.parse::<i32>().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid());
+        }
+        Ok(RankRange{ first, last })
+    }
+}
+
+
+#[derive(Debug)]
+enum Sink {
+    Stdout,
+    Stderr,
+    File(fs::File),
+    Discard,
+}
+impl Sink {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Stdout => io::stdout().write_all(buf),
+            Sink::Stderr => io::stderr().write_all(buf),
+            Sink::File(file) => file.write_all(buf),
+            Sink::Discard => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Stdout => io::stdout().flush(),
+            Sink::Stderr => io::stderr().flush(),
+            Sink::File(file) => file.flush(),
+            Sink::Discard => Ok(()),
+        }
+    }
+}
+
+
+/// Line-buffered stdout or stderr of the module. Every line is written with a single write, so
+/// lines of different ranks don't interleave when mpirun forwards them.
+#[derive(Debug)]
+struct RankOutput {
+    sink: Sink,
+    prefix: Option<String>,
+    line: Vec<u8>,
+}
+impl RankOutput {
+    fn new(sink: Sink, prefix: Option<String>) -> RankOutput {
+        RankOutput{ sink, prefix, line: Vec::new() }
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        match &self.prefix {
+            Some(prefix) => {
+                let mut prefixed = Vec::with_capacity(prefix.len() + line.len());
+                prefixed.extend_from_slice(prefix.as_bytes());
+                prefixed.extend_from_slice(line);
+                self.sink.write_all(&prefixed)
+            }
+            None => self.sink.write_all(line),
+        }
+    }
+}
+impl Write for RankOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        if let Some(end) = self.line.iter().rposition(|&byte| byte == b'\n') {
+            let lines: Vec<u8> = self.line.drain(..=end).collect();
+            for line in lines.split_inclusive(|&byte| byte == b'\n') {
+                self.write_line(line)?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Partial lines stay buffered until they are complete or the module exits
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+impl Drop for RankOutput {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            let _ = self.write_line(&line).and_then(|_| self.sink.flush());
+        }
+    }
+}
+impl Read for RankOutput {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "can not read from an output stream"))
+    }
+}
+impl Seek for RankOutput {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Err(io::Error::new(io::ErrorKind::Other, "can not seek an output stream"))
+    }
+}
+impl VirtualFile for RankOutput {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn set_len(&mut self, _new_size: u64) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn unlink(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    fn bytes_available(&self) -> Result<usize, FsError> {
+        Ok(0)
+    }
+}
+
+
+/// Installs the stdout and stderr of the module. MPI is initialized to find out the rank unless
+/// the default output is used.
+pub fn configure(opt: &OutputOpt, wasi_state: &mut WasiStateBuilder) -> Result<()> {
+    if opt.is_default() {
+        return Ok(());
+    }
+
+    let rank = collective::init_world_rank();
+    let prefix = if opt.rank_prefix { Some(format!("[rank {}] ", rank)) } else { None };
+    let shown = opt.output_ranks.is_empty() || opt.output_ranks.iter().any(|range| range.contains(rank));
+
+    let (stdout, stderr) = match &opt.output_dir {
+        _ if !shown => (Sink::Discard, Sink::Discard),
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            let create = |extension: &str| {
+                let path = dir.join(format!("rank-{}.{}", rank, extension));
+                fs::File::create(&path).map_err(|e| anyhow!("could not create {}: {}", path.display(), e))
+            };
+            (Sink::File(create("out")?), Sink::File(create("err")?))
+        }
+        None => (Sink::Stdout, Sink::Stderr),
+    };
+
+    wasi_state.stdout(Box::new(RankOutput::new(stdout, prefix.clone())));
+    wasi_state.stderr(Box::new(RankOutput::new(stderr, prefix)));
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use addr2line::Context;
+use addr2line::gimli::{Dwarf, EndianSlice, LittleEndian, SectionId};
+use anyhow::{anyhow, Result};
+use wasmer::FrameInfo;
+use wasmparser::{Name, NameSectionReader, Parser, Payload};
+
+type DwarfReader = EndianSlice<'static, LittleEndian>;
+
+
+/// Resolves the guest frames of a trap to function names from the module's `name` section and,
+/// if requested, to source locations from its DWARF sections.
+pub struct Symbolizer {
+    function_names: HashMap<u32, String>,
+    /// DWARF addresses of WebAssembly modules are offsets into the contents of the code section
+    code_section_offset: usize,
+    debug_info: Option<Context<DwarfReader>>,
+}
+impl Symbolizer {
+    /// Without module bytes, e.g. for precompiled artifacts, only the names kept by wasmer are used
+    pub fn empty() -> Symbolizer {
+        Symbolizer{
+            function_names: HashMap::new(),
+            code_section_offset: 0,
+            debug_info: None,
+        }
+    }
+
+    pub fn new(wasm_bytes: &[u8], debug_info: bool) -> Result<Symbolizer> {
+        let mut function_names = HashMap::new();
+        let mut code_section_offset = 0;
+        let mut dwarf_sections: HashMap<&str, &[u8]> = HashMap::new();
+
+        for payload in Parser::new(0).parse_all(wasm_bytes) {
+            match payload? {
+                Payload::CodeSectionStart { range, .. } => code_section_offset = range.start,
+                Payload::CustomSection { name: "name", data, data_offset, .. } => {
+                    for name in NameSectionReader::new(data, data_offset)? {
+                        if let Name::Function(names) = name? {
+                            let mut map = names.get_map()?;
+                            for _ in 0..map.get_count() {
+                                let naming = map.read()?;
+                                function_names.insert(naming.index, naming.name.to_string());
+                            }
+                        }
+                    }
+                }
+                Payload::CustomSection { name, data, .. } if debug_info && name.starts_with(".debug_") => {
+                    dwarf_sections.insert(name, data);
+                }
+                _ => {}
+            }
+        }
+
+        let debug_info = if dwarf_sections.is_empty() {
+            None
+        } else {
+            // The debug info is needed until the process exits, so it is not worth tracking its lifetime
+            let dwarf = Dwarf::load(|id: SectionId| -> Result<DwarfReader, addr2line::gimli::Error> {
+                let data: &'static [u8] = match dwarf_sections.get(id.name()) {
+                    Some(data) => Box::leak(data.to_vec().into_boxed_slice()),
+                    None => &[],
+                };
+                Ok(EndianSlice::new(data, LittleEndian))
+            }).map_err(|e| anyhow!("invalid DWARF debug info: {}", e))?;
+            Some(Context::from_dwarf(dwarf).map_err(|e| anyhow!("invalid DWARF debug info: {}", e))?)
+        };
+
+        Ok(Symbolizer{ function_names, code_section_offset, debug_info })
+    }
+
+    fn function_name(&self, frame: &FrameInfo) -> String {
+        match self.function_names.get(&frame.func_index()) {
+            Some(name) => name.clone(),
+            None => match frame.function_name() {
+                Some(name) => name.to_string(),
+                None => format!("<function {}>", frame.func_index()),
+            },
+        }
+    }
+
+    fn location(&self, frame: &FrameInfo) -> Option<String> {
+        let context = self.debug_info.as_ref()?;
+        let address = frame.module_offset().checked_sub(self.code_section_offset)?;
+        let location = context.find_location(address as u64).ok()??;
+
+        let file = location.file?;
+        Some(match (location.line, location.column) {
+            (Some(line), Some(column)) => format!("{}:{}:{}", file, line, column),
+            (Some(line), None) => format!("{}:{}", file, line),
+            _ => file.to_string(),
+        })
+    }
+
+    /// Formats a frame as `function at file:line:column`, or with its module offset if the source
+    /// location is unknown
+    pub fn frame(&self, frame: &FrameInfo) -> String {
+        match self.location(frame) {
+            Some(location) => format!("{} at {}", self.function_name(frame), location),
+            None => format!("{} (module offset {:#x})", self.function_name(frame), frame.module_offset()),
+        }
+    }
+}
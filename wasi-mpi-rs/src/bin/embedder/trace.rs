@@ -0,0 +1,278 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use structopt::StructOpt;
+use wasmer_vfs::{host_fs, FileDescriptor, FileOpener, FileSystem, FsError, Metadata, OpenOptions, OpenOptionsConfig, ReadDir, VirtualFile};
+use wasmer_wasi::{WasiEnv, WasiStateBuilder};
+
+use wasm_mpi_rs::Tracer;
+
+/// Name of the merged trace written by `embedder trace merge`
+const MERGED_TRACE: &str = "trace.json";
+
+
+/// Returns the path of the trace of a rank in the trace directory
+pub fn rank_trace_path(dir: &Path, rank: i32) -> PathBuf {
+    dir.join(format!("rank-{}.json", rank))
+}
+
+
+/// A host file whose reads, writes and seeks are recorded as WASI I/O events
+struct TracedFile {
+    path: String,
+    file: Box<dyn VirtualFile>,
+    tracer: Arc<Tracer>,
+}
+impl TracedFile {
+    fn new(path: String, file: Box<dyn VirtualFile>, tracer: Arc<Tracer>) -> TracedFile {
+        TracedFile{ path, file, tracer }
+    }
+
+    fn record<T>(&self, name: &str, start: Instant, result: &io::Result<T>, args: Value) {
+        let mut args = args;
+        args["path"] = json!(self.path);
+        if let Err(e) = result {
+            args["error"] = json!(e.to_string());
+        }
+        self.tracer.complete("wasi", name, start, args);
+    }
+}
+impl fmt::Debug for TracedFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TracedFile").field("path", &self.path).field("file", &self.file).finish()
+    }
+}
+impl Read for TracedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let result = self.file.read(buf);
+        self.record("fd_read", start, &result, json!({ "bytes": result.as_ref().ok() }));
+        result
+    }
+}
+impl Write for TracedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let result = self.file.write(buf);
+        self.record("fd_write", start, &result, json!({ "bytes": result.as_ref().ok() }));
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+impl Seek for TracedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let start = Instant::now();
+        let result = self.file.seek(pos);
+        self.record("fd_seek", start, &result, json!({ "offset": result.as_ref().ok() }));
+        result
+    }
+}
+impl VirtualFile for TracedFile {
+    fn last_accessed(&self) -> u64 {
+        self.file.last_accessed()
+    }
+
+    fn last_modified(&self) -> u64 {
+        self.file.last_modified()
+    }
+
+    fn created_time(&self) -> u64 {
+        self.file.created_time()
+    }
+
+    fn size(&self) -> u64 {
+        self.file.size()
+    }
+
+    fn set_len(&mut self, new_size: u64) -> Result<(), FsError> {
+        self.file.set_len(new_size)
+    }
+
+    fn unlink(&mut self) -> Result<(), FsError> {
+        self.file.unlink()
+    }
+
+    fn sync_to_disk(&self) -> Result<(), FsError> {
+        let start = Instant::now();
+        let result = self.file.sync_to_disk();
+        self.tracer.complete("wasi", "fd_sync", start, json!({ "path": self.path }));
+        result
+    }
+
+    fn bytes_available(&self) -> Result<usize, FsError> {
+        self.file.bytes_available()
+    }
+
+    fn get_fd(&self) -> Option<FileDescriptor> {
+        self.file.get_fd()
+    }
+}
+
+
+/// The host filesystem, with the files the module opens wrapped in `TracedFile`
+struct TracedFileSystem {
+    fs: host_fs::FileSystem,
+    tracer: Arc<Tracer>,
+}
+impl fmt::Debug for TracedFileSystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TracedFileSystem").field("fs", &self.fs).finish()
+    }
+}
+impl FileSystem for TracedFileSystem {
+    fn read_dir(&self, path: &Path) -> Result<ReadDir, FsError> {
+        self.fs.read_dir(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<(), FsError> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<(), FsError> {
+        self.fs.remove_dir(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), FsError> {
+        self.fs.rename(from, to)
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata, FsError> {
+        self.fs.metadata(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), FsError> {
+        self.fs.remove_file(path)
+    }
+
+    fn new_open_options(&self) -> OpenOptions {
+        OpenOptions::new(Box::new(TracedFileOpener{ tracer: self.tracer.clone() }))
+    }
+}
+
+
+struct TracedFileOpener {
+    tracer: Arc<Tracer>,
+}
+impl FileOpener for TracedFileOpener {
+    fn open(&mut self, path: &Path, conf: &OpenOptionsConfig) -> Result<Box<dyn VirtualFile>, FsError> {
+        let start = Instant::now();
+        let result = host_fs::FileSystem.new_open_options()
+            .read(conf.read())
+            .write(conf.write())
+            .create_new(conf.create_new())
+            .create(conf.create())
+            .append(conf.append())
+            .truncate(conf.truncate())
+            .open(path);
+
+        let path = path.display().to_string();
+        let mut args = json!({ "path": path, "read": conf.read(), "write": conf.write() });
+        if let Err(e) = &result {
+            args["error"] = json!(e.to_string());
+        }
+        self.tracer.complete("wasi", "path_open", start, args);
+        Ok(Box::new(TracedFile::new(path, result?, self.tracer.clone())))
+    }
+}
+
+
+/// Records the file I/O of the module
+pub fn configure(tracer: &Arc<Tracer>, wasi_state: &mut WasiStateBuilder) {
+    wasi_state.set_fs(Box::new(TracedFileSystem{ fs: host_fs::FileSystem, tracer: tracer.clone() }));
+}
+
+
+/// Records the I/O on stdin, stdout and stderr, whichever files were installed for them
+pub fn trace_stdio(tracer: &Arc<Tracer>, wasi_env: &WasiEnv) -> Result<()> {
+    let mut state = wasi_env.state();
+    for &(fd, name) in &[(0, "<stdin>"), (1, "<stdout>"), (2, "<stderr>")] {
+        let stdio = match fd {
+            0 => state.fs.stdin_mut(),
+            1 => state.fs.stdout_mut(),
+            _ => state.fs.stderr_mut(),
+        };
+        let stdio = stdio.map_err(|e| anyhow!("could not trace {}: {}", name, e))?;
+        if let Some(file) = stdio.take() {
+            *stdio = Some(Box::new(TracedFile::new(name.to_string(), file, tracer.clone())));
+        }
+    }
+    Ok(())
+}
+
+
+#[derive(StructOpt)]
+#[structopt(name = "embedder trace", about = "Work with the traces written by --trace")]
+pub struct TraceOpt {
+    #[structopt(subcommand)]
+    pub command: TraceCommand,
+}
+
+
+#[derive(StructOpt)]
+pub enum TraceCommand {
+    /// Merge the traces of all ranks into one, e.g. to open them in Perfetto at once
+    Merge {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+        /// Defaults to trace.json in the trace directory
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+}
+
+
+/// The traces are already aligned to the clock of rank 0, so merging them only joins their events
+fn merge(dir: &Path, output: &Path) -> Result<usize> {
+    let mut events = Vec::new();
+    let mut ranks = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_rank_trace = path.file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| name.starts_with("rank-") && name.ends_with(".json"));
+        if !is_rank_trace {
+            continue;
+        }
+
+        let mut trace: Value = serde_json::from_slice(&fs::read(&path)?)
+            .map_err(|e| anyhow!("invalid trace {}: {}", path.display(), e))?;
+        match trace["traceEvents"].take() {
+            Value::Array(rank_events) => events.extend(rank_events),
+            _ => bail!("{} is not a trace written by --trace", path.display()),
+        }
+        ranks.push(trace["otherData"].take());
+    }
+    if ranks.is_empty() {
+        bail!("no traces in {}", dir.display());
+    }
+
+    let rank_count = ranks.len();
+    let trace = json!({
+        "traceEvents": events,
+        "displayTimeUnit": "ms",
+        "otherData": { "ranks": ranks },
+    });
+    fs::write(output, serde_json::to_vec(&trace)?)?;
+    Ok(rank_count)
+}
+
+
+pub fn run_command(opt: &TraceOpt) -> Result<()> {
+    match &opt.command {
+        TraceCommand::Merge { dir, output } => {
+            let output = output.clone().unwrap_or_else(|| dir.join(MERGED_TRACE));
+            let ranks = merge(dir, &output)?;
+            println!("Merged the traces of {} ranks into {}", ranks, output.display());
+        }
+    }
+    Ok(())
+}
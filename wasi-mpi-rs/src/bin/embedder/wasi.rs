@@ -0,0 +1,118 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use structopt::StructOpt;
+use wasmer_vfs::host_fs;
+use wasmer_wasi::WasiStateBuilder;
+
+use crate::collective;
+
+/// Replaced by the rank in `MPI_COMM_WORLD` in host paths
+const RANK_PLACEHOLDER: &str = "{rank}";
+
+
+/// Filesystem, environment and stdin of the module. Host paths may contain `{rank}`, e.g. to give
+/// every rank a private scratch directory.
+#[derive(StructOpt)]
+pub struct WasiOpt {
+    /// Give the module access to a host directory at the same path
+    #[structopt(short, long, number_of_values = 1)]
+    pub dir: Vec<String>,
+    /// Give the module read-only access to a host directory at the same path
+    #[structopt(long, number_of_values = 1)]
+    pub ro_dir: Vec<String>,
+    /// Give the module access to a host directory at another path, e.g. /scratch:/tmp/job/{rank}
+    #[structopt(long, number_of_values = 1, value_name = "GUEST:HOST")]
+    pub mapdir: Vec<MappedDir>,
+    /// Give the module read-only access to a host directory at another path
+    #[structopt(long, number_of_values = 1, value_name = "GUEST:HOST")]
+    pub ro_mapdir: Vec<MappedDir>,
+    /// Set an environment variable of the module
+    #[structopt(long, number_of_values = 1, value_name = "KEY=VALUE")]
+    pub env: Vec<EnvVar>,
+    /// Pass the environment of the embedder on to the module, `--env` takes precedence
+    #[structopt(long)]
+    pub env_inherit: bool,
+    /// Read the module's stdin from a file instead of the embedder's stdin
+    #[structopt(long)]
+    pub stdin: Option<String>,
+}
+
+
+/// A host directory that is mapped to another path in the guest
+pub struct MappedDir {
+    pub guest: String,
+    pub host: String,
+}
+impl FromStr for MappedDir {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some((guest, host)) if !guest.is_empty() && !host.is_empty() => Ok(MappedDir{ guest: guest.to_string(), host: host.to_string() }),
+            _ => Err(anyhow!("invalid mapped directory {}, expected GUEST:HOST", s)),
+        }
+    }
+}
+
+
+pub struct EnvVar {
+    pub key: String,
+    pub value: String,
+}
+impl FromStr for EnvVar {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok(EnvVar{ key: key.to_string(), value: value.to_string() }),
+            _ => Err(anyhow!("invalid environment variable {}, expected KEY=VALUE", s)),
+        }
+    }
+}
+
+
+/// Replaces `{rank}` in a host path. MPI is only initialized if the path contains the placeholder.
+fn expand_rank(path: &str) -> String {
+    if !path.contains(RANK_PLACEHOLDER) {
+        return path.to_string();
+    }
+    path.replace(RANK_PLACEHOLDER, &collective::init_world_rank().to_string())
+}
+
+
+/// Applies the options to the WASI state of the module
+pub fn configure(opt: &WasiOpt, wasi_state: &mut WasiStateBuilder) -> Result<()> {
+    for dir in &opt.dir {
+        wasi_state.preopen_dir(expand_rank(dir))?;
+    }
+    for dir in &opt.ro_dir {
+        let dir = expand_rank(dir);
+        wasi_state.preopen(|p| p.directory(&dir).read(true).write(false).create(false))?;
+    }
+    for mapped in &opt.mapdir {
+        wasi_state.map_dir(&mapped.guest, expand_rank(&mapped.host))?;
+    }
+    for mapped in &opt.ro_mapdir {
+        let host = expand_rank(&mapped.host);
+        wasi_state.preopen(|p| p.directory(&host).alias(&mapped.guest).read(true).write(false).create(false))?;
+    }
+
+    // WASI keeps duplicate variables, so inherited ones are left out if they are set explicitly
+    if opt.env_inherit {
+        wasi_state.envs(env::vars().filter(|(key, _)| !opt.env.iter().any(|var| &var.key == key)));
+    }
+    for var in &opt.env {
+        wasi_state.env(&var.key, &var.value);
+    }
+
+    if let Some(path) = &opt.stdin {
+        let path = expand_rank(path);
+        let file = fs::File::open(&path).map_err(|e| anyhow!("could not open {} for stdin: {}", path, e))?;
+        wasi_state.stdin(Box::new(host_fs::File::new(file, PathBuf::from(path), true, false, false)));
+    }
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use wasmer::{FromToNativeWasmType, Function, Store, WasmTypeList};
+
+use crate::{Env, MpiCall, ReturnValue};
+
+
+/// Implemented by a marker type per host function, so that the name is known to the wrapper
+/// without being captured by it. Wasmer only accepts host functions without captured state.
+pub trait HostFunctionName {
+    const NAME: &'static &'static str;
+}
+
+
+/// Records the host function a module called last, e.g. to report it when the module traps
+pub struct CallTracker {
+    last_call: AtomicPtr<&'static str>,
+}
+impl CallTracker {
+    pub fn new() -> CallTracker {
+        CallTracker{ last_call: AtomicPtr::new(null_mut()) }
+    }
+
+    fn enter(&self, name: &'static &'static str) {
+        self.last_call.store(name as *const &'static str as *mut &'static str, Ordering::Relaxed);
+    }
+
+    /// Returns the import name of the host function that was called last, if any
+    pub fn last_call(&self) -> Option<&'static str> {
+        let name = self.last_call.load(Ordering::Relaxed);
+        if name.is_null() {
+            None
+        } else {
+            // Only references to the `NAME` constants are stored
+            Some(unsafe { *name })
+        }
+    }
+}
+
+
+/// Turns a host function into a `Function` that records its calls in `Env::calls` and passes MPI
+/// calls through the tools in `Env::tools`
+pub trait TrackedHostFunction<Args, Rets> {
+    fn tracked<N: HostFunctionName>(self, store: &Store, env: &Env) -> Function;
+}
+
+macro_rules! impl_tracked_host_function {
+    ($($arg:ident: $arg_type:ident),*) => {
+        // Wasmer implements `WasmTypeList` for `(A1)` rather than `(A1,)`
+        #[allow(unused_parens)]
+        impl<F, $($arg_type,)* Rets> TrackedHostFunction<($($arg_type,)*), Rets> for F
+        where
+            F: Fn(&Env, $($arg_type),*) -> Rets + Copy + Send + Sync + 'static,
+            $($arg_type: FromToNativeWasmType,)*
+            ($($arg_type),*): WasmTypeList,
+            Rets: WasmTypeList,
+        {
+            fn tracked<N: HostFunctionName>(self, store: &Store, env: &Env) -> Function {
+                // `self` is a function item, so the closure stays zero-sized
+                Function::new_native_with_env(store, env.clone(), move |env: &Env, $($arg: $arg_type),*| -> Rets {
+                    env.calls.enter(N::NAME);
+                    if env.tools.is_empty() || !N::NAME.starts_with("MPI_") {
+                        return self(env, $($arg),*);
+                    }
+
+                    let mut args = ($($arg),*).into_array().as_mut().to_vec();
+                    let mut call = MpiCall::new(env, *N::NAME, &mut args);
+                    let ret = env.tools.intercept(&mut call, |args| {
+                        let mut array = <($($arg_type),*)>::empty_array();
+                        array.as_mut().copy_from_slice(args);
+                        let ($($arg),*) = <($($arg_type),*)>::from_array(array);
+                        let mut rets = self(env, $($arg),*).into_array();
+                        ReturnValue(rets.as_mut().first().copied().unwrap_or(0))
+                    });
+
+                    let mut rets = Rets::empty_array();
+                    if let Some(first) = rets.as_mut().first_mut() {
+                        *first = ret.0;
+                    }
+                    Rets::from_array(rets)
+                })
+            }
+        }
+    };
+}
+
+impl_tracked_host_function!();
+impl_tracked_host_function!(a1: A1);
+impl_tracked_host_function!(a1: A1, a2: A2);
+impl_tracked_host_function!(a1: A1, a2: A2, a3: A3);
+impl_tracked_host_function!(a1: A1, a2: A2, a3: A3, a4: A4);
+impl_tracked_host_function!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5);
+impl_tracked_host_function!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5, a6: A6);
+impl_tracked_host_function!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5, a6: A6, a7: A7);
+impl_tracked_host_function!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5, a6: A6, a7: A7, a8: A8);
+impl_tracked_host_function!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5, a6: A6, a7: A7, a8: A8, a9: A9);
+impl_tracked_host_function!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5, a6: A6, a7: A7, a8: A8, a9: A9, a10: A10);
+impl_tracked_host_function!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5, a6: A6, a7: A7, a8: A8, a9: A9, a10: A10, a11: A11);
+impl_tracked_host_function!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5, a6: A6, a7: A7, a8: A8, a9: A9, a10: A10, a11: A11, a12: A12);
+impl_tracked_host_function!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5, a6: A6, a7: A7, a8: A8, a9: A9, a10: A10, a11: A11, a12: A12, a13: A13);
@@ -0,0 +1,789 @@
+use std::collections::{HashMap, HashSet};
+use std::mem::MaybeUninit;
+use std::sync::Mutex;
+
+use libc::c_void;
+use wasmer::{Array, WasmPtr};
+
+use crate::frames::call_site;
+use crate::{
+    CallAction, DataArgs, Env, HostHandle, MessageStatus, MpiCall, MpiComm, MpiRequest, ReturnValue, Tool,
+    MPI_ANY_SOURCE, MPI_ANY_TAG, MPI_BYTE, MPI_CHAR, MPI_COMM_SELF, MPI_COMM_WORLD, MPI_C_BOOL, MPI_DATATYPE_NULL,
+    MPI_DOUBLE, MPI_DOUBLE_INT, MPI_FLOAT, MPI_INT, MPI_INT16_T, MPI_INT32_T, MPI_INT64_T, MPI_INT8_T, MPI_IN_PLACE,
+    MPI_LONG, MPI_LONG_LONG, MPI_LONG_LONG_INT, MPI_UINT16_T, MPI_UINT32_T, MPI_UINT64_T, MPI_UINT8_T, MPI_UINT_T,
+};
+
+/// Kind of a derived datatype in the records exchanged by the checks. Derived datatypes are only
+/// compared by their size.
+const DERIVED_DATATYPE: i64 = -2;
+
+/// Marks a buffer that is `MPI_IN_PLACE` in a collective record
+const NO_BLOCK: i64 = i64::MIN;
+
+/// Collectives by their index in collective records, without the `_c` suffix of large-count calls
+const COLLECTIVES: &[&str] = &[
+    "MPI_Allgather", "MPI_Allreduce", "MPI_Alltoall", "MPI_Alltoallv", "MPI_Barrier", "MPI_Bcast", "MPI_Comm_create",
+    "MPI_Comm_dup", "MPI_Comm_split", "MPI_Comm_split_type", "MPI_Gather", "MPI_Reduce", "MPI_Scatter",
+];
+
+const DATATYPE_NAMES: &[(i32, &str)] = &[
+    (MPI_INT8_T, "MPI_INT8_T"), (MPI_INT16_T, "MPI_INT16_T"), (MPI_INT32_T, "MPI_INT32_T"), (MPI_INT, "MPI_INT"),
+    (MPI_INT64_T, "MPI_INT64_T"), (MPI_UINT8_T, "MPI_UINT8_T"), (MPI_UINT16_T, "MPI_UINT16_T"),
+    (MPI_UINT32_T, "MPI_UINT32_T"), (MPI_UINT_T, "MPI_UINT_T"), (MPI_UINT64_T, "MPI_UINT64_T"), (MPI_LONG, "MPI_LONG"),
+    (MPI_LONG_LONG, "MPI_LONG_LONG"), (MPI_LONG_LONG_INT, "MPI_LONG_LONG_INT"), (MPI_FLOAT, "MPI_FLOAT"),
+    (MPI_DOUBLE, "MPI_DOUBLE"), (MPI_DOUBLE_INT, "MPI_DOUBLE_INT"), (MPI_CHAR, "MPI_CHAR"), (MPI_C_BOOL, "MPI_C_BOOL"),
+    (MPI_BYTE, "MPI_BYTE"), (MPI_DATATYPE_NULL, "MPI_DATATYPE_NULL"),
+];
+
+const BLOCK_LEN: usize = 4;
+const COLLECTIVE_RECORD_LEN: usize = 3 + 2 * BLOCK_LEN;
+/// Source and tag of a message, then its block
+const MESSAGE_RECORD_LEN: usize = 2 + BLOCK_LEN;
+
+/// Message records that are kept before the completed ones are cleaned up
+const RECORD_SENDS_CLEANUP: usize = 64;
+
+
+/// Count, datatype and size of a message buffer, as sent or received
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Block {
+    /// The guest datatype handle, for reports
+    datatype: i64,
+    /// The smallest predefined datatype handle with the same host datatype, or `DERIVED_DATATYPE`
+    kind: i64,
+    count: i64,
+    bytes: i64,
+}
+impl Block {
+    fn of(call: &MpiCall, data: DataArgs) -> Block {
+        let datatype = call.datatype(data);
+        Block{
+            datatype: datatype as i64,
+            kind: datatype_kind(call.env(), datatype),
+            count: call.count(data),
+            bytes: call.bytes(data) as i64,
+        }
+    }
+
+    /// `None` for `MPI_IN_PLACE`
+    fn of_buffer(call: &MpiCall, data: Option<DataArgs>) -> Option<Block> {
+        let data = data?;
+        match call.arg(data.buf) {
+            MPI_IN_PLACE => None,
+            _ => Some(Block::of(call, data)),
+        }
+    }
+
+    fn encode(block: Option<Block>) -> [i64; BLOCK_LEN] {
+        match block {
+            Some(block) => [block.datatype, block.kind, block.count, block.bytes],
+            None => [NO_BLOCK; BLOCK_LEN],
+        }
+    }
+
+    fn decode(record: &[i64]) -> Option<Block> {
+        match record[0] {
+            NO_BLOCK => None,
+            datatype => Some(Block{ datatype, kind: record[1], count: record[2], bytes: record[3] }),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("{} x {} ({} bytes)", self.count, datatype_name(self.datatype), self.bytes)
+    }
+
+    /// Describes how `received` doesn't match `sent`, if it doesn't. Collectives have to receive
+    /// exactly what is sent, point-to-point receives may be larger.
+    fn mismatch(sent: Block, received: Block, exact: bool) -> Option<String> {
+        if sent.kind != DERIVED_DATATYPE && received.kind != DERIVED_DATATYPE && sent.kind != received.kind {
+            return Some(format!("{} are received as {}", sent.describe(), datatype_name(received.datatype)));
+        }
+        if sent.bytes > received.bytes || (exact && sent.bytes != received.bytes) {
+            return Some(format!("{} are received into {}", sent.describe(), received.describe()));
+        }
+        None
+    }
+}
+
+
+/// What every rank passes to a collective call
+struct CollectiveRecord {
+    collective: i64,
+    root: i64,
+    op: i64,
+    send: Option<Block>,
+    recv: Option<Block>,
+}
+impl CollectiveRecord {
+    fn of(call: &MpiCall) -> CollectiveRecord {
+        let name = call.name().trim_end_matches("_c");
+        let signature = call.signature();
+        CollectiveRecord{
+            collective: COLLECTIVES.iter().position(|&collective| collective == name).map_or(-1, |index| index as i64),
+            root: call.root().map_or(-1, |root| root as i64),
+            op: call.op().map_or(-1, |op| op as i64),
+            send: Block::of_buffer(call, signature.send),
+            recv: Block::of_buffer(call, signature.recv),
+        }
+    }
+
+    fn encode(&self) -> [i64; COLLECTIVE_RECORD_LEN] {
+        let mut record = [0; COLLECTIVE_RECORD_LEN];
+        record[..3].copy_from_slice(&[self.collective, self.root, self.op]);
+        record[3..3 + BLOCK_LEN].copy_from_slice(&Block::encode(self.send));
+        record[3 + BLOCK_LEN..].copy_from_slice(&Block::encode(self.recv));
+        record
+    }
+
+    fn decode(record: &[i64]) -> CollectiveRecord {
+        CollectiveRecord{
+            collective: record[0],
+            root: record[1],
+            op: record[2],
+            send: Block::decode(&record[3..3 + BLOCK_LEN]),
+            recv: Block::decode(&record[3 + BLOCK_LEN..]),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        COLLECTIVES.get(self.collective as usize).copied().unwrap_or("another collective")
+    }
+}
+
+
+/// Describes why the records of a collective call don't match across the ranks of its
+/// communicator, if they don't. Records are indexed by communicator rank.
+fn check_collective(records: &[CollectiveRecord]) -> Option<String> {
+    let first = &records[0];
+    for (rank, record) in records.iter().enumerate() {
+        if record.collective != first.collective {
+            return Some(format!("rank {} calls {} while rank 0 calls {}", rank, record.name(), first.name()));
+        }
+        if record.root != first.root {
+            return Some(format!("rank {} passes root {} while rank 0 passes root {}", rank, record.root, first.root));
+        }
+        if record.op != first.op {
+            return Some(format!("rank {} passes op {} while rank 0 passes op {}", rank, record.op, first.op));
+        }
+    }
+    if first.root >= records.len() as i64 {
+        return Some(format!("root {} is not a rank of the communicator", first.root));
+    }
+
+    // Which blocks of which ranks have to match: (sender, sent block, receiver, received block)
+    let root = first.root.max(0) as usize;
+    let mut pairs = Vec::new();
+    for (rank, record) in records.iter().enumerate() {
+        let (send, recv) = (record.send.or(record.recv), record.recv.or(record.send));
+        match first.name() {
+            "MPI_Bcast" => pairs.push((root, records[root].send, rank, recv)),
+            "MPI_Allreduce" | "MPI_Reduce" => pairs.push((0, first.recv, rank, recv)),
+            "MPI_Allgather" | "MPI_Alltoall" => {
+                pairs.push((rank, send, 0, first.recv.or(first.send)));
+                pairs.push((0, first.send.or(first.recv), rank, recv));
+            }
+            "MPI_Gather" => pairs.push((rank, send, root, records[root].recv)),
+            "MPI_Scatter" => pairs.push((root, records[root].send, rank, recv)),
+            _ => {}
+        }
+    }
+    for (sender, sent, receiver, received) in pairs {
+        if let (Some(sent), Some(received)) = (sent, received) {
+            if let Some(mismatch) = Block::mismatch(sent, received, true) {
+                return Some(format!("rank {} sends {} to rank {}", sender, mismatch, receiver));
+            }
+        }
+    }
+    None
+}
+
+
+/// The receive of the message record that goes with a receive of the module
+struct RecordReceive {
+    comm: i32,
+    block: Block,
+    /// The record is received along with the message if its source and tag are given, and after
+    /// the message from the source and tag in its status otherwise
+    request: Option<MpiRequest>,
+    record: Box<[i64; MESSAGE_RECORD_LEN]>,
+}
+
+
+/// A request of the module that was not completed yet
+struct PendingRequest {
+    name: &'static str,
+    site: String,
+    /// Guest address, size and hash of the send buffer when the send was started
+    send_buffer: Option<(u32, u32, u64)>,
+    receive: Option<RecordReceive>,
+}
+
+
+/// A call that is in progress
+struct CheckedCall {
+    site: String,
+    /// The request completed by `MPI_Wait`, which is freed by the call
+    request: Option<PendingRequest>,
+    /// The requests passed to `MPI_Waitany` and `MPI_Testany`, of which one may be completed
+    any_requests: Vec<i32>,
+    receive: Option<RecordReceive>,
+}
+
+
+struct CheckState {
+    problems: i64,
+    /// Duplicates of the module's communicators that carry the checks' own messages
+    shadow_comms: HashMap<i32, MpiComm>,
+    /// Communicators whose message order can't be followed anymore, e.g. after a wildcard receive
+    /// with `MPI_STATUS_IGNORE`
+    unordered_comms: HashSet<i32>,
+    requests: HashMap<i32, PendingRequest>,
+    /// Call and site that created the communicators and groups of the module
+    comm_sites: HashMap<i32, (&'static str, String)>,
+    group_sites: HashMap<i32, (&'static str, String)>,
+    record_sends: Vec<(MpiRequest, Box<[i64; MESSAGE_RECORD_LEN]>)>,
+    current: Option<CheckedCall>,
+}
+impl CheckState {
+    fn report(&mut self, message: &str) {
+        self.problems += 1;
+        eprintln!("[rank {}] mpi-check: {}", world_rank(), message);
+    }
+}
+
+
+/// Validates how a module uses MPI: collective calls have to match across ranks, requests have
+/// to be completed, communicators and groups freed, send buffers left alone until their send
+/// completes and messages received with the datatype they were sent with. Messages are checked
+/// by sending a record of each message along with it on a duplicate of its communicator.
+pub struct MpiChecker {
+    state: Mutex<CheckState>,
+}
+impl MpiChecker {
+    pub fn new() -> MpiChecker {
+        MpiChecker{
+            state: Mutex::new(CheckState{
+                problems: 0,
+                shadow_comms: HashMap::new(),
+                unordered_comms: HashSet::new(),
+                requests: HashMap::new(),
+                comm_sites: HashMap::new(),
+                group_sites: HashMap::new(),
+                record_sends: Vec::new(),
+                current: None,
+            }),
+        }
+    }
+
+    /// Compares what the ranks of the communicator pass to a collective and aborts on a mismatch,
+    /// which would otherwise hang or corrupt data
+    fn check_collective(&self, call: &MpiCall, site: &str) {
+        let comm = match call.comm() {
+            Some(comm) => comm,
+            None => return,
+        };
+        let host_comm = self.state.lock().unwrap().shadow_comms.get(&comm).copied().or_else(|| call.host_comm());
+        let MpiComm(host_comm) = match host_comm {
+            Some(host_comm) => host_comm,
+            None => return,
+        };
+
+        let mut rank = 0;
+        let mut size = 0;
+        unsafe {
+            mpi_sys::MPI_Comm_rank(host_comm, &mut rank);
+            mpi_sys::MPI_Comm_size(host_comm, &mut size);
+        }
+        let record = CollectiveRecord::of(call).encode();
+        let mut records = vec![0i64; size.max(0) as usize * COLLECTIVE_RECORD_LEN];
+        unsafe {
+            mpi_sys::MPI_Allgather(
+                record.as_ptr() as *const c_void, COLLECTIVE_RECORD_LEN as i32, mpi_sys::RSMPI_INT64_T,
+                records.as_mut_ptr() as *mut c_void, COLLECTIVE_RECORD_LEN as i32, mpi_sys::RSMPI_INT64_T,
+                host_comm,
+            );
+        }
+        let records: Vec<_> = records.chunks(COLLECTIVE_RECORD_LEN).map(CollectiveRecord::decode).collect();
+
+        let mismatch = match check_collective(&records) {
+            Some(mismatch) => mismatch,
+            None => return,
+        };
+        if rank == 0 {
+            let message = format!("{} on communicator {} in {} doesn't match across ranks: {}", call.name(), comm, site, mismatch);
+            self.state.lock().unwrap().report(&message);
+        }
+        unsafe {
+            mpi_sys::MPI_Barrier(host_comm);
+            mpi_sys::MPI_Abort(host_comm, 1);
+        }
+    }
+
+    /// Sends the record of a message ahead of it
+    fn send_record(&self, call: &MpiCall) {
+        let (comm, dest, tag, data) = match (call.comm(), call.dest(), call.send_tag(), call.signature().send) {
+            (Some(comm), Some(dest), Some(tag), Some(data)) if dest >= 0 => (comm, dest, tag, data),
+            _ => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        let MpiComm(shadow_comm) = match state.shadow_comms.get(&comm) {
+            Some(&shadow_comm) => shadow_comm,
+            None => return,
+        };
+
+        let mut record = Box::new([0i64; MESSAGE_RECORD_LEN]);
+        record[0] = call.comm_rank().unwrap_or(-1) as i64;
+        record[1] = tag as i64;
+        record[2..].copy_from_slice(&Block::encode(Some(Block::of(call, data))));
+        let mut request = MpiRequest::default();
+        unsafe {
+            mpi_sys::MPI_Isend(
+                record.as_ptr() as *const c_void, MESSAGE_RECORD_LEN as i32, mpi_sys::RSMPI_INT64_T, dest, tag,
+                shadow_comm, &mut request.0,
+            );
+        }
+        state.record_sends.push((request, record));
+
+        if state.record_sends.len() > RECORD_SENDS_CLEANUP {
+            state.record_sends.retain(|(request, _)| !test(*request));
+        }
+    }
+
+    /// Prepares the receive of the record of a message. The record is received along with the
+    /// message if the receive names its source and tag, in which case both are matched alike.
+    fn receive_record(&self, call: &MpiCall) -> Option<RecordReceive> {
+        let (comm, source, tag, data) = match (call.comm(), call.source(), call.recv_tag(), call.signature().recv) {
+            (Some(comm), Some(source), Some(tag), Some(data)) if source >= 0 || source == MPI_ANY_SOURCE => (comm, source, tag, data),
+            _ => return None,
+        };
+        let state = self.state.lock().unwrap();
+        let &MpiComm(shadow_comm) = state.shadow_comms.get(&comm)?;
+        if state.unordered_comms.contains(&comm) {
+            return None;
+        }
+
+        let mut receive = RecordReceive{
+            comm,
+            block: Block::of(call, data),
+            request: None,
+            record: Box::new([0; MESSAGE_RECORD_LEN]),
+        };
+        if source != MPI_ANY_SOURCE && tag != MPI_ANY_TAG {
+            let mut request = MpiRequest::default();
+            unsafe {
+                mpi_sys::MPI_Irecv(
+                    receive.record.as_mut_ptr() as *mut c_void, MESSAGE_RECORD_LEN as i32, mpi_sys::RSMPI_INT64_T,
+                    source, tag, shadow_comm, &mut request.0,
+                );
+            }
+            receive.request = Some(request);
+        }
+        Some(receive)
+    }
+
+    /// Removes a request of the module that is completed by a call, and checks that its send buffer
+    /// wasn't modified in the meantime
+    fn complete_request(&self, call: &MpiCall, request: i32, site: &str) -> Option<PendingRequest> {
+        let mut state = self.state.lock().unwrap();
+        let pending = state.requests.remove(&request)?;
+        if let Some((buf, bytes, send_hash)) = pending.send_buffer {
+            if guest_hash(call.env(), buf, bytes).map_or(false, |hash| hash != send_hash) {
+                let message = format!("the buffer of {} in {} was modified before {} in {}", pending.name, pending.site, call.name(), site);
+                state.report(&message);
+            }
+        }
+        Some(pending)
+    }
+
+    /// Receives the record of a received message and compares it to the receive
+    fn complete_receive(&self, receive: RecordReceive, status: Option<MessageStatus>, site: &str) {
+        let RecordReceive{ comm, block, request, mut record } = receive;
+        let mut state = self.state.lock().unwrap();
+        let MpiComm(shadow_comm) = match state.shadow_comms.get(&comm) {
+            Some(&shadow_comm) => shadow_comm,
+            None => return,
+        };
+        match (request, status) {
+            (Some(mut request), _) => unsafe {
+                mpi_sys::MPI_Wait(&mut request.0, mpi_sys::RSMPI_STATUS_IGNORE);
+            },
+            (None, Some(status)) if !state.unordered_comms.contains(&comm) => unsafe {
+                mpi_sys::MPI_Recv(
+                    record.as_mut_ptr() as *mut c_void, MESSAGE_RECORD_LEN as i32, mpi_sys::RSMPI_INT64_T,
+                    status.source, status.tag, shadow_comm, mpi_sys::RSMPI_STATUS_IGNORE,
+                );
+            },
+            _ => {
+                state.unordered_comms.insert(comm);
+                return;
+            }
+        }
+        if state.unordered_comms.contains(&comm) {
+            return;
+        }
+
+        let (source, tag) = (record[0], record[1]);
+        if let Some(status) = status {
+            if (status.source as i64, status.tag as i64) != (source, tag) {
+                state.unordered_comms.insert(comm);
+                return;
+            }
+        }
+        let sent = match Block::decode(&record[2..]) {
+            Some(sent) => sent,
+            None => return,
+        };
+        if let Some(mismatch) = Block::mismatch(sent, block, false) {
+            let message = format!("message from rank {} with tag {} on communicator {} in {}: {}", source, tag, comm, site, mismatch);
+            state.report(&message);
+        }
+    }
+
+    /// Reports what was left behind by the module and cleans up the checks' own messages
+    fn finalize(&self, env: &Env) {
+        let mut state = self.state.lock().unwrap();
+
+        let mut requests: Vec<_> = state.requests.drain().collect();
+        requests.sort_by_key(|&(request, _)| request);
+        for (request, pending) in &requests {
+            state.report(&format!("request {} of {} in {} was never completed", request, pending.name, pending.site));
+        }
+
+        for comm in env.mpi_comms.allocated() {
+            let is_null = env.mpi_comms.get(comm).map_or(true, |MpiComm(host_comm)| unsafe { host_comm == mpi_sys::RSMPI_COMM_NULL });
+            if !is_null {
+                let message = match state.comm_sites.get(&comm) {
+                    Some((name, site)) => format!("communicator {} of {} in {} was never freed", comm, name, site),
+                    None => format!("communicator {} was never freed", comm),
+                };
+                state.report(&message);
+            }
+        }
+        for group in env.mpi_groups.allocated() {
+            let message = match state.group_sites.get(&group) {
+                Some((name, site)) => format!("group {} of {} in {} was never freed", group, name, site),
+                None => format!("group {} was never freed", group),
+            };
+            state.report(&message);
+        }
+
+        // Records of messages that were never received
+        for (_, pending) in requests {
+            if let Some(RecordReceive{ request: Some(mut request), .. }) = pending.receive {
+                unsafe {
+                    mpi_sys::MPI_Cancel(&mut request.0);
+                    mpi_sys::MPI_Wait(&mut request.0, mpi_sys::RSMPI_STATUS_IGNORE);
+                }
+            }
+        }
+        unsafe {
+            mpi_sys::MPI_Barrier(mpi_sys::RSMPI_COMM_WORLD);
+        }
+        let mut record = [0i64; MESSAGE_RECORD_LEN];
+        for &MpiComm(shadow_comm) in state.shadow_comms.values() {
+            loop {
+                let mut flag = 0;
+                let mut status = MaybeUninit::<mpi_sys::MPI_Status>::uninit();
+                unsafe {
+                    mpi_sys::MPI_Iprobe(MPI_ANY_SOURCE, MPI_ANY_TAG, shadow_comm, &mut flag, status.as_mut_ptr());
+                }
+                if flag == 0 {
+                    break;
+                }
+                let status = unsafe { status.assume_init() };
+                unsafe {
+                    mpi_sys::MPI_Recv(
+                        record.as_mut_ptr() as *mut c_void, MESSAGE_RECORD_LEN as i32, mpi_sys::RSMPI_INT64_T,
+                        status.MPI_SOURCE, status.MPI_TAG, shadow_comm, mpi_sys::RSMPI_STATUS_IGNORE,
+                    );
+                }
+            }
+        }
+        for (mut request, record) in state.record_sends.drain(..) {
+            if !test(request) {
+                unsafe {
+                    mpi_sys::MPI_Request_free(&mut request.0);
+                }
+                // MPI may still read the record
+                Box::leak(record);
+            }
+        }
+
+        let mut problems = 0i64;
+        unsafe {
+            mpi_sys::MPI_Allreduce(
+                &state.problems as *const i64 as *const c_void, &mut problems as *mut i64 as *mut c_void, 1,
+                mpi_sys::RSMPI_INT64_T, mpi_sys::RSMPI_SUM, mpi_sys::RSMPI_COMM_WORLD,
+            );
+        }
+        if world_rank() == 0 {
+            match problems {
+                0 => eprintln!("mpi-check: no problems found"),
+                1 => eprintln!("mpi-check: 1 problem found"),
+                _ => eprintln!("mpi-check: {} problems found", problems),
+            }
+        }
+    }
+
+    /// Duplicates a communicator of the module for the checks' own messages. Must be called by all
+    /// ranks of the communicator.
+    fn add_shadow_comm(&self, env: &Env, comm: i32) {
+        let MpiComm(host_comm) = match env.mpi_comms.get(comm) {
+            Some(host_comm) => host_comm,
+            None => return,
+        };
+        if unsafe { host_comm == mpi_sys::RSMPI_COMM_NULL } {
+            return;
+        }
+        let mut shadow_comm = MpiComm::default();
+        unsafe {
+            mpi_sys::MPI_Comm_dup(host_comm, &mut shadow_comm.0);
+        }
+        self.state.lock().unwrap().shadow_comms.insert(comm, shadow_comm);
+    }
+}
+
+
+impl Tool for MpiChecker {
+    fn before(&self, call: &mut MpiCall) -> CallAction {
+        let site = call_site();
+        let mut request = None;
+        match call.name() {
+            "MPI_Finalize" => self.finalize(call.env()),
+            "MPI_Wait" => request = call.request().and_then(|request| self.complete_request(call, request, &site)),
+            "MPI_Comm_free" => {
+                if let Some(comm) = call.pointee(0) {
+                    let shadow_comm = {
+                        let mut state = self.state.lock().unwrap();
+                        state.comm_sites.remove(&comm);
+                        state.unordered_comms.remove(&comm);
+                        state.shadow_comms.remove(&comm)
+                    };
+                    if let Some(mut shadow_comm) = shadow_comm {
+                        unsafe {
+                            mpi_sys::MPI_Comm_free(&mut shadow_comm.0);
+                        }
+                    }
+                }
+            }
+            "MPI_Group_free" => {
+                if let Some(group) = call.pointee(0) {
+                    self.state.lock().unwrap().group_sites.remove(&group);
+                }
+            }
+            _ => {}
+        }
+
+        if call.signature().collective {
+            self.check_collective(call, &site);
+        }
+        self.send_record(call);
+        let receive = self.receive_record(call);
+        let any_requests = call.any_requests().unwrap_or_default();
+        self.state.lock().unwrap().current = Some(CheckedCall{ site, request, any_requests, receive });
+        CallAction::Proceed
+    }
+
+    fn after(&self, call: &MpiCall, _ret: &mut ReturnValue) {
+        let CheckedCall{ site, mut request, any_requests, receive } = match self.state.lock().unwrap().current.take() {
+            Some(current) => current,
+            None => return,
+        };
+        let env = call.env();
+
+        if call.name() == "MPI_Init" {
+            self.add_shadow_comm(env, MPI_COMM_WORLD);
+            self.add_shadow_comm(env, MPI_COMM_SELF);
+        }
+        if let Some(comm) = call.new_comm() {
+            self.add_shadow_comm(env, comm);
+            self.state.lock().unwrap().comm_sites.insert(comm, (call.name(), site.clone()));
+        }
+        if let Some(group) = call.new_group() {
+            self.state.lock().unwrap().group_sites.insert(group, (call.name(), site.clone()));
+        }
+
+        // The request completed by `MPI_Waitany` or `MPI_Testany` is only known after the call
+        if let Some(&completed) = call.completed_index().and_then(|index| any_requests.get(index)) {
+            if let Some(pending) = self.complete_request(call, completed, &site) {
+                if let Some(receive) = pending.receive {
+                    self.complete_receive(receive, call.status(), &site);
+                }
+            }
+            request = None;
+        }
+
+        match (call.signature().request, request) {
+            // `MPI_Wait`
+            (Some(_), Some(pending)) => {
+                if let Some(receive) = pending.receive {
+                    self.complete_receive(receive, call.status(), &site);
+                }
+            }
+            (Some(_), None) if call.name() != "MPI_Wait" => {
+                if let Some(request) = call.request() {
+                    let send_buffer = call.signature().send.and_then(|data| {
+                        let (buf, bytes) = (call.arg(data.buf) as u32, call.bytes(data) as u32);
+                        Some((buf, bytes, guest_hash(env, buf, bytes)?))
+                    });
+                    let pending = PendingRequest{ name: call.name(), site, send_buffer, receive };
+                    self.state.lock().unwrap().requests.insert(request, pending);
+                }
+            }
+            _ => {
+                if let Some(receive) = receive {
+                    self.complete_receive(receive, call.status(), &site);
+                }
+            }
+        }
+    }
+}
+
+
+/// FNV-1a of guest memory, to notice changes of send buffers. `None` if it is out of bounds.
+fn guest_hash(env: &Env, buf: u32, bytes: u32) -> Option<u64> {
+    let memory = env.memory.get_ref()?;
+    let buffer = WasmPtr::<u8, Array>::new(buf).deref(memory, 0, bytes)?;
+    Some(buffer.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte.get() as u64).wrapping_mul(0x100000001b3)))
+}
+
+
+/// Whether a request of the checks completed, freeing it if so
+fn test(mut request: MpiRequest) -> bool {
+    let mut flag = 0;
+    unsafe {
+        mpi_sys::MPI_Test(&mut request.0, &mut flag, mpi_sys::RSMPI_STATUS_IGNORE);
+    }
+    flag != 0
+}
+
+
+/// The smallest predefined handle with the same host datatype, so that e.g. `MPI_INT` matches
+/// `MPI_INT32_T`
+fn datatype_kind(env: &Env, datatype: i32) -> i64 {
+    let datatypes = &env.mpi_datatypes;
+    if !datatypes.is_predefined(datatype) {
+        return DERIVED_DATATYPE;
+    }
+    let bits = match datatypes.get(datatype) {
+        Some(host_datatype) => host_datatype.to_bits(),
+        None => return datatype as i64,
+    };
+    let kind = (MPI_DATATYPE_NULL..datatype)
+        .find(|&other| datatypes.get(other).map_or(false, |host_datatype| host_datatype.to_bits() == bits))
+        .unwrap_or(datatype);
+    kind as i64
+}
+
+
+fn datatype_name(datatype: i64) -> String {
+    match DATATYPE_NAMES.iter().find(|&&(handle, _)| handle as i64 == datatype) {
+        Some((_, name)) => name.to_string(),
+        None => format!("datatype {}", datatype),
+    }
+}
+
+
+pub(crate) fn world_rank() -> i32 {
+    let mut rank = -1;
+    unsafe {
+        mpi_sys::MPI_Comm_rank(mpi_sys::RSMPI_COMM_WORLD, &mut rank);
+    }
+    rank
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MPI_MAX, MPI_SUM};
+
+    fn block(datatype: i32, kind: i32, count: i64, size: i64) -> Block {
+        Block{ datatype: datatype as i64, kind: kind as i64, count, bytes: count * size }
+    }
+
+    /// `MPI_INT` is the same host datatype as `MPI_INT32_T`, which is its kind
+    fn ints(count: i64) -> Block {
+        block(MPI_INT, MPI_INT32_T, count, 4)
+    }
+
+    fn record(collective: &str, root: i32, op: i32, send: Option<Block>, recv: Option<Block>) -> CollectiveRecord {
+        CollectiveRecord{
+            collective: COLLECTIVES.iter().position(|&name| name == collective).unwrap() as i64,
+            root: root as i64,
+            op: op as i64,
+            send,
+            recv,
+        }
+    }
+
+    fn bcast(root: i32, buffer: Block) -> CollectiveRecord {
+        record("MPI_Bcast", root, -1, Some(buffer), Some(buffer))
+    }
+
+    fn allreduce(op: i32, send: Option<Block>, recv: Block) -> CollectiveRecord {
+        record("MPI_Allreduce", -1, op, send, Some(recv))
+    }
+
+    #[test]
+    fn matching_collectives_pass() {
+        assert_eq!(check_collective(&[bcast(1, ints(3)), bcast(1, ints(3)), bcast(1, ints(3))]), None);
+        assert_eq!(check_collective(&[allreduce(MPI_SUM, Some(ints(3)), ints(3)), allreduce(MPI_SUM, Some(ints(3)), ints(3))]), None);
+    }
+
+    #[test]
+    fn root_mismatch() {
+        let records = [bcast(0, ints(3)), bcast(0, ints(3)), bcast(1, ints(3))];
+        assert_eq!(check_collective(&records), Some("rank 2 passes root 1 while rank 0 passes root 0".to_string()));
+
+        let records = [bcast(2, ints(3)), bcast(2, ints(3))];
+        assert_eq!(check_collective(&records), Some("root 2 is not a rank of the communicator".to_string()));
+    }
+
+    #[test]
+    fn op_mismatch() {
+        let records = [allreduce(MPI_SUM, Some(ints(3)), ints(3)), allreduce(MPI_MAX, Some(ints(3)), ints(3))];
+        assert_eq!(check_collective(&records), Some(format!("rank 1 passes op {} while rank 0 passes op {}", MPI_MAX, MPI_SUM)));
+    }
+
+    #[test]
+    fn datatypes_of_the_same_kind_match() {
+        let int32s = block(MPI_INT32_T, MPI_INT32_T, 3, 4);
+        assert_eq!(Block::mismatch(ints(3), int32s, true), None);
+        assert_eq!(check_collective(&[bcast(0, ints(3)), bcast(0, int32s)]), None);
+
+        let floats = block(MPI_FLOAT, MPI_FLOAT, 3, 4);
+        assert_eq!(Block::mismatch(ints(3), floats, true), Some("3 x MPI_INT (12 bytes) are received as MPI_FLOAT".to_string()));
+    }
+
+    #[test]
+    fn bcast_has_to_receive_exactly_what_is_sent() {
+        let records = [bcast(0, ints(3)), bcast(0, ints(4))];
+        assert_eq!(
+            check_collective(&records),
+            Some("rank 0 sends 3 x MPI_INT (12 bytes) are received into 4 x MPI_INT (16 bytes) to rank 1".to_string()),
+        );
+        // Point-to-point receives may be larger than the message
+        assert_eq!(Block::mismatch(ints(3), ints(4), false), None);
+    }
+
+    #[test]
+    fn allreduce_in_place_compares_the_receive_buffer() {
+        let records = [allreduce(MPI_SUM, None, ints(3)), allreduce(MPI_SUM, Some(ints(3)), ints(3))];
+        assert_eq!(check_collective(&records), None);
+
+        let records = [allreduce(MPI_SUM, Some(ints(3)), ints(3)), allreduce(MPI_SUM, None, ints(2))];
+        assert_eq!(
+            check_collective(&records),
+            Some("rank 0 sends 3 x MPI_INT (12 bytes) are received into 2 x MPI_INT (8 bytes) to rank 1".to_string()),
+        );
+    }
+
+    #[test]
+    fn records_survive_encoding() {
+        let encoded = allreduce(MPI_SUM, None, ints(3)).encode();
+        let decoded = CollectiveRecord::decode(&encoded);
+        assert_eq!(decoded.name(), "MPI_Allreduce");
+        assert_eq!((decoded.root, decoded.op, decoded.send, decoded.recv), (-1, MPI_SUM as i64, None, Some(ints(3))));
+    }
+}
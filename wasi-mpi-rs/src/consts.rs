@@ -13,6 +13,15 @@ pub const MPI_COMM_WORLD: i32 = 0;
 pub const MPI_COMM_SELF: i32 = 1;
 pub const MPI_COMM_NULL: i32 = 2;
 
+pub const MPI_ANY_SOURCE: i32 = -1;
+pub const MPI_ANY_TAG: i32 = -1;
+
+pub const MPI_REQUEST_NULL: i32 = -1;
+pub const MPI_UNDEFINED: i32 = -32766;
+
+// Guest address that mpi.h defines as MPI_IN_PLACE
+pub const MPI_IN_PLACE: i32 = 1;
+
 pub const MPI_INT8_T: i32 = 0;
 pub const MPI_INT16_T: i32 = 1;
 pub const MPI_INT32_T: i32 = 2;
@@ -46,3 +55,18 @@ pub const MPI_MAXLOC: i32 = 8;
 pub const MPI_MINLOC: i32 = 9;
 pub const MPI_OP_NULL: i32 = -1;
 
+
+pub const MPI_KEYVAL_INVALID: i32 = -1;
+pub const MPI_NULL_COPY_FN: i32 = 0;
+pub const MPI_DUP_FN: i32 = -1;
+pub const MPI_NULL_DELETE_FN: i32 = 0;
+
+pub const MPI_TAG_UB: i32 = 100;
+pub const MPI_UNIVERSE_SIZE: i32 = 101;
+pub const MPI_APPNUM: i32 = 102;
+
+pub const MPI_INFO_NULL: i32 = 0;
+pub const MPI_MAX_INFO_KEY: i32 = 36;
+pub const MPI_MAX_INFO_VAL: i32 = 256;
+
+pub const MPI_COMM_TYPE_SHARED: i32 = 0;
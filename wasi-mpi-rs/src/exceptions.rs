@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use wasmer::{Exports, ExternType, Function, Module, RuntimeError, Store, Type, Val, WasmPtr};
+
+use crate::{Env, IMPORT_NAMESPACE, read_c_string};
+
+/// Bytes reserved in front of every exception object. The first word holds the pointer that is
+/// passed to the catch block, which differs from the object for base classes and pointer types.
+const EXCEPTION_HEADER_SIZE: u32 = 16;
+
+const INVOKE_PREFIX: &str = "invoke_";
+const FIND_MATCHING_CATCH_PREFIX: &str = "__cxa_find_matching_catch_";
+
+
+struct ExceptionInfo {
+    type_info: i32,
+    destructor: i32,
+    /// Number of catch blocks that are currently handling the exception
+    handlers: u32,
+    caught: bool,
+    rethrown: bool,
+}
+
+
+/// State of the C++ exceptions of a module compiled with Emscripten-style exception handling
+/// (`-mllvm -enable-emscripten-cxx-exceptions`).
+///
+/// LLVM turns every call that may throw into a call of an `invoke_*` import, which calls the function
+/// through the function table and reports a thrown exception by calling the module's `setThrew`. The
+/// landing pad then asks `__cxa_find_matching_catch_*` which of its catch clauses matches. Throwing
+/// raises a `CxxException` trap that unwinds the guest stack up to the innermost `invoke_*` call.
+#[derive(Default)]
+pub struct CxxExceptions {
+    exceptions: HashMap<i32, ExceptionInfo>,
+    /// The exception that was thrown most recently, it is examined by the next landing pad
+    last_thrown: Option<i32>,
+    caught: Vec<i32>,
+    uncaught: i32,
+    /// Second return value of `__cxa_find_matching_catch_*`, the type of the matching clause
+    temp_ret0: i32,
+}
+impl CxxExceptions {
+    pub fn new() -> CxxExceptions {
+        CxxExceptions::default()
+    }
+}
+
+
+/// Raised to unwind the guest stack when a C++ exception is thrown. If no `invoke_*` call catches it,
+/// it ends the execution of the module like a trap.
+#[derive(Debug)]
+pub struct CxxException {
+    pub ptr: i32,
+    pub type_name: String,
+}
+impl fmt::Display for CxxException {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "uncaught C++ exception of type {}", self.type_name)
+    }
+}
+impl Error for CxxException {}
+
+
+/// Reads the mangled name of a `std::type_info`, which follows its vtable pointer
+fn type_name(env: &Env, type_info: i32) -> String {
+    let memory = env.memory.get_ref().expect("uninitialized memory in type_name");
+    let name_ptr: WasmPtr<u32> = WasmPtr::new(type_info as u32 + 4);
+    name_ptr.deref(memory)
+        .and_then(|name| read_c_string(memory, WasmPtr::new(name.get())))
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| format!("<type_info {:#x}>", type_info))
+}
+
+
+fn throw(env: &Env, ptr: i32) -> ! {
+    let type_info = {
+        let mut cxx_exceptions = env.cxx_exceptions.lock().unwrap();
+        cxx_exceptions.last_thrown = Some(ptr);
+        cxx_exceptions.exceptions.get(&ptr).map(|info| info.type_info).unwrap_or(0)
+    };
+    // Native host functions can only trap by raising, which unwinds without running destructors, so
+    // no locks may be held here
+    #[allow(deprecated)]
+    RuntimeError::raise(Box::new(CxxException{ ptr, type_name: type_name(env, type_info) }))
+}
+
+
+fn adjusted_ptr(ptr: i32) -> WasmPtr<i32> {
+    WasmPtr::new(ptr as u32 - EXCEPTION_HEADER_SIZE)
+}
+
+
+fn destroy_exception(env: &Env, ptr: i32, destructor: i32) {
+    if destructor != 0 {
+        let table = env.function_table.get_ref().expect("Export __indirect_function_table to use C++ exceptions");
+        match table.get(destructor as u32) {
+            Some(Val::FuncRef(Some(function))) => {
+                function.call(&[Val::I32(ptr)]).expect("error calling the destructor of a C++ exception");
+            }
+            _ => panic!("invalid destructor {} of a C++ exception", destructor),
+        }
+    }
+    wasm__cxa_free_exception(env, ptr);
+}
+
+
+#[allow(non_snake_case)]
+pub fn wasm__cxa_allocate_exception(env: &Env, size: i32) -> i32 {
+    let malloc = env.malloc.get_ref().expect("Export malloc() to use C++ exceptions");
+    let header = malloc.call(size + EXCEPTION_HEADER_SIZE as i32).expect("error calling malloc() in __cxa_allocate_exception");
+    if header.offset() == 0 {
+        panic!("out of memory allocating a C++ exception of {} bytes", size);
+    }
+    (header.offset() + EXCEPTION_HEADER_SIZE) as i32
+}
+
+
+#[allow(non_snake_case)]
+pub fn wasm__cxa_begin_catch(env: &Env, ptr: i32) -> i32 {
+    {
+        let mut cxx_exceptions = env.cxx_exceptions.lock().unwrap();
+        let cxx_exceptions = &mut *cxx_exceptions;
+        if let Some(info) = cxx_exceptions.exceptions.get_mut(&ptr) {
+            if !info.caught {
+                info.caught = true;
+                cxx_exceptions.uncaught -= 1;
+            }
+            info.rethrown = false;
+            info.handlers += 1;
+        }
+        cxx_exceptions.caught.push(ptr);
+        cxx_exceptions.last_thrown = None;
+    }
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in __cxa_begin_catch");
+    adjusted_ptr(ptr).deref(memory).expect("out-of-bounds ptr in __cxa_begin_catch").get()
+}
+
+
+#[allow(non_snake_case)]
+pub fn wasm__cxa_end_catch(env: &Env) {
+    let finished = {
+        let mut cxx_exceptions = env.cxx_exceptions.lock().unwrap();
+        let ptr = match cxx_exceptions.caught.pop() {
+            Some(ptr) => ptr,
+            None => return,
+        };
+        let info = match cxx_exceptions.exceptions.get_mut(&ptr) {
+            Some(info) => info,
+            None => return,
+        };
+
+        info.handlers = info.handlers.saturating_sub(1);
+        if info.handlers == 0 && !info.rethrown {
+            cxx_exceptions.exceptions.remove(&ptr).map(|info| (ptr, info.destructor))
+        } else {
+            None
+        }
+    };
+
+    if let Some((ptr, destructor)) = finished {
+        destroy_exception(env, ptr, destructor);
+    }
+}
+
+
+#[allow(non_snake_case)]
+pub fn wasm__cxa_free_exception(env: &Env, ptr: i32) {
+    env.cxx_exceptions.lock().unwrap().exceptions.remove(&ptr);
+    let free = env.free.get_ref().expect("Export free() to use C++ exceptions");
+    free.call(WasmPtr::new(ptr as u32 - EXCEPTION_HEADER_SIZE)).expect("error calling free() in __cxa_free_exception");
+}
+
+
+#[allow(non_snake_case)]
+pub fn wasm__cxa_rethrow(env: &Env) {
+    let ptr = {
+        let mut cxx_exceptions = env.cxx_exceptions.lock().unwrap();
+        let cxx_exceptions = &mut *cxx_exceptions;
+        let ptr = *cxx_exceptions.caught.last().expect("__cxa_rethrow called outside of a catch block");
+        if let Some(info) = cxx_exceptions.exceptions.get_mut(&ptr) {
+            if !info.rethrown {
+                info.rethrown = true;
+                info.caught = false;
+                cxx_exceptions.uncaught += 1;
+            }
+        }
+        ptr
+    };
+    throw(env, ptr)
+}
+
+
+#[allow(non_snake_case)]
+pub fn wasm__cxa_throw(env: &Env, ptr: i32, ty: i32, destructor: i32) {
+    {
+        let mut cxx_exceptions = env.cxx_exceptions.lock().unwrap();
+        cxx_exceptions.exceptions.insert(ptr, ExceptionInfo{
+            type_info: ty,
+            destructor,
+            handlers: 0,
+            caught: false,
+            rethrown: false,
+        });
+        cxx_exceptions.uncaught += 1;
+    }
+    throw(env, ptr)
+}
+
+
+#[allow(non_snake_case)]
+pub fn wasm__cxa_uncaught_exceptions(env: &Env) -> i32 {
+    env.cxx_exceptions.lock().unwrap().uncaught
+}
+
+
+/// Only referenced as the personality of landing pads, which are replaced by `invoke_*` calls
+#[allow(non_snake_case)]
+pub fn wasm__gxx_personality_v0(_env: &Env, _args: i32) -> i32 {
+    0
+}
+
+
+/// Continues unwinding after a landing pad that only ran destructors or had no matching clause
+#[allow(non_snake_case)]
+pub fn wasm__resumeException(env: &Env, ptr: i32) {
+    let ptr = env.cxx_exceptions.lock().unwrap().last_thrown.unwrap_or(ptr);
+    throw(env, ptr)
+}
+
+
+#[allow(non_snake_case)]
+pub fn wasm_getTempRet0(env: &Env) -> i32 {
+    env.cxx_exceptions.lock().unwrap().temp_ret0
+}
+
+
+#[allow(non_snake_case)]
+pub fn wasm_llvm_eh_typeid_for(_env: &Env, type_info: i32) -> i32 {
+    // Catch clauses are identified by the address of their type_info, like in __cxa_find_matching_catch
+    type_info
+}
+
+
+#[allow(non_snake_case)]
+pub fn wasm_setTempRet0(env: &Env, value: i32) {
+    env.cxx_exceptions.lock().unwrap().temp_ret0 = value;
+}
+
+
+fn zero_value(ty: Type) -> Val {
+    match ty {
+        Type::I32 => Val::I32(0),
+        Type::I64 => Val::I64(0),
+        Type::F32 => Val::F32(0.0),
+        Type::F64 => Val::F64(0.0),
+        Type::V128 => Val::V128(0),
+        Type::ExternRef => Val::null(),
+        Type::FuncRef => Val::FuncRef(None),
+    }
+}
+
+
+/// Calls a function of the function table and catches C++ exceptions thrown by it
+fn invoke(env: &Env, result_types: &[Type], args: &[Val]) -> Result<Vec<Val>, RuntimeError> {
+    let table = env.function_table.get_ref().expect("Export __indirect_function_table to use C++ exceptions");
+    let index = args[0].unwrap_i32();
+    let function = match table.get(index as u32) {
+        Some(Val::FuncRef(Some(function))) => function,
+        _ => return Err(RuntimeError::new(format!("invalid function table index {} in invoke", index))),
+    };
+
+    let stack_save = env.stack_save.get_ref().expect("Link libwasi-mpi-exceptions to use C++ exceptions");
+    let stack_pointer = stack_save.call()?;
+
+    match function.call(&args[1..]) {
+        Ok(results) => Ok(results.into_vec()),
+        Err(e) => match e.downcast::<CxxException>() {
+            Ok(_) => {
+                // The unwound frames didn't get to pop their part of the shadow stack
+                let stack_restore = env.stack_restore.get_ref().expect("Link libwasi-mpi-exceptions to use C++ exceptions");
+                stack_restore.call(stack_pointer)?;
+
+                let set_threw = env.set_threw.get_ref().expect("Link libwasi-mpi-exceptions to use C++ exceptions");
+                set_threw.call(1, 0)?;
+                // The results are ignored by the caller, which branches to its landing pad
+                Ok(result_types.iter().map(|ty| zero_value(*ty)).collect())
+            }
+            Err(e) => Err(e),
+        },
+    }
+}
+
+
+/// Returns the thrown exception and stores the type of the first catch clause that matches it, or the
+/// thrown type if none matches, for `getTempRet0`
+fn find_matching_catch(env: &Env, clauses: &[Val]) -> Result<Vec<Val>, RuntimeError> {
+    let (ptr, thrown_type) = {
+        let mut cxx_exceptions = env.cxx_exceptions.lock().unwrap();
+        match cxx_exceptions.last_thrown {
+            Some(ptr) => (ptr, cxx_exceptions.exceptions.get(&ptr).map(|info| info.type_info).unwrap_or(0)),
+            None => {
+                cxx_exceptions.temp_ret0 = 0;
+                return Ok(vec![Val::I32(0)]);
+            }
+        }
+    };
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in __cxa_find_matching_catch");
+    let adjusted_ptr = adjusted_ptr(ptr);
+    let adjusted_cell = adjusted_ptr.deref(memory).expect("out-of-bounds ptr in __cxa_find_matching_catch");
+
+    let mut matching_type = thrown_type;
+    for clause in clauses {
+        let catch_type = clause.unwrap_i32();
+        adjusted_cell.set(ptr);
+
+        // A null type is a catch (...) clause
+        let matches = if catch_type == 0 || catch_type == thrown_type {
+            true
+        } else {
+            // Base classes and pointer conversions need the type hierarchy, which only libc++abi knows
+            let can_catch = env.can_catch.get_ref().expect("Link libwasi-mpi-exceptions to use C++ exceptions");
+            can_catch.call(catch_type, thrown_type, adjusted_ptr.offset() as i32)? != 0
+        };
+        if matches {
+            matching_type = catch_type;
+            break;
+        }
+    }
+
+    env.cxx_exceptions.lock().unwrap().temp_ret0 = matching_type;
+    Ok(vec![Val::I32(ptr)])
+}
+
+
+/// Adds the `invoke_*` and `__cxa_find_matching_catch_*` functions imported by `module` to `exports`.
+/// Their signatures depend on the module, so they can't be part of the fixed set of host functions.
+pub fn register_exception_imports(store: &Store, env: &Env, module: &Module, exports: &mut Exports) {
+    for import in module.imports().filter(|import| import.module() == IMPORT_NAMESPACE) {
+        let ty = match import.ty() {
+            ExternType::Function(ty) => ty.clone(),
+            _ => continue,
+        };
+
+        let function = if import.name().starts_with(INVOKE_PREFIX) {
+            if ty.params().first() != Some(&Type::I32) {
+                continue;
+            }
+            let result_types = ty.results().to_vec();
+            Function::new_with_env(store, &ty, env.clone(), move |env: &Env, args: &[Val]| invoke(env, &result_types, args))
+        } else if import.name().starts_with(FIND_MATCHING_CATCH_PREFIX) {
+            if ty.results() != [Type::I32] || ty.params().iter().any(|param| *param != Type::I32) {
+                continue;
+            }
+            Function::new_with_env(store, &ty, env.clone(), find_matching_catch)
+        } else {
+            continue;
+        };
+        exports.insert(import.name(), function);
+    }
+}
@@ -0,0 +1,79 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use wasmer::FrameInfo;
+use wasmer_engine::{is_wasm_pc, FRAME_INFO};
+
+
+thread_local! {
+    /// Names of the guest functions that called into the host, by return address
+    static CALL_SITES: RefCell<HashMap<usize, String>> = RefCell::new(HashMap::new());
+}
+
+
+/// Calls `f` with the return address of every guest frame on the stack, innermost first, until it
+/// returns `false`. Only the native stack is walked, nothing is symbolized, so this is cheap enough
+/// to be done on every MPI call, unlike `RuntimeError::new`, which resolves the whole trace.
+fn walk_guest_frames<F>(mut f: F) where F: FnMut(usize) -> bool {
+    backtrace::trace(|frame| {
+        let pc = frame.ip() as usize;
+        if pc == 0 || !is_wasm_pc(pc) {
+            return true;
+        }
+        f(pc)
+    });
+}
+
+
+/// Looks up the guest function of a return address found on the stack
+fn frame_info(pc: usize) -> Option<FrameInfo> {
+    // The return address points behind the call instruction, which may be the end of the function
+    FRAME_INFO.read().unwrap().lookup_frame_info(pc - 1)
+}
+
+
+/// Returns the return addresses of the guest frames on the stack, innermost first. They are resolved
+/// with `resolve_frames` once the frames are needed.
+pub fn guest_return_addresses() -> Vec<usize> {
+    let mut pcs = Vec::new();
+    walk_guest_frames(|pc| {
+        pcs.push(pc);
+        true
+    });
+    pcs
+}
+
+
+/// Resolves return addresses from `guest_return_addresses` to the frames of the guest stack. Frames
+/// of modules that were dropped in the meantime are left out.
+pub fn resolve_frames(pcs: &[usize]) -> Vec<FrameInfo> {
+    pcs.iter().filter_map(|&pc| frame_info(pc)).collect()
+}
+
+
+/// Returns the name of the guest function that called the current host function, from the name
+/// section if the module has one
+pub(crate) fn call_site() -> String {
+    let mut caller = None;
+    walk_guest_frames(|pc| {
+        caller = Some(pc);
+        false
+    });
+    let pc = match caller {
+        Some(pc) => pc,
+        None => return "<unknown>".to_string(),
+    };
+
+    CALL_SITES.with(|call_sites| {
+        call_sites.borrow_mut()
+            .entry(pc)
+            .or_insert_with(|| match frame_info(pc) {
+                Some(frame) => match frame.function_name() {
+                    Some(name) => name.to_string(),
+                    None => format!("<function {}>", frame.func_index()),
+                },
+                None => "<unknown>".to_string(),
+            })
+            .clone()
+    })
+}
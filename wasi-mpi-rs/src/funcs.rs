@@ -1,17 +1,153 @@
 use std::ptr::null_mut;
 use std::time::Instant;
 
-use libc::c_void;
-use wasmer::{WasmPtr, Array};
+use libc::{c_char, c_void};
+use wasmer::{WasmCell, WasmPtr, Array};
 
-use crate::{Env, MpiComm, MpiDatatype, MpiGroup, MpiOp};
-use crate::consts::{MpiComparisonResult, MPI_COMM_NULL, MPI_SUCCESS};
+use crate::{attributes, guest_bytes, init_host_mpi, read_c_string, Env, MpiComm, MpiDatatype, MpiGroup, MpiInfo, MpiObjectKind, MpiOp, MpiRequest};
+use crate::consts::{MpiComparisonResult, MPI_COMM_NULL, MPI_COMM_TYPE_SHARED, MPI_DATATYPE_NULL, MPI_INFO_NULL, MPI_IN_PLACE, MPI_MAX_INFO_KEY, MPI_REQUEST_NULL, MPI_SUCCESS, MPI_UNDEFINED};
 use std::ops::Deref;
 
+
+/// Elements per block when large counts are split up for the host MPI, which only takes `int` counts
+const LARGE_COUNT_CHUNK: i64 = 1 << 30;
+
+
+/// Translates the requests of `MPI_Waitany` and `MPI_Testany`. `MPI_REQUEST_NULL` is passed on as
+/// the host's null request, which MPI skips.
+fn host_requests(env: &Env, requests: &[WasmCell<i32>]) -> Vec<mpi_sys::MPI_Request> {
+    requests.iter()
+        .map(|request| match env.mpi_requests.get(request.get()) {
+            Some(MpiRequest(host_request)) => host_request,
+            None => unsafe { mpi_sys::RSMPI_REQUEST_NULL },
+        })
+        .collect()
+}
+
+
+/// Frees the request that `MPI_Waitany` or `MPI_Testany` completed, sets it to `MPI_REQUEST_NULL`
+/// in the module and returns its index
+fn complete_any(env: &Env, requests: &[WasmCell<i32>], host_index: i32) -> i32 {
+    if host_index == mpi_sys::MPI_UNDEFINED || host_index < 0 {
+        return MPI_UNDEFINED;
+    }
+    let request = &requests[host_index as usize];
+    env.mpi_requests.free_instance(request.get());
+    request.set(MPI_REQUEST_NULL);
+    host_index
+}
+
+
+fn type_extent(host_datatype: mpi_sys::MPI_Datatype) -> i64 {
+    let mut lb: mpi_sys::MPI_Aint = 0;
+    let mut extent: mpi_sys::MPI_Aint = 0;
+    unsafe {
+        mpi_sys::MPI_Type_get_extent(host_datatype, &mut lb, &mut extent);
+    }
+    extent as i64
+}
+
+
+/// Returns the number of bytes spanned by `count` elements of a host datatype, which is used to
+/// bounds-check guest buffers before handing them to MPI.
+fn buffer_len(host_datatype: mpi_sys::MPI_Datatype, count: i64) -> u64 {
+    (count.max(0) * type_extent(host_datatype).max(0)) as u64
+}
+
+
+/// Calls `f` with `count` elements of `host_datatype` if the count fits into an `int`. Otherwise `f`
+/// is called with a single element of a derived datatype that covers all `count` elements.
+fn with_large_count<F>(host_datatype: mpi_sys::MPI_Datatype, count: i64, f: F) -> i32 where F: FnOnce(i32, mpi_sys::MPI_Datatype) -> i32 {
+    if count <= i32::MAX as i64 {
+        return f(count as i32, host_datatype);
+    }
+
+    let chunks = count / LARGE_COUNT_CHUNK;
+    let remainder = count % LARGE_COUNT_CHUNK;
+
+    let MpiDatatype(mut chunk_type) = MpiDatatype::default();
+    let MpiDatatype(mut chunks_type) = MpiDatatype::default();
+    let MpiDatatype(mut large_type) = MpiDatatype::default();
+    unsafe {
+        mpi_sys::MPI_Type_contiguous(LARGE_COUNT_CHUNK as i32, host_datatype, &mut chunk_type);
+        mpi_sys::MPI_Type_contiguous(chunks as i32, chunk_type, &mut chunks_type);
+
+        if remainder == 0 {
+            large_type = chunks_type;
+        } else {
+            let MpiDatatype(mut remainder_type) = MpiDatatype::default();
+            mpi_sys::MPI_Type_contiguous(remainder as i32, host_datatype, &mut remainder_type);
+
+            let blocklengths = [1, 1];
+            let displacements = [0, (chunks * LARGE_COUNT_CHUNK * type_extent(host_datatype)) as mpi_sys::MPI_Aint];
+            let types = [chunks_type, remainder_type];
+            mpi_sys::MPI_Type_create_struct(2, blocklengths.as_ptr(), displacements.as_ptr(), types.as_ptr(), &mut large_type);
+
+            mpi_sys::MPI_Type_free(&mut remainder_type);
+            mpi_sys::MPI_Type_free(&mut chunks_type);
+        }
+        mpi_sys::MPI_Type_free(&mut chunk_type);
+        mpi_sys::MPI_Type_commit(&mut large_type);
+    }
+
+    // Pending operations are not affected by freeing their datatype
+    let mpi_ret = f(1, large_type);
+    unsafe {
+        mpi_sys::MPI_Type_free(&mut large_type);
+    }
+    mpi_ret
+}
+
+
+/// Splits `count` elements into consecutive blocks of at most `chunk` elements, given as the index
+/// of their first element and their length. There is always at least one block, so that every rank
+/// takes part in a collective even if its count is zero.
+fn chunk_blocks(count: i64, chunk: i64) -> Vec<(i64, i32)> {
+    let mut blocks = Vec::new();
+    let mut offset: i64 = 0;
+    loop {
+        let len = (count - offset).min(chunk);
+        blocks.push((offset, len as i32));
+        offset += len;
+
+        if offset >= count {
+            return blocks;
+        }
+    }
+}
+
+
+/// Returns `MPI_IN_PLACE` of the host MPI. mpi-sys doesn't export it, as mpi.h defines it as a cast.
+#[cfg(feature = "openmpi")]
+fn host_in_place() -> *mut c_void {
+    1 as *mut c_void
+}
+#[cfg(feature = "mvapich")]
+fn host_in_place() -> *mut c_void {
+    -1isize as *mut c_void
+}
+
+
+/// Calls `f` for consecutive blocks of at most `LARGE_COUNT_CHUNK` elements with the byte offset and
+/// length of each block. Used for reductions, as predefined operations only work on predefined
+/// datatypes.
+fn for_each_chunk<F>(host_datatype: mpi_sys::MPI_Datatype, count: i64, mut f: F) -> i32 where F: FnMut(usize, i32) -> i32 {
+    let extent = type_extent(host_datatype);
+
+    let mut mpi_ret = MPI_SUCCESS;
+    for (offset, len) in chunk_blocks(count, LARGE_COUNT_CHUNK) {
+        mpi_ret = f((offset * extent) as usize, len);
+        if mpi_ret != MPI_SUCCESS {
+            break;
+        }
+    }
+    mpi_ret
+}
+
+
 #[allow(non_snake_case)]
 pub fn MPI_Abort(env: &Env, comm: i32, errorcode: i32) -> i32 {
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid comm");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
 
     unsafe {
         mpi_sys::MPI_Abort(host_comm, errorcode)
@@ -25,12 +161,9 @@ pub fn MPI_Allgather(env: &Env, sendbuf: WasmPtr<u8>, sendcount: i32, sendtype:
     let sendbuf_ptr = sendbuf.deref(memory).expect("out-of-bounds ptr in MPI_Allgather");
     let recvbuf_ptr = recvbuf.deref(memory).expect("out-of-bounds ptr in MPI_Alltather");
 
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(host_sendtype) = *mpi_datatypes.get(sendtype).expect("invalid sendtype");
-    let MpiDatatype(host_recvtype) = *mpi_datatypes.get(recvtype).expect("invalid recvtype");
-
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid comm");
+    let MpiDatatype(host_sendtype) = env.mpi_datatypes.get(sendtype).expect("invalid sendtype");
+    let MpiDatatype(host_recvtype) = env.mpi_datatypes.get(recvtype).expect("invalid recvtype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
 
     unsafe {
         mpi_sys::MPI_Allgather(sendbuf_ptr.as_ptr() as *mut c_void, sendcount, host_sendtype, recvbuf_ptr.as_ptr() as *mut c_void, recvcount, host_recvtype, host_comm)
@@ -39,12 +172,16 @@ pub fn MPI_Allgather(env: &Env, sendbuf: WasmPtr<u8>, sendcount: i32, sendtype:
 
 
 #[allow(non_snake_case)]
-pub fn MPI_Alloc_mem(env: &Env, size: i32, info: i32, baseptr: WasmPtr<WasmPtr<u8>>) -> i32 {
+pub fn MPI_Alloc_mem(env: &Env, size: u32, info: i32, baseptr: WasmPtr<WasmPtr<u8>>) -> i32 {
     let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Alloc_mem");
     let malloc = env.malloc.get_ref().expect("Export malloc() to use MPI_Alloc_mem()");
     let derefed_ptr = baseptr.deref(memory).expect("out-of-bounds ptr in MPI_Alloc_mem");
 
-    let alloced = malloc.call(size).expect("error calling malloc() in MPI_Alloc_mem()");
+    // The memory is allocated inside the module, so allocation hints of the host MPI do not apply
+    env.mpi_infos.get(info).expect("invalid info");
+
+    // MPI_Aint is the width of a guest pointer, reinterpreting it allows allocations beyond 2 GiB
+    let alloced = malloc.call(size as i32).expect("error calling malloc() in MPI_Alloc_mem()");
     derefed_ptr.set(alloced);
     return MPI_SUCCESS;
 }
@@ -56,14 +193,9 @@ pub fn MPI_Allreduce(env: &Env, sendbuf: WasmPtr<u8>, recvbuf: WasmPtr<u8>, coun
     let sendbuf_ptr = sendbuf.deref(memory).expect("out-of-bounds ptr in MPI_Allreduce");
     let recvbuf_ptr = recvbuf.deref(memory).expect("out-of-bounds ptr in MPI_Allreduce");
 
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(host_datatype) = *mpi_datatypes.get(datatype).expect("invalid datatype");
-
-    let mpi_ops = env.mpi_ops.read().unwrap();
-    let MpiOp(host_op) = *mpi_ops.get(op).expect("invalid op");
-
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid comm");
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiOp(host_op) = env.mpi_ops.get(op).expect("invalid op");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
 
     unsafe {
         mpi_sys::MPI_Allreduce(sendbuf_ptr.as_ptr() as *mut c_void, recvbuf_ptr.as_ptr() as *mut c_void, count, host_datatype, host_op, host_comm)
@@ -72,18 +204,40 @@ pub fn MPI_Allreduce(env: &Env, sendbuf: WasmPtr<u8>, recvbuf: WasmPtr<u8>, coun
 }
 
 
+#[allow(non_snake_case)]
+pub fn MPI_Allreduce_c(env: &Env, sendbuf: WasmPtr<u8, Array>, recvbuf: WasmPtr<u8, Array>, count: i64, datatype: i32, op: i32, comm: i32) -> i32 {
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiOp(host_op) = env.mpi_ops.get(op).expect("invalid op");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Allreduce_c");
+    let len = buffer_len(host_datatype, count);
+    let sendbuf_ptr = if sendbuf.offset() as i32 == MPI_IN_PLACE {
+        None
+    } else {
+        Some(guest_bytes(memory, sendbuf, len).expect("out-of-bounds ptr in MPI_Allreduce_c"))
+    };
+    let recvbuf_ptr = guest_bytes(memory, recvbuf, len).expect("out-of-bounds ptr in MPI_Allreduce_c");
+
+    for_each_chunk(host_datatype, count, |offset, chunk_count| unsafe {
+        let sendbuf_ptr = match sendbuf_ptr {
+            Some(sendbuf_ptr) => sendbuf_ptr.add(offset) as *mut c_void,
+            None => host_in_place(),
+        };
+        mpi_sys::MPI_Allreduce(sendbuf_ptr, recvbuf_ptr.add(offset) as *mut c_void, chunk_count, host_datatype, host_op, host_comm)
+    })
+}
+
+
 #[allow(non_snake_case)]
 pub fn MPI_Alltoall(env: &Env, sendbuf: WasmPtr<u8>, sendcount: i32, sendtype: i32, recvbuf: WasmPtr<i32>, recvcount: i32, recvtype: i32, comm: i32) -> i32 {
     let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Alltoall");
     let sendbuf_ptr = sendbuf.deref(memory).expect("out-of-bounds ptr in MPI_Alltoall");
     let recvbuf_ptr = recvbuf.deref(memory).expect("out-of-bounds ptr in MPI_Alltoall");
 
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(host_sendtype) = *mpi_datatypes.get(sendtype).expect("invalid sendtype");
-    let MpiDatatype(host_recvtype) = *mpi_datatypes.get(recvtype).expect("invalid recvtype");
-
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid comm");
+    let MpiDatatype(host_sendtype) = env.mpi_datatypes.get(sendtype).expect("invalid sendtype");
+    let MpiDatatype(host_recvtype) = env.mpi_datatypes.get(recvtype).expect("invalid recvtype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
 
     unsafe {
         mpi_sys::MPI_Alltoall(sendbuf_ptr.as_ptr() as *mut c_void, sendcount, host_sendtype, recvbuf_ptr.as_ptr() as *mut c_void, recvcount, host_recvtype, host_comm)
@@ -101,12 +255,9 @@ pub fn MPI_Alltoallv(env: &Env, sendbuf: WasmPtr<u8>, sendcounts: WasmPtr<i32>,
     let recvcounts_ptr = recvcounts.deref(memory).expect("out-of-bounds ptr in MPI_Alltoallv");
     let rdispls_ptr = rdispls.deref(memory).expect("out-of-bounds ptr in MPI_Alltoallv");
 
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(host_sendtype) = *mpi_datatypes.get(sendtype).expect("invalid sendtype");
-    let MpiDatatype(host_recvtype) = *mpi_datatypes.get(recvtype).expect("invalid recvtype");
-
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid comm");
+    let MpiDatatype(host_sendtype) = env.mpi_datatypes.get(sendtype).expect("invalid sendtype");
+    let MpiDatatype(host_recvtype) = env.mpi_datatypes.get(recvtype).expect("invalid recvtype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
 
     unsafe {
         mpi_sys::MPI_Alltoallv(
@@ -126,8 +277,7 @@ pub fn MPI_Alltoallv(env: &Env, sendbuf: WasmPtr<u8>, sendcounts: WasmPtr<i32>,
 
 #[allow(non_snake_case)]
 pub fn MPI_Barrier(env: &Env, comm: i32) -> i32 {
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid communicator");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid communicator");
 
     unsafe {
         mpi_sys::MPI_Barrier(host_comm)
@@ -140,11 +290,8 @@ pub fn MPI_Bcast(env: &Env, buffer: WasmPtr<u8>, count: i32, datatype: i32, root
     let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Bcast");
     let buffer_ptr = buffer.deref(memory).expect("out-of-bounds ptr in MPI_Bcast");
 
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(host_datatype) = *mpi_datatypes.get(datatype).expect("invalid datatype");
-
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid comm");
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
 
     unsafe {
         mpi_sys::MPI_Bcast(buffer_ptr.as_ptr() as *mut c_void, count, host_datatype, root, host_comm)
@@ -152,14 +299,27 @@ pub fn MPI_Bcast(env: &Env, buffer: WasmPtr<u8>, count: i32, datatype: i32, root
 }
 
 
+#[allow(non_snake_case)]
+pub fn MPI_Bcast_c(env: &Env, buffer: WasmPtr<u8, Array>, count: i64, datatype: i32, root: i32, comm: i32) -> i32 {
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Bcast_c");
+    let buffer_ptr = guest_bytes(memory, buffer, buffer_len(host_datatype, count)).expect("out-of-bounds ptr in MPI_Bcast_c");
+
+    with_large_count(host_datatype, count, |host_count, host_datatype| unsafe {
+        mpi_sys::MPI_Bcast(buffer_ptr as *mut c_void, host_count, host_datatype, root, host_comm)
+    })
+}
+
+
 #[allow(non_snake_case)]
 pub fn MPI_Comm_compare(env: &Env, comm1: i32, comm2: i32, result: WasmPtr<i32>) -> i32 {
     let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Comm_compare");
     let result_ptr = result.deref(memory).expect("out-of-bounds ptr in MPI_Comm_compare");
 
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm1) = *mpi_comms.get(comm1).expect("invalid comm1");
-    let MpiComm(host_comm2) = *mpi_comms.get(comm2).expect("invalid comm2");
+    let MpiComm(host_comm1) = env.mpi_comms.get(comm1).expect("invalid comm1");
+    let MpiComm(host_comm2) = env.mpi_comms.get(comm2).expect("invalid comm2");
 
     let mut host_result = -1;
     let host_result_ref: *mut i32 = &mut host_result;
@@ -184,21 +344,73 @@ pub fn MPI_Comm_create(env: &Env, comm: i32, group: i32, newcomm: WasmPtr<i32>)
     let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Comm_create");
     let newcomm_ptr = newcomm.deref(memory).expect("out-of-bounds.ptr in MPI_Comm_create");
 
-    let mut mpi_comms = env.mpi_comms.write().unwrap();
-    let mpi_groups = env.mpi_groups.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid comm");
-    let MpiGroup(host_group) = *mpi_groups.get(group).expect("invalid group");
-
-    let (newcomm_id, host_newcomm) = mpi_comms.alloc_instance();
-    let host_newcomm_ref: *mut mpi_sys::MPI_Comm = &mut host_newcomm.0;
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
+    let MpiGroup(host_group) = env.mpi_groups.get(group).expect("invalid group");
 
+    let mut host_newcomm = MpiComm::default();
     let mpi_ret = unsafe {
         mpi_sys::MPI_Comm_create(
             host_comm,
             host_group,
-            host_newcomm_ref,
+            &mut host_newcomm.0,
         )
     };
+
+    let newcomm_id = env.mpi_comms.alloc_instance(host_newcomm);
+    newcomm_ptr.set(newcomm_id);
+    mpi_ret
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Comm_create_keyval(env: &Env, comm_copy_attr_fn: i32, comm_delete_attr_fn: i32, comm_keyval: WasmPtr<i32>, extra_state: i32) -> i32 {
+    attributes::create_keyval(env, MpiObjectKind::Comm, comm_copy_attr_fn, comm_delete_attr_fn, comm_keyval, extra_state)
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Comm_delete_attr(env: &Env, comm: i32, comm_keyval: i32) -> i32 {
+    env.mpi_comms.get(comm).expect("invalid comm");
+
+    attributes::delete_attr(env, MpiObjectKind::Comm, comm, comm_keyval)
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Comm_dup(env: &Env, comm: i32, newcomm: WasmPtr<i32>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Comm_dup");
+    let newcomm_ptr = newcomm.deref(memory).expect("out-of-bounds ptr in MPI_Comm_dup");
+
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
+
+    let mut host_newcomm = MpiComm::default();
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Comm_dup(host_comm, &mut host_newcomm.0)
+    };
+    let newcomm_id = env.mpi_comms.alloc_instance(host_newcomm);
+
+    // Attributes are cached by the embedder, so their copy callbacks have to be invoked here
+    let cached_attributes = env.mpi_attributes.read().unwrap().attributes(MpiObjectKind::Comm, comm);
+    for (keyval_id, value) in cached_attributes {
+        let keyval = env.mpi_attributes.read().unwrap().keyval(keyval_id).expect("invalid keyval");
+        match attributes::call_copy_fn(env, &keyval, comm, keyval_id, value) {
+            Ok(Some(new_value)) => {
+                env.mpi_attributes.write().unwrap().set(MpiObjectKind::Comm, newcomm_id, keyval_id, new_value);
+            }
+            Ok(None) => {}
+            // A failed copy callback fails the duplication, which undoes the attributes copied so far
+            Err(copy_ret) => {
+                attributes::delete_all_attrs(env, MpiObjectKind::Comm, newcomm_id);
+                unsafe {
+                    mpi_sys::MPI_Comm_free(&mut host_newcomm.0);
+                }
+                env.mpi_comms.free_instance(newcomm_id);
+                newcomm_ptr.set(MPI_COMM_NULL);
+                return copy_ret;
+            }
+        }
+    }
+
     newcomm_ptr.set(newcomm_id);
     mpi_ret
 }
@@ -210,35 +422,64 @@ pub fn MPI_Comm_free(env: &Env, comm: WasmPtr<i32>) -> i32 {
     let comm_ptr = comm.deref(memory).expect("out-of-bounds ptr in  MPI_Comm_free");
     let comm_id = comm_ptr.get();
 
-    let mut mpi_comms = env.mpi_comms.write().unwrap();
-    let mpi_comm = mpi_comms.get_mut(comm_id).expect("invalid comm");
-    let host_comm_ref: *mut mpi_sys::MPI_Comm = &mut mpi_comm.0;
+    let mut mpi_comm = env.mpi_comms.get(comm_id).expect("invalid comm");
+
+    let attr_ret = attributes::delete_all_attrs(env, MpiObjectKind::Comm, comm_id);
+    if attr_ret != MPI_SUCCESS {
+        return attr_ret;
+    }
 
     unsafe {
-        mpi_sys::MPI_Comm_free(host_comm_ref);
+        mpi_sys::MPI_Comm_free(&mut mpi_comm.0);
     }
 
-    mpi_comms.free_instance(comm_id);
+    env.mpi_comms.free_instance(comm_id);
     return MPI_SUCCESS;
 }
 
 
+#[allow(non_snake_case)]
+pub fn MPI_Comm_free_keyval(env: &Env, comm_keyval: WasmPtr<i32>) -> i32 {
+    attributes::free_keyval(env, MpiObjectKind::Comm, comm_keyval)
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Comm_get_attr(env: &Env, comm: i32, comm_keyval: i32, attribute_val: WasmPtr<WasmPtr<u8>>, flag: WasmPtr<i32>) -> i32 {
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
+
+    if let Some(host_keyval) = attributes::host_predefined_keyval(comm_keyval) {
+        let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Comm_get_attr");
+        let attribute_val_ptr = attribute_val.deref(memory).expect("out-of-bounds ptr in MPI_Comm_get_attr");
+        let flag_ptr = flag.deref(memory).expect("out-of-bounds ptr in MPI_Comm_get_attr");
+
+        match attributes::get_predefined_attr(env, host_comm, comm_keyval, host_keyval) {
+            Some(value) => {
+                attribute_val_ptr.set(WasmPtr::new(value as u32));
+                flag_ptr.set(1);
+            }
+            None => flag_ptr.set(0),
+        }
+        return MPI_SUCCESS;
+    }
+
+    attributes::get_attr(env, MpiObjectKind::Comm, comm, comm_keyval, attribute_val, flag)
+}
+
+
 #[allow(non_snake_case)]
 pub fn MPI_Comm_group(env: &Env, comm: i32, group: WasmPtr<i32>) -> i32 {
     let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Comm_group");
     let group_ptr = group.deref(memory).expect("out-of-bounds ptr in MPI_Comm_group");
 
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid_comm");
-
-    let mut mpi_groups = env.mpi_groups.write().unwrap();
-    let (group_id, mpi_group) = mpi_groups.alloc_instance();
-    let host_group_ref: *mut mpi_sys::MPI_Group = &mut mpi_group.0;
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid_comm");
 
+    let mut mpi_group = MpiGroup::default();
     unsafe {
-        mpi_sys::MPI_Comm_group(host_comm, host_group_ref);
+        mpi_sys::MPI_Comm_group(host_comm, &mut mpi_group.0);
     }
 
+    let group_id = env.mpi_groups.alloc_instance(mpi_group);
     group_ptr.set(group_id);
     return MPI_SUCCESS;
 }
@@ -249,23 +490,20 @@ pub fn MPI_Comm_split(env: &Env, comm: i32, color: i32, key: i32, newcomm: WasmP
     let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Comm_split");
     let newcomm_ptr = newcomm.deref(memory).expect("out-of-bounds ptr in MPI_Comm_split");
 
-    let mut mpi_comms = env.mpi_comms.write().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid_comm");
-
-    let (newcomm_id, mpi_newcomm) = mpi_comms.alloc_instance();
-    let host_newcomm_ref: *mut mpi_sys::MPI_Comm = &mut mpi_newcomm.0;
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid_comm");
 
+    let mut mpi_newcomm = MpiComm::default();
     let newcomm_is_null: bool;
     unsafe {
-        mpi_sys::MPI_Comm_split(host_comm, color, key, host_newcomm_ref);
+        mpi_sys::MPI_Comm_split(host_comm, color, key, &mut mpi_newcomm.0);
         newcomm_is_null = mpi_newcomm.0 == mpi_sys::RSMPI_COMM_NULL;
     }
 
     // Handle the case where color == MPI_UNDEFINED which will lead to newcomm being set to MPI_COMM_NULL
     if newcomm_is_null  {
-        mpi_comms.free_instance(newcomm_id);
         newcomm_ptr.set(MPI_COMM_NULL);
     } else {
+        let newcomm_id = env.mpi_comms.alloc_instance(mpi_newcomm);
         newcomm_ptr.set(newcomm_id);
     }
 
@@ -273,10 +511,39 @@ pub fn MPI_Comm_split(env: &Env, comm: i32, color: i32, key: i32, newcomm: WasmP
 }
 
 
+#[allow(non_snake_case)]
+pub fn MPI_Comm_split_type(env: &Env, comm: i32, split_type: i32, key: i32, info: i32, newcomm: WasmPtr<i32>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Comm_split_type");
+    let newcomm_ptr = newcomm.deref(memory).expect("out-of-bounds ptr in MPI_Comm_split_type");
+
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid_comm");
+    let MpiInfo(host_info) = env.mpi_infos.get(info).expect("invalid info");
+    let host_split_type = match split_type {
+        MPI_COMM_TYPE_SHARED => mpi_sys::MPI_COMM_TYPE_SHARED as i32,
+        _ => mpi_sys::MPI_UNDEFINED,
+    };
+
+    let mut mpi_newcomm = MpiComm::default();
+    let newcomm_is_null: bool;
+    let mpi_ret = unsafe {
+        let mpi_ret = mpi_sys::MPI_Comm_split_type(host_comm, host_split_type, key, host_info, &mut mpi_newcomm.0);
+        newcomm_is_null = mpi_newcomm.0 == mpi_sys::RSMPI_COMM_NULL;
+        mpi_ret
+    };
+
+    if newcomm_is_null {
+        newcomm_ptr.set(MPI_COMM_NULL);
+    } else {
+        let newcomm_id = env.mpi_comms.alloc_instance(mpi_newcomm);
+        newcomm_ptr.set(newcomm_id);
+    }
+    mpi_ret
+}
+
+
 #[allow(non_snake_case)]
 pub fn MPI_Comm_rank(env: &Env, comm: i32, rank: WasmPtr<i32>) -> i32 {
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid communicator");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid communicator");
 
     let memory = env.memory.get_ref().expect("uninitialized memory in wasm_MPI_Comm_rank");
     let derefed_ptr = rank.deref(memory).expect("out-of-bounds ptr in wasm_MPI_Comm_rank");
@@ -287,10 +554,17 @@ pub fn MPI_Comm_rank(env: &Env, comm: i32, rank: WasmPtr<i32>) -> i32 {
 }
 
 
+#[allow(non_snake_case)]
+pub fn MPI_Comm_set_attr(env: &Env, comm: i32, comm_keyval: i32, attribute_val: i32) -> i32 {
+    env.mpi_comms.get(comm).expect("invalid comm");
+
+    attributes::set_attr(env, MpiObjectKind::Comm, comm, comm_keyval, attribute_val)
+}
+
+
 #[allow(non_snake_case)]
 pub fn MPI_Comm_size(env: &Env, comm: i32, size: WasmPtr<i32>) -> i32 {
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid communicator");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid communicator");
 
     let memory = env.memory.get_ref().expect("uninitialized memory in wasm_MPI_Comm_size");
     let derefed_ptr = size.deref(memory).expect("out-of-bounds ptr in wasm_MPI_Comm_size");
@@ -324,12 +598,9 @@ pub fn MPI_Gather(env:&Env, sendbuf: WasmPtr<u8>, sendcount: i32, sendtype: i32,
     let sendbuf_ptr = sendbuf.deref(memory).expect("out-of-bounds ptr in MPI_Gather");
     let recvbuf_ptr = recvbuf.deref(memory).expect("out-of-bounds ptr in MPI_Gather");
 
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(host_sendtype) = *mpi_datatypes.get(sendtype).expect("invalid sendtype");
-    let MpiDatatype(host_recvtype) = *mpi_datatypes.get(recvtype).expect("invalid recvtype");
-
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid comm");
+    let MpiDatatype(host_sendtype) = env.mpi_datatypes.get(sendtype).expect("invalid sendtype");
+    let MpiDatatype(host_recvtype) = env.mpi_datatypes.get(recvtype).expect("invalid recvtype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
 
     unsafe {
         mpi_sys::MPI_Gather(sendbuf_ptr.as_ptr() as *mut c_void, sendcount, host_sendtype, recvbuf_ptr.as_ptr() as *mut c_void, recvcount, host_recvtype, root, host_comm)
@@ -344,28 +615,47 @@ pub fn MPI_Get_count(env: &Env, status: WasmPtr<u8>, datatype: i32, count: WasmP
     let status_ptr = status.deref(memory).expect("out-of-bounds ptr in MPI_Get_count");
     let count_ptr = count.deref(memory).expect("out-of-bounds ptr in  MPI_Get count");
 
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(host_datatype) = *mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
 
     unsafe {
         mpi_sys::MPI_Get_count(status_ptr.as_ptr() as *mut mpi_sys::MPI_Status, host_datatype, count_ptr.as_ptr())
     }
 }
 
+#[allow(non_snake_case)]
+pub fn MPI_Get_count_c(env: &Env, status: WasmPtr<u8>, datatype: i32, count: WasmPtr<i64>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Get_count_c");
+    let status_ptr = status.deref(memory).expect("out-of-bounds ptr in MPI_Get_count_c");
+    let count_ptr = count.deref(memory).expect("out-of-bounds ptr in MPI_Get_count_c");
+
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+
+    // Messages beyond 2^31 elements are received with a datatype built from `datatype`, so the host
+    // can't count them in `datatype`. Every datatype of the module is a predefined host datatype,
+    // which is one basic element, and the large datatype consists of nothing else, so the number of
+    // basic elements is the count in both cases and `MPI_UNDEFINED` for partial elements.
+    let mut elements: mpi_sys::MPI_Count = 0;
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Get_elements_x(status_ptr.as_ptr() as *mut mpi_sys::MPI_Status, host_datatype, &mut elements)
+    };
+
+    count_ptr.set(elements as i64);
+    mpi_ret
+}
+
+
 #[allow(non_snake_case)]
 pub fn MPI_Group_free(env: &Env, group: WasmPtr<i32>) -> i32 {
     let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Group_free");
     let group_ptr = group.deref(memory).expect("out-of-bounds ptr in MPI_Group_free");
     let group_id = group_ptr.get();
 
-    let mut mpi_groups = env.mpi_groups.write().unwrap();
-    let mpi_group = mpi_groups.get_mut(group_id).expect("invalid group");
-    let host_group_ptr: *mut mpi_sys::MPI_Group = &mut mpi_group.0;
+    let mut mpi_group = env.mpi_groups.get(group_id).expect("invalid group");
 
     let mpi_ret = unsafe {
-        mpi_sys::MPI_Group_free(host_group_ptr)
+        mpi_sys::MPI_Group_free(&mut mpi_group.0)
     };
-    mpi_groups.free_instance(group_id);
+    env.mpi_groups.free_instance(group_id);
     mpi_ret
 }
 
@@ -381,21 +671,19 @@ pub fn MPI_Group_range_incl(
     let ranges_slice = ranges.deref(memory, 0, (3*n) as u32).expect("out-of-bounds ptr in MPI_Group_range_incl");
     let newgroup_ptr = newgroup.deref(memory).expect("out-of-bounds ptr in MPI_Group_range_incl");
 
-    let mut mpi_groups = env.mpi_groups.write().unwrap();
-    let MpiGroup(host_group) = *mpi_groups.get(group).expect("invalid group");
-
-    let (newgroup_id, host_newgroup) = mpi_groups.alloc_instance();
-    let host_newgroup_ptr: *mut mpi_sys::MPI_Group = &mut host_newgroup.0;
+    let MpiGroup(host_group) = env.mpi_groups.get(group).expect("invalid group");
 
+    let mut host_newgroup = MpiGroup::default();
     let mpi_ret = unsafe {
         mpi_sys::MPI_Group_range_incl(
             host_group,
             n,
             ranges_slice.as_ptr() as *mut [i32; 3],
-            host_newgroup_ptr,
+            &mut host_newgroup.0,
         )
     };
 
+    let newgroup_id = env.mpi_groups.alloc_instance(host_newgroup);
     newgroup_ptr.set(newgroup_id);
     mpi_ret
 }
@@ -406,9 +694,8 @@ pub fn MPI_Group_translate_ranks(env: &Env, group1: i32, n: i32, ranks1: WasmPtr
     let ranks1_ptr = ranks1.deref(memory).expect("out-of-bounds ptr in MPI_Group_translate_ranks");
     let ranks2_ptr = ranks2.deref(memory).expect("out-of-bounds ptr in MPI_Group_translate_ranks");
 
-    let mpi_groups = env.mpi_groups.read().unwrap();
-    let MpiGroup(host_group1) = *mpi_groups.get(group1).expect("invalid group1");
-    let MpiGroup(host_group2) = *mpi_groups.get(group2).expect("invalid group2");
+    let MpiGroup(host_group1) = env.mpi_groups.get(group1).expect("invalid group1");
+    let MpiGroup(host_group2) = env.mpi_groups.get(group2).expect("invalid group2");
 
     unsafe {
         mpi_sys::MPI_Group_translate_ranks(host_group1, n, ranks1_ptr.as_ptr(), host_group2, ranks2_ptr.as_ptr())
@@ -416,12 +703,148 @@ pub fn MPI_Group_translate_ranks(env: &Env, group1: i32, n: i32, ranks1: WasmPtr
 }
 
 
+#[allow(non_snake_case)]
+pub fn MPI_Info_create(env: &Env, info: WasmPtr<i32>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Info_create");
+    let info_ptr = info.deref(memory).expect("out-of-bounds ptr in MPI_Info_create");
+
+    let mut host_info = MpiInfo::default();
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Info_create(&mut host_info.0)
+    };
+
+    let info_id = env.mpi_infos.alloc_instance(host_info);
+    info_ptr.set(info_id);
+    mpi_ret
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Info_delete(env: &Env, info: i32, key: WasmPtr<u8, Array>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Info_delete");
+    let host_key = read_c_string(memory, key).expect("out-of-bounds key in MPI_Info_delete");
+
+    let MpiInfo(host_info) = env.mpi_infos.get(info).expect("invalid info");
+
+    unsafe {
+        mpi_sys::MPI_Info_delete(host_info, host_key.as_ptr())
+    }
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Info_dup(env: &Env, info: i32, newinfo: WasmPtr<i32>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Info_dup");
+    let newinfo_ptr = newinfo.deref(memory).expect("out-of-bounds ptr in MPI_Info_dup");
+
+    let MpiInfo(host_info) = env.mpi_infos.get(info).expect("invalid info");
+
+    let mut host_newinfo = MpiInfo::default();
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Info_dup(host_info, &mut host_newinfo.0)
+    };
+
+    let newinfo_id = env.mpi_infos.alloc_instance(host_newinfo);
+    newinfo_ptr.set(newinfo_id);
+    mpi_ret
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Info_free(env: &Env, info: WasmPtr<i32>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Info_free");
+    let info_ptr = info.deref(memory).expect("out-of-bounds ptr in MPI_Info_free");
+    let info_id = info_ptr.get();
+
+    let mut mpi_info = env.mpi_infos.get(info_id).expect("invalid info");
+
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Info_free(&mut mpi_info.0)
+    };
+    env.mpi_infos.free_instance(info_id);
+    info_ptr.set(MPI_INFO_NULL);
+    mpi_ret
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Info_get(env: &Env, info: i32, key: WasmPtr<u8, Array>, valuelen: i32, value: WasmPtr<u8, Array>, flag: WasmPtr<i32>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Info_get");
+    let host_key = read_c_string(memory, key).expect("out-of-bounds key in MPI_Info_get");
+    // MPI writes up to valuelen characters and the terminating NUL byte
+    let value_ptr = guest_bytes(memory, value, valuelen.max(0) as u64 + 1).expect("out-of-bounds ptr in MPI_Info_get");
+    let flag_ptr = flag.deref(memory).expect("out-of-bounds ptr in MPI_Info_get");
+
+    let MpiInfo(host_info) = env.mpi_infos.get(info).expect("invalid info");
+
+    unsafe {
+        mpi_sys::MPI_Info_get(host_info, host_key.as_ptr(), valuelen, value_ptr as *mut c_char, flag_ptr.as_ptr())
+    }
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Info_get_nkeys(env: &Env, info: i32, nkeys: WasmPtr<i32>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Info_get_nkeys");
+    let nkeys_ptr = nkeys.deref(memory).expect("out-of-bounds ptr in MPI_Info_get_nkeys");
+
+    let MpiInfo(host_info) = env.mpi_infos.get(info).expect("invalid info");
+
+    unsafe {
+        mpi_sys::MPI_Info_get_nkeys(host_info, nkeys_ptr.as_ptr())
+    }
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Info_get_nthkey(env: &Env, info: i32, n: i32, key: WasmPtr<u8, Array>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Info_get_nthkey");
+    let key_slice = key.deref(memory, 0, (MPI_MAX_INFO_KEY + 1) as u32).expect("out-of-bounds ptr in MPI_Info_get_nthkey");
+
+    let MpiInfo(host_info) = env.mpi_infos.get(info).expect("invalid info");
+
+    // The host's MPI_MAX_INFO_KEY may be larger than the one the guest sized its buffer with
+    // (OpenMPI uses 36 like the guest, MPICH uses 255)
+    let mut host_key = vec![0 as c_char; 1024];
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Info_get_nthkey(host_info, n, host_key.as_mut_ptr())
+    };
+
+    let key_len = host_key.iter().position(|c| *c == 0).unwrap_or(host_key.len()).min(MPI_MAX_INFO_KEY as usize);
+    for i in 0..key_len {
+        key_slice[i].set(host_key[i] as u8);
+    }
+    key_slice[key_len].set(0);
+    mpi_ret
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Info_set(env: &Env, info: i32, key: WasmPtr<u8, Array>, value: WasmPtr<u8, Array>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Info_set");
+    let host_key = read_c_string(memory, key).expect("out-of-bounds key in MPI_Info_set");
+    let host_value = read_c_string(memory, value).expect("out-of-bounds value in MPI_Info_set");
+
+    let MpiInfo(host_info) = env.mpi_infos.get(info).expect("invalid info");
+
+    unsafe {
+        mpi_sys::MPI_Info_set(host_info, host_key.as_ptr(), host_value.as_ptr())
+    }
+}
+
+
 #[allow(non_snake_case)]
 pub fn MPI_Init(env: &Env, _argc: i32, _argv: i32) -> i32 {
+    // The embedder initializes MPI itself to compile modules collectively
+    let mut initialized = 0;
     unsafe {
-        // OpenMPI does not use argc and argv so just pass nullptr to them
-        mpi_sys::MPI_Init(null_mut(), null_mut())
+        mpi_sys::MPI_Initialized(&mut initialized);
+    }
+    if initialized != 0 {
+        return MPI_SUCCESS;
     }
+
+    init_host_mpi()
 }
 
 
@@ -438,45 +861,81 @@ pub fn MPI_Initialized(env: &Env, flag: WasmPtr<i32>) -> i32 {
 
 #[allow(non_snake_case)]
 pub fn MPI_Irecv(env: &Env, buf: WasmPtr<u8>, count: i32, datatype: i32, source: i32, tag: i32, comm: i32, request: WasmPtr<i32>) -> i32 {
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid communicator");
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(host_datatype) = *mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid communicator");
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
 
     let memory = env.memory.get_ref().expect("uninitialized memory in wasm_MPI_Irecv");
     let buf_ptr = buf.deref(memory).expect("out-of-bounds ptr in wasm_MPI_Irecv");
     let request_ptr = request.deref(memory).expect("out-of-bounds ptr in wasm_MPI_Irecv");
 
-    let mut mpi_requests = env.mpi_requests.write().unwrap();
-    let (request_id, mpi_request) = mpi_requests.alloc_instance();
-    let host_request_ptr: *mut mpi_sys::MPI_Request = &mut mpi_request.0;
+    let mut mpi_request = MpiRequest::default();
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Irecv(buf_ptr.as_ptr() as *mut c_void, count, host_datatype, source, tag, host_comm, &mut mpi_request.0)
+    };
 
+    let request_id = env.mpi_requests.alloc_instance(mpi_request);
     request_ptr.set(request_id);
-    unsafe {
-        mpi_sys::MPI_Irecv(buf_ptr.as_ptr() as *mut c_void, count, host_datatype, source, tag, host_comm, host_request_ptr)
-    }
+    mpi_ret
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Irecv_c(env: &Env, buf: WasmPtr<u8, Array>, count: i64, datatype: i32, source: i32, tag: i32, comm: i32, request: WasmPtr<i32>) -> i32 {
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid communicator");
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Irecv_c");
+    let buf_ptr = guest_bytes(memory, buf, buffer_len(host_datatype, count)).expect("out-of-bounds ptr in MPI_Irecv_c");
+    let request_ptr = request.deref(memory).expect("out-of-bounds ptr in MPI_Irecv_c");
+
+    let mut mpi_request = MpiRequest::default();
+    let mpi_ret = with_large_count(host_datatype, count, |host_count, host_datatype| unsafe {
+        mpi_sys::MPI_Irecv(buf_ptr as *mut c_void, host_count, host_datatype, source, tag, host_comm, &mut mpi_request.0)
+    });
+
+    let request_id = env.mpi_requests.alloc_instance(mpi_request);
+    request_ptr.set(request_id);
+    mpi_ret
 }
 
 
 #[allow(non_snake_case)]
 pub fn MPI_Isend(env: &Env, buf: WasmPtr<u8>, count: i32, datatype: i32, dest: i32, tag: i32, comm: i32, request: WasmPtr<i32>) -> i32 {
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid communicator");
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(host_datatype) = *mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid communicator");
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
 
     let memory = env.memory.get_ref().expect("uninitialized memory in wasm_MPI_Isend");
     let buf_ptr = buf.deref(memory).expect("out-of-bounds ptr in wasm_MPI_Isend");
     let request_ptr = request.deref(memory).expect("out-of-bounds ptr in wasm_MPI_Isend");
 
-    let mut mpi_requests = env.mpi_requests.write().unwrap();
-    let (request_id, mpi_request) = mpi_requests.alloc_instance();
-    let host_request_ptr: *mut mpi_sys::MPI_Request = &mut mpi_request.0;
+    let mut mpi_request = MpiRequest::default();
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Isend(buf_ptr.as_ptr() as *mut c_void, count, host_datatype, dest, tag, host_comm, &mut mpi_request.0)
+    };
 
+    let request_id = env.mpi_requests.alloc_instance(mpi_request);
     request_ptr.set(request_id);
-    unsafe {
-        mpi_sys::MPI_Isend(buf_ptr.as_ptr() as *mut c_void, count, host_datatype, dest, tag, host_comm, host_request_ptr)
-    }
+    mpi_ret
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Isend_c(env: &Env, buf: WasmPtr<u8, Array>, count: i64, datatype: i32, dest: i32, tag: i32, comm: i32, request: WasmPtr<i32>) -> i32 {
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid communicator");
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Isend_c");
+    let buf_ptr = guest_bytes(memory, buf, buffer_len(host_datatype, count)).expect("out-of-bounds ptr in MPI_Isend_c");
+    let request_ptr = request.deref(memory).expect("out-of-bounds ptr in MPI_Isend_c");
+
+    let mut mpi_request = MpiRequest::default();
+    let mpi_ret = with_large_count(host_datatype, count, |host_count, host_datatype| unsafe {
+        mpi_sys::MPI_Isend(buf_ptr as *mut c_void, host_count, host_datatype, dest, tag, host_comm, &mut mpi_request.0)
+    });
+
+    let request_id = env.mpi_requests.alloc_instance(mpi_request);
+    request_ptr.set(request_id);
+    mpi_ret
 }
 
 
@@ -486,17 +945,83 @@ pub fn MPI_Op_create(env: &Env, function: i32, commute: i32, op: WasmPtr<i32>) -
 }
 
 
+#[allow(non_snake_case)]
+pub fn MPI_Pack(env: &Env, inbuf: WasmPtr<u8, Array>, incount: i32, datatype: i32, outbuf: WasmPtr<u8, Array>, outsize: i32, position: WasmPtr<i32>, comm: i32) -> i32 {
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Pack");
+    let inbuf_ptr = guest_bytes(memory, inbuf, buffer_len(host_datatype, incount as i64)).expect("out-of-bounds ptr in MPI_Pack");
+    let outbuf_ptr = guest_bytes(memory, outbuf, outsize.max(0) as u64).expect("out-of-bounds ptr in MPI_Pack");
+    let position_ptr = position.deref(memory).expect("out-of-bounds ptr in MPI_Pack");
+
+    unsafe {
+        mpi_sys::MPI_Pack(inbuf_ptr as *mut c_void, incount, host_datatype, outbuf_ptr as *mut c_void, outsize, position_ptr.as_ptr(), host_comm)
+    }
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Pack_external(env: &Env, datarep: WasmPtr<u8, Array>, inbuf: WasmPtr<u8, Array>, incount: i32, datatype: i32, outbuf: WasmPtr<u8, Array>, outsize: i32, position: WasmPtr<i32>) -> i32 {
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Pack_external");
+    let host_datarep = read_c_string(memory, datarep).expect("out-of-bounds datarep in MPI_Pack_external");
+    let inbuf_ptr = guest_bytes(memory, inbuf, buffer_len(host_datatype, incount as i64)).expect("out-of-bounds ptr in MPI_Pack_external");
+    let outbuf_ptr = guest_bytes(memory, outbuf, outsize.max(0) as u64).expect("out-of-bounds ptr in MPI_Pack_external");
+    let position_ptr = position.deref(memory).expect("out-of-bounds ptr in MPI_Pack_external");
+
+    // MPI_Aint is 32 bits wide in the guest
+    let mut host_position = position_ptr.get() as mpi_sys::MPI_Aint;
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Pack_external(host_datarep.as_ptr(), inbuf_ptr as *mut c_void, incount, host_datatype, outbuf_ptr as *mut c_void, outsize as mpi_sys::MPI_Aint, &mut host_position)
+    };
+
+    position_ptr.set(host_position as i32);
+    mpi_ret
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Pack_external_size(env: &Env, datarep: WasmPtr<u8, Array>, incount: i32, datatype: i32, size: WasmPtr<i32>) -> i32 {
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Pack_external_size");
+    let host_datarep = read_c_string(memory, datarep).expect("out-of-bounds datarep in MPI_Pack_external_size");
+    let size_ptr = size.deref(memory).expect("out-of-bounds ptr in MPI_Pack_external_size");
+
+    let mut host_size: mpi_sys::MPI_Aint = 0;
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Pack_external_size(host_datarep.as_ptr(), incount, host_datatype, &mut host_size)
+    };
+
+    size_ptr.set(host_size as i32);
+    mpi_ret
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Pack_size(env: &Env, incount: i32, datatype: i32, comm: i32, size: WasmPtr<i32>) -> i32 {
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Pack_size");
+    let size_ptr = size.deref(memory).expect("out-of-bounds ptr in MPI_Pack_size");
+
+    unsafe {
+        mpi_sys::MPI_Pack_size(incount, host_datatype, host_comm, size_ptr.as_ptr())
+    }
+}
+
+
 #[allow(non_snake_case)]
 pub fn MPI_Recv(env: &Env, buf: WasmPtr<u8>, count: i32, datatype: i32, source: i32, tag: i32, comm: i32, status: WasmPtr<i32>) -> i32 {
     let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Recv");
     let buf_ptr = buf.deref(memory).expect("out-of-bounds ptr in MPI_Recv");
     let status_ptr = status.deref(memory).expect("out-of-bounds ptr in MPI_Recv");
 
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(host_datatype) = *mpi_datatypes.get(datatype).expect("invalid datatype");
-
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid comm");
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
 
     unsafe {
         mpi_sys::MPI_Recv(buf_ptr.as_ptr() as *mut c_void, count, host_datatype, source, tag, host_comm, status_ptr.as_ptr() as *mut mpi_sys::MPI_Status)
@@ -505,24 +1030,75 @@ pub fn MPI_Recv(env: &Env, buf: WasmPtr<u8>, count: i32, datatype: i32, source:
 }
 
 
+#[allow(non_snake_case)]
+pub fn MPI_Recv_c(env: &Env, buf: WasmPtr<u8, Array>, count: i64, datatype: i32, source: i32, tag: i32, comm: i32, status: WasmPtr<i32>) -> i32 {
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Recv_c");
+    let buf_ptr = guest_bytes(memory, buf, buffer_len(host_datatype, count)).expect("out-of-bounds ptr in MPI_Recv_c");
+    let status_ptr = status.deref(memory).expect("out-of-bounds ptr in MPI_Recv_c");
+
+    with_large_count(host_datatype, count, |host_count, host_datatype| unsafe {
+        mpi_sys::MPI_Recv(buf_ptr as *mut c_void, host_count, host_datatype, source, tag, host_comm, status_ptr.as_ptr() as *mut mpi_sys::MPI_Status)
+    })
+}
+
+
 #[allow(non_snake_case)]
 pub fn MPI_Reduce(env: &Env, sendbuf: WasmPtr<u8>, recvbuf: WasmPtr<u8>, count: i32, datatype: i32, op: i32, root: i32, comm: i32) -> i32 {
     let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Reduce");
     let sendbuf_ptr = sendbuf.deref(memory).expect("out-of-bounds ptr in MPI_Reduce");
     let recvbuf_ptr = recvbuf.deref(memory).expect("out-of-bounds ptr in MPI_Reduce");
 
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(host_datatype) = *mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiOp(host_op) = env.mpi_ops.get(op).expect("invalid op");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
 
-    let mpi_ops = env.mpi_ops.read().unwrap();
-    let MpiOp(host_op) = *mpi_ops.get(op).expect("invalid op");
+    unsafe {
+        mpi_sys::MPI_Reduce(sendbuf_ptr.as_ptr() as *mut c_void, recvbuf_ptr.as_ptr() as *mut c_void, count, host_datatype, host_op, root, host_comm)
+    }
+}
 
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid comm");
 
+#[allow(non_snake_case)]
+pub fn MPI_Reduce_c(env: &Env, sendbuf: WasmPtr<u8, Array>, recvbuf: WasmPtr<u8, Array>, count: i64, datatype: i32, op: i32, root: i32, comm: i32) -> i32 {
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiOp(host_op) = env.mpi_ops.get(op).expect("invalid op");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Reduce_c");
+    let len = buffer_len(host_datatype, count);
+
+    // The receive buffer is only significant at the root, which is also the only rank that may
+    // reduce in place
+    let mut rank: i32 = -1;
     unsafe {
-        mpi_sys::MPI_Reduce(sendbuf_ptr.as_ptr() as *mut c_void, recvbuf_ptr.as_ptr() as *mut c_void, count, host_datatype, host_op, root, host_comm)
+        mpi_sys::MPI_Comm_rank(host_comm, &mut rank);
     }
+    let sendbuf_ptr = if sendbuf.offset() as i32 == MPI_IN_PLACE {
+        assert!(rank == root, "MPI_IN_PLACE is only allowed at the root in MPI_Reduce_c");
+        None
+    } else {
+        Some(guest_bytes(memory, sendbuf, len).expect("out-of-bounds ptr in MPI_Reduce_c"))
+    };
+    let recvbuf_ptr = if rank == root {
+        Some(guest_bytes(memory, recvbuf, len).expect("out-of-bounds ptr in MPI_Reduce_c"))
+    } else {
+        None
+    };
+
+    for_each_chunk(host_datatype, count, |offset, chunk_count| unsafe {
+        let recvbuf_ptr = match recvbuf_ptr {
+            Some(recvbuf_ptr) => recvbuf_ptr.add(offset) as *mut c_void,
+            None => null_mut()
+        };
+        let sendbuf_ptr = match sendbuf_ptr {
+            Some(sendbuf_ptr) => sendbuf_ptr.add(offset) as *mut c_void,
+            None => host_in_place(),
+        };
+        mpi_sys::MPI_Reduce(sendbuf_ptr, recvbuf_ptr, chunk_count, host_datatype, host_op, root, host_comm)
+    })
 }
 
 
@@ -532,12 +1108,9 @@ pub fn MPI_Scatter(env: &Env, sendbuf: WasmPtr<u8>, sendcount: i32, sendtype: i3
     let sendbuf_ptr = sendbuf.deref(memory).expect("out-of-bounds ptr in MPI_Scatter");
     let recvbuf_ptr = recvbuf.deref(memory).expect("out-of-bounds ptr in MPI_Scatter");
 
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(host_sendtype) = *mpi_datatypes.get(sendtype).expect("invalid sendtype");
-    let MpiDatatype(host_recvtype) = *mpi_datatypes.get(recvtype).expect("invalid recvtype");
-
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid comm");
+    let MpiDatatype(host_sendtype) = env.mpi_datatypes.get(sendtype).expect("invalid sendtype");
+    let MpiDatatype(host_recvtype) = env.mpi_datatypes.get(recvtype).expect("invalid recvtype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
 
     unsafe {
         mpi_sys::MPI_Scatter(sendbuf_ptr.as_ptr() as *mut c_void, sendcount, host_sendtype, recvbuf_ptr.as_ptr() as *mut c_void, recvcount, host_recvtype, root, host_comm)
@@ -550,11 +1123,8 @@ pub fn MPI_Send(env: &Env, buf: WasmPtr<u8>, count: i32, datatype: i32, dest: i3
     let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Send");
     let buf_ptr = buf.deref(memory).expect("out-of-bounds ptr in MPI_Send");
 
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(host_datatype) = *mpi_datatypes.get(datatype).expect("invalid datatype");
-
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(host_comm) = *mpi_comms.get(comm).expect("invalid comm");
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
     
     unsafe {
         mpi_sys::MPI_Send(buf_ptr.as_ptr() as *mut c_void, count, host_datatype, dest, tag, host_comm)
@@ -562,6 +1132,20 @@ pub fn MPI_Send(env: &Env, buf: WasmPtr<u8>, count: i32, datatype: i32, dest: i3
 }
 
 
+#[allow(non_snake_case)]
+pub fn MPI_Send_c(env: &Env, buf: WasmPtr<u8, Array>, count: i64, datatype: i32, dest: i32, tag: i32, comm: i32) -> i32 {
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Send_c");
+    let buf_ptr = guest_bytes(memory, buf, buffer_len(host_datatype, count)).expect("out-of-bounds ptr in MPI_Send_c");
+
+    with_large_count(host_datatype, count, |host_count, host_datatype| unsafe {
+        mpi_sys::MPI_Send(buf_ptr as *mut c_void, host_count, host_datatype, dest, tag, host_comm)
+    })
+}
+
+
 #[allow(non_snake_case)]
 pub fn MPI_Sendrecv(env: &Env, sendbuf: WasmPtr<u8>, sendcount: i32, sendtype: i32, dest: i32, sendtag: i32, recvbuf: WasmPtr<u8>, recvcount: i32, recvtype: i32, source: i32, recvtag: i32, comm: i32, status: WasmPtr<u8>) -> i32 {
     let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Sendrecv");
@@ -569,12 +1153,9 @@ pub fn MPI_Sendrecv(env: &Env, sendbuf: WasmPtr<u8>, sendcount: i32, sendtype: i
     let recvbuf_ptr = recvbuf.deref(memory).expect("out-of-bounds ptr in MPI_Sendrecv");
     let status_ptr = status.deref(memory).expect("out-of-bounds ptr in MPI_Sendrecv");
 
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(sendtype_host) = *mpi_datatypes.get(sendtype).expect("invalid sendtype");
-    let MpiDatatype(recvtype_host) = *mpi_datatypes.get(recvtype).expect("invalid recvtype");
-
-    let mpi_comms = env.mpi_comms.read().unwrap();
-    let MpiComm(comm_host) = *mpi_comms.get(comm).expect("invalid_comm");
+    let MpiDatatype(sendtype_host) = env.mpi_datatypes.get(sendtype).expect("invalid sendtype");
+    let MpiDatatype(recvtype_host) = env.mpi_datatypes.get(recvtype).expect("invalid recvtype");
+    let MpiComm(comm_host) = env.mpi_comms.get(comm).expect("invalid_comm");
 
     unsafe {
         mpi_sys::MPI_Sendrecv(sendbuf_ptr.as_ptr() as *mut c_void, sendcount, sendtype_host, dest, sendtag, recvbuf_ptr.as_ptr() as *mut c_void, recvcount, recvtype_host, source, recvtag, comm_host, status_ptr.as_ptr() as *mut mpi_sys::MPI_Status)
@@ -582,9 +1163,85 @@ pub fn MPI_Sendrecv(env: &Env, sendbuf: WasmPtr<u8>, sendcount: i32, sendtype: i
 }
 
 
+#[allow(non_snake_case)]
+pub fn MPI_Testany(env: &Env, count: i32, array_of_requests: WasmPtr<i32, Array>, index: WasmPtr<i32>, flag: WasmPtr<i32>, status: WasmPtr<u64>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Testany");
+    let requests = array_of_requests.deref(memory, 0, count.max(0) as u32).expect("out-of-bounds ptr in MPI_Testany");
+    let index_ptr = index.deref(memory).expect("out-of-bounds ptr in MPI_Testany");
+    let flag_ptr = flag.deref(memory).expect("out-of-bounds ptr in MPI_Testany");
+    let status_ptr = status.deref(memory).expect("out-of-bounds ptr in MPI_Testany");
+
+    let mut host_requests = host_requests(env, &requests);
+    let mut host_index = 0;
+    let mut host_flag = 0;
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Testany(count, host_requests.as_mut_ptr(), &mut host_index, &mut host_flag, status_ptr.as_ptr() as *mut mpi_sys::MPI_Status)
+    };
+
+    index_ptr.set(complete_any(env, &requests, host_index));
+    flag_ptr.set(host_flag);
+    mpi_ret
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Type_create_keyval(env: &Env, type_copy_attr_fn: i32, type_delete_attr_fn: i32, type_keyval: WasmPtr<i32>, extra_state: i32) -> i32 {
+    attributes::create_keyval(env, MpiObjectKind::Datatype, type_copy_attr_fn, type_delete_attr_fn, type_keyval, extra_state)
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Type_delete_attr(env: &Env, datatype: i32, type_keyval: i32) -> i32 {
+    env.mpi_datatypes.get(datatype).expect("invalid datatype");
+
+    attributes::delete_attr(env, MpiObjectKind::Datatype, datatype, type_keyval)
+}
+
+
 #[allow(non_snake_case)]
 pub fn MPI_Type_free(env: &Env, datatype: WasmPtr<i32>) -> i32 {
-    panic!("MPI_Type_free is not supported yet");
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Type_free");
+    let datatype_ptr = datatype.deref(memory).expect("out-of-bounds ptr in MPI_Type_free");
+    let datatype_id = datatype_ptr.get();
+
+    let mut mpi_datatype = env.mpi_datatypes.get(datatype_id).expect("invalid datatype");
+
+    let attr_ret = attributes::delete_all_attrs(env, MpiObjectKind::Datatype, datatype_id);
+    if attr_ret != MPI_SUCCESS {
+        return attr_ret;
+    }
+
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Type_free(&mut mpi_datatype.0)
+    };
+    // Predefined datatypes can't be freed, MPI reports that as an error
+    if mpi_ret == MPI_SUCCESS {
+        env.mpi_datatypes.free_instance(datatype_id);
+        datatype_ptr.set(MPI_DATATYPE_NULL);
+    }
+    mpi_ret
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Type_free_keyval(env: &Env, type_keyval: WasmPtr<i32>) -> i32 {
+    attributes::free_keyval(env, MpiObjectKind::Datatype, type_keyval)
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Type_get_attr(env: &Env, datatype: i32, type_keyval: i32, attribute_val: WasmPtr<WasmPtr<u8>>, flag: WasmPtr<i32>) -> i32 {
+    env.mpi_datatypes.get(datatype).expect("invalid datatype");
+
+    attributes::get_attr(env, MpiObjectKind::Datatype, datatype, type_keyval, attribute_val, flag)
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Type_set_attr(env: &Env, datatype: i32, type_keyval: i32, attribute_val: i32) -> i32 {
+    env.mpi_datatypes.get(datatype).expect("invalid datatype");
+
+    attributes::set_attr(env, MpiObjectKind::Datatype, datatype, type_keyval, attribute_val)
 }
 
 
@@ -592,8 +1249,7 @@ pub fn MPI_Type_free(env: &Env, datatype: WasmPtr<i32>) -> i32 {
 pub fn MPI_Type_size(env: &Env, datatype: i32, size: WasmPtr<i32>) -> i32 {
     let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Type_size");
     let size_ptr = size.deref(memory).expect("out-of-bounds ptr in MPI_Type_size");
-    let mpi_datatypes = env.mpi_datatypes.read().unwrap();
-    let MpiDatatype(host_datatype) = *mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
 
     unsafe {
         mpi_sys::MPI_Type_size(host_datatype, size_ptr.as_ptr())
@@ -601,6 +1257,59 @@ pub fn MPI_Type_size(env: &Env, datatype: i32, size: WasmPtr<i32>) -> i32 {
 }
 
 
+#[allow(non_snake_case)]
+pub fn MPI_Type_size_c(env: &Env, datatype: i32, size: WasmPtr<i64>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Type_size_c");
+    let size_ptr = size.deref(memory).expect("out-of-bounds ptr in MPI_Type_size_c");
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+
+    let mut host_size: mpi_sys::MPI_Count = 0;
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Type_size_x(host_datatype, &mut host_size)
+    };
+
+    size_ptr.set(host_size as i64);
+    mpi_ret
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Unpack(env: &Env, inbuf: WasmPtr<u8, Array>, insize: i32, position: WasmPtr<i32>, outbuf: WasmPtr<u8, Array>, outcount: i32, datatype: i32, comm: i32) -> i32 {
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+    let MpiComm(host_comm) = env.mpi_comms.get(comm).expect("invalid comm");
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Unpack");
+    let inbuf_ptr = guest_bytes(memory, inbuf, insize.max(0) as u64).expect("out-of-bounds ptr in MPI_Unpack");
+    let position_ptr = position.deref(memory).expect("out-of-bounds ptr in MPI_Unpack");
+    let outbuf_ptr = guest_bytes(memory, outbuf, buffer_len(host_datatype, outcount as i64)).expect("out-of-bounds ptr in MPI_Unpack");
+
+    unsafe {
+        mpi_sys::MPI_Unpack(inbuf_ptr as *mut c_void, insize, position_ptr.as_ptr(), outbuf_ptr as *mut c_void, outcount, host_datatype, host_comm)
+    }
+}
+
+
+#[allow(non_snake_case)]
+pub fn MPI_Unpack_external(env: &Env, datarep: WasmPtr<u8, Array>, inbuf: WasmPtr<u8, Array>, insize: i32, position: WasmPtr<i32>, outbuf: WasmPtr<u8, Array>, outcount: i32, datatype: i32) -> i32 {
+    let MpiDatatype(host_datatype) = env.mpi_datatypes.get(datatype).expect("invalid datatype");
+
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Unpack_external");
+    let host_datarep = read_c_string(memory, datarep).expect("out-of-bounds datarep in MPI_Unpack_external");
+    let inbuf_ptr = guest_bytes(memory, inbuf, insize.max(0) as u64).expect("out-of-bounds ptr in MPI_Unpack_external");
+    let position_ptr = position.deref(memory).expect("out-of-bounds ptr in MPI_Unpack_external");
+    let outbuf_ptr = guest_bytes(memory, outbuf, buffer_len(host_datatype, outcount as i64)).expect("out-of-bounds ptr in MPI_Unpack_external");
+
+    // MPI_Aint is 32 bits wide in the guest
+    let mut host_position = position_ptr.get() as mpi_sys::MPI_Aint;
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Unpack_external(host_datarep.as_ptr(), inbuf_ptr as *mut c_void, insize as mpi_sys::MPI_Aint, &mut host_position, outbuf_ptr as *mut c_void, outcount, host_datatype)
+    };
+
+    position_ptr.set(host_position as i32);
+    mpi_ret
+}
+
+
 #[allow(non_snake_case)]
 pub fn MPI_Wait(env: &Env, request: WasmPtr<i32>, status: WasmPtr<u64>) -> i32 {
     let memory = env.memory.get_ref().expect("uninitialized memory in wasm_MPI_Wait");
@@ -608,15 +1317,13 @@ pub fn MPI_Wait(env: &Env, request: WasmPtr<i32>, status: WasmPtr<u64>) -> i32 {
     let status_ptr = status.deref(memory).expect("out-of-bounds ptr in wasm_MPI_Wait");
 
     let request_id = request_ptr.get();
-    let mut mpi_requests = env.mpi_requests.write().unwrap();
-    let mpi_request = mpi_requests.get_mut(request_id).expect("invalid request");
-    let host_request_ptr: *mut mpi_sys::MPI_Request = &mut mpi_request.0;
+    let mut mpi_request = env.mpi_requests.get(request_id).expect("invalid request");
 
     let mpi_retval = unsafe {
-        mpi_sys::MPI_Wait(host_request_ptr, status_ptr.as_ptr() as *mut mpi_sys::MPI_Status)
+        mpi_sys::MPI_Wait(&mut mpi_request.0, status_ptr.as_ptr() as *mut mpi_sys::MPI_Status)
     };
 
-    mpi_requests.free_instance(request_id);
+    env.mpi_requests.free_instance(request_id);
     mpi_retval
 }
 
@@ -627,9 +1334,49 @@ pub fn MPI_Waitall(env: &Env, count: i32, array_of_requests: WasmPtr<i32>, array
 }
 
 
+#[allow(non_snake_case)]
+pub fn MPI_Waitany(env: &Env, count: i32, array_of_requests: WasmPtr<i32, Array>, index: WasmPtr<i32>, status: WasmPtr<u64>) -> i32 {
+    let memory = env.memory.get_ref().expect("uninitialized memory in MPI_Waitany");
+    let requests = array_of_requests.deref(memory, 0, count.max(0) as u32).expect("out-of-bounds ptr in MPI_Waitany");
+    let index_ptr = index.deref(memory).expect("out-of-bounds ptr in MPI_Waitany");
+    let status_ptr = status.deref(memory).expect("out-of-bounds ptr in MPI_Waitany");
+
+    let mut host_requests = host_requests(env, &requests);
+    let mut host_index = 0;
+    let mpi_ret = unsafe {
+        mpi_sys::MPI_Waitany(count, host_requests.as_mut_ptr(), &mut host_index, status_ptr.as_ptr() as *mut mpi_sys::MPI_Status)
+    };
+
+    index_ptr.set(complete_any(env, &requests, host_index));
+    mpi_ret
+}
+
+
 #[allow(non_snake_case)]
 pub fn MPI_Wtime(env: &Env) -> f64 {
     unsafe {
         mpi_sys::MPI_Wtime()
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_blocks_cover_the_count() {
+        assert_eq!(chunk_blocks(0, 4), vec![(0, 0)]);
+        assert_eq!(chunk_blocks(3, 4), vec![(0, 3)]);
+        assert_eq!(chunk_blocks(8, 4), vec![(0, 4), (4, 4)]);
+        assert_eq!(chunk_blocks(11, 4), vec![(0, 4), (4, 4), (8, 3)]);
+    }
+
+    #[test]
+    fn chunk_blocks_split_large_counts() {
+        let count = 2 * LARGE_COUNT_CHUNK + 5;
+        let blocks = chunk_blocks(count, LARGE_COUNT_CHUNK);
+        assert_eq!(blocks, vec![(0, LARGE_COUNT_CHUNK as i32), (LARGE_COUNT_CHUNK, LARGE_COUNT_CHUNK as i32), (2 * LARGE_COUNT_CHUNK, 5)]);
+        assert_eq!(blocks.iter().map(|(_, len)| *len as i64).sum::<i64>(), count);
+    }
+}
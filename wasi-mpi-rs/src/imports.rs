@@ -0,0 +1,328 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use wasmer::{Exports, Extern, ExternType, Function, FunctionType, ImportObject, ImportType, Module, NamedResolver, RuntimeError, Store};
+
+use crate::*;
+
+/// The namespace that modules built with the WASI MPI toolchain import MPI functions from
+pub const IMPORT_NAMESPACE: &str = "env";
+
+
+/// Generates the registry of host functions from `"import_name" => function` entries
+macro_rules! host_functions {
+    ($($name:literal => $func:ident,)*) => {
+        /// Import names of all host functions provided by this crate
+        pub const HOST_FUNCTIONS: &[&str] = &[$($name),*];
+
+        fn host_function(name: &str, store: &Store, env: &Env) -> Option<Function> {
+            match name {
+                $($name => {
+                    struct Name;
+                    impl HostFunctionName for Name {
+                        const NAME: &'static &'static str = &$name;
+                    }
+                    Some($func.tracked::<Name>(store, env))
+                })*
+                _ => None,
+            }
+        }
+    };
+}
+
+host_functions! {
+    "__cxa_allocate_exception" => wasm__cxa_allocate_exception,
+    "__cxa_begin_catch" => wasm__cxa_begin_catch,
+    "__cxa_end_catch" => wasm__cxa_end_catch,
+    "__cxa_free_exception" => wasm__cxa_free_exception,
+    "__cxa_rethrow" => wasm__cxa_rethrow,
+    "__cxa_throw" => wasm__cxa_throw,
+    "__cxa_uncaught_exceptions" => wasm__cxa_uncaught_exceptions,
+    "__gxx_personality_v0" => wasm__gxx_personality_v0,
+    "__resumeException" => wasm__resumeException,
+    "gethostname" => gethostname,
+    "getTempRet0" => wasm_getTempRet0,
+    "llvm_eh_typeid_for" => wasm_llvm_eh_typeid_for,
+    "MPI_Abort" => MPI_Abort,
+    "MPI_Allgather" => MPI_Allgather,
+    "MPI_Alloc_mem" => MPI_Alloc_mem,
+    "MPI_Allreduce" => MPI_Allreduce,
+    "MPI_Allreduce_c" => MPI_Allreduce_c,
+    "MPI_Alltoall" => MPI_Alltoall,
+    "MPI_Alltoallv" => MPI_Alltoallv,
+    "MPI_Barrier" => MPI_Barrier,
+    "MPI_Bcast" => MPI_Bcast,
+    "MPI_Bcast_c" => MPI_Bcast_c,
+    "MPI_Comm_compare" => MPI_Comm_compare,
+    "MPI_Comm_create" => MPI_Comm_create,
+    "MPI_Comm_create_keyval" => MPI_Comm_create_keyval,
+    "MPI_Comm_delete_attr" => MPI_Comm_delete_attr,
+    "MPI_Comm_dup" => MPI_Comm_dup,
+    "MPI_Comm_free" => MPI_Comm_free,
+    "MPI_Comm_free_keyval" => MPI_Comm_free_keyval,
+    "MPI_Comm_get_attr" => MPI_Comm_get_attr,
+    "MPI_Comm_group" => MPI_Comm_group,
+    "MPI_Comm_split" => MPI_Comm_split,
+    "MPI_Comm_split_type" => MPI_Comm_split_type,
+    "MPI_Comm_rank" => MPI_Comm_rank,
+    "MPI_Comm_set_attr" => MPI_Comm_set_attr,
+    "MPI_Comm_size" => MPI_Comm_size,
+    "MPI_Finalize" => MPI_Finalize,
+    "MPI_Free_mem" => MPI_Free_mem,
+    "MPI_Gather" => MPI_Gather,
+    "MPI_Get_count" => MPI_Get_count,
+    "MPI_Get_count_c" => MPI_Get_count_c,
+    "MPI_Group_free" => MPI_Group_free,
+    "MPI_Group_range_incl" => MPI_Group_range_incl,
+    "MPI_Group_translate_ranks" => MPI_Group_translate_ranks,
+    "MPI_Info_create" => MPI_Info_create,
+    "MPI_Info_delete" => MPI_Info_delete,
+    "MPI_Info_dup" => MPI_Info_dup,
+    "MPI_Info_free" => MPI_Info_free,
+    "MPI_Info_get" => MPI_Info_get,
+    "MPI_Info_get_nkeys" => MPI_Info_get_nkeys,
+    "MPI_Info_get_nthkey" => MPI_Info_get_nthkey,
+    "MPI_Info_set" => MPI_Info_set,
+    "MPI_Init" => MPI_Init,
+    "MPI_Initialized" => MPI_Initialized,
+    "MPI_Irecv" => MPI_Irecv,
+    "MPI_Irecv_c" => MPI_Irecv_c,
+    "MPI_Isend" => MPI_Isend,
+    "MPI_Isend_c" => MPI_Isend_c,
+    "MPI_Op_create" => MPI_Op_create,
+    "MPI_Pack" => MPI_Pack,
+    "MPI_Pack_external" => MPI_Pack_external,
+    "MPI_Pack_external_size" => MPI_Pack_external_size,
+    "MPI_Pack_size" => MPI_Pack_size,
+    "MPI_Recv" => MPI_Recv,
+    "MPI_Recv_c" => MPI_Recv_c,
+    "MPI_Reduce" => MPI_Reduce,
+    "MPI_Reduce_c" => MPI_Reduce_c,
+    "MPI_Scatter" => MPI_Scatter,
+    "MPI_Send" => MPI_Send,
+    "MPI_Send_c" => MPI_Send_c,
+    "MPI_Sendrecv" => MPI_Sendrecv,
+    "MPI_Testany" => MPI_Testany,
+    "MPI_Type_create_keyval" => MPI_Type_create_keyval,
+    "MPI_Type_delete_attr" => MPI_Type_delete_attr,
+    "MPI_Type_free" => MPI_Type_free,
+    "MPI_Type_free_keyval" => MPI_Type_free_keyval,
+    "MPI_Type_get_attr" => MPI_Type_get_attr,
+    "MPI_Type_set_attr" => MPI_Type_set_attr,
+    "MPI_Type_size" => MPI_Type_size,
+    "MPI_Type_size_c" => MPI_Type_size_c,
+    "MPI_Unpack" => MPI_Unpack,
+    "MPI_Unpack_external" => MPI_Unpack_external,
+    "MPI_Wait" => MPI_Wait,
+    "MPI_Waitall" => MPI_Waitall,
+    "MPI_Waitany" => MPI_Waitany,
+    "MPI_Wtime" => MPI_Wtime,
+    "setTempRet0" => wasm_setTempRet0,
+}
+
+
+/// Builds the exports that satisfy the MPI imports of a module. All host functions are included by
+/// default, individual functions can be left out with `exclude` or the set can be restricted with
+/// `only`.
+pub struct ImportsBuilder<'a> {
+    store: &'a Store,
+    env: &'a Env,
+    only: Option<HashSet<String>>,
+    excluded: HashSet<String>,
+}
+impl<'a> ImportsBuilder<'a> {
+    pub fn new(store: &'a Store, env: &'a Env) -> Self {
+        ImportsBuilder{
+            store,
+            env,
+            only: None,
+            excluded: HashSet::new(),
+        }
+    }
+
+    /// Only include the given host functions
+    pub fn only<I, S>(mut self, names: I) -> Self where I: IntoIterator<Item = S>, S: Into<String> {
+        self.only = Some(names.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Leave out a host function, e.g. to provide a different implementation for it
+    pub fn exclude<S: Into<String>>(mut self, name: S) -> Self {
+        self.excluded.insert(name.into());
+        self
+    }
+
+    fn is_included(&self, name: &str) -> bool {
+        let selected = match &self.only {
+            Some(only) => only.contains(name),
+            None => true,
+        };
+        selected && !self.excluded.contains(name)
+    }
+
+    pub fn build(&self) -> Exports {
+        let mut exports = Exports::new();
+        for name in HOST_FUNCTIONS.iter().filter(|name| self.is_included(name)) {
+            let function = host_function(name, self.store, self.env).expect("host function missing from registry");
+            exports.insert(*name, function);
+        }
+        exports
+    }
+}
+
+
+/// Returns exports for all host functions, to be registered under `IMPORT_NAMESPACE`
+pub fn imports(store: &Store, env: &Env) -> Exports {
+    ImportsBuilder::new(store, env).build()
+}
+
+
+/// Returns the names of MPI functions the module imports that are not part of `exports`
+pub fn unsatisfied_imports(module: &Module, exports: &Exports) -> Vec<String> {
+    module.imports()
+        .filter(|import| import.module() == IMPORT_NAMESPACE)
+        .map(|import| import.name().to_string())
+        .filter(|name| name.starts_with("MPI_") || name.starts_with("PMPI_"))
+        .filter(|name| !exports.contains(name.as_str()))
+        .collect()
+}
+
+
+/// An import of a module that can't be satisfied by an import object
+pub enum ImportIssue {
+    /// Nothing is registered under the import's namespace and name
+    Unresolved(ImportType),
+    /// A function is registered, but its signature differs from the declared one
+    SignatureMismatch { import: ImportType, provided: FunctionType },
+    /// Something of a different kind is registered, e.g. a global for a function import
+    KindMismatch { import: ImportType, provided: ExternType },
+}
+impl ImportIssue {
+    pub fn import(&self) -> &ImportType {
+        match self {
+            ImportIssue::Unresolved(import) => import,
+            ImportIssue::SignatureMismatch { import, .. } => import,
+            ImportIssue::KindMismatch { import, .. } => import,
+        }
+    }
+}
+impl fmt::Display for ImportIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let import = self.import();
+        write!(f, "{}.{}: {}", import.module(), import.name(), extern_type_name(import.ty()))?;
+
+        match self {
+            ImportIssue::Unresolved(_) if import.module() == IMPORT_NAMESPACE && import.name().starts_with("MPI_") => {
+                write!(f, " is not implemented by this build of wasm-mpi-rs")
+            }
+            ImportIssue::Unresolved(_) => write!(f, " is not provided by the embedder"),
+            ImportIssue::SignatureMismatch { provided, .. } => write!(f, " does not match the host function {}", provided),
+            ImportIssue::KindMismatch { provided, .. } => write!(f, " is provided as {}", extern_type_name(provided)),
+        }
+    }
+}
+
+
+fn extern_type_name(ty: &ExternType) -> String {
+    match ty {
+        ExternType::Function(ty) => format!("function {}", ty),
+        ExternType::Global(ty) => format!("global {}", ty),
+        ExternType::Table(ty) => format!("table {}", ty),
+        ExternType::Memory(ty) => format!("memory {}", ty),
+    }
+}
+
+
+/// Checks every import of the module against `resolver` before instantiating it, which only
+/// reports the first link error. Function signatures must match exactly, for other kinds of imports
+/// only the kind is checked.
+pub fn check_imports(store: &Store, module: &Module, resolver: &dyn NamedResolver) -> Vec<ImportIssue> {
+    let mut issues = Vec::new();
+
+    for import in module.imports() {
+        let provided = match resolver.resolve_by_name(import.module(), import.name()) {
+            Some(export) => Extern::from_vm_export(store, export).ty(),
+            None => {
+                issues.push(ImportIssue::Unresolved(import));
+                continue;
+            }
+        };
+
+        match (import.ty(), &provided) {
+            (ExternType::Function(declared), ExternType::Function(provided)) => {
+                if declared != provided {
+                    issues.push(ImportIssue::SignatureMismatch { import: import.clone(), provided: provided.clone() });
+                }
+            }
+            (ExternType::Global(_), ExternType::Global(_)) |
+            (ExternType::Table(_), ExternType::Table(_)) |
+            (ExternType::Memory(_), ExternType::Memory(_)) => {}
+            _ => issues.push(ImportIssue::KindMismatch { import: import.clone(), provided }),
+        }
+    }
+
+    issues
+}
+
+
+/// Host functions for unresolved function imports that trap when they are called, so that modules
+/// which link functions they never call can still be instantiated
+pub struct ImportStubs {
+    pub imports: ImportObject,
+    pub stubbed: Vec<String>,
+    calls: Arc<Mutex<BTreeMap<String, u64>>>,
+}
+impl ImportStubs {
+    /// Stubs every unresolved function import among `issues`, all other issues are left alone
+    pub fn new(store: &Store, issues: &[ImportIssue]) -> Self {
+        let calls = Arc::new(Mutex::new(BTreeMap::new()));
+        let mut namespaces: HashMap<String, Exports> = HashMap::new();
+        let mut stubbed = Vec::new();
+
+        for issue in issues.iter().filter(|issue| Self::covers(issue)) {
+            let import = issue.import();
+            let ty = match import.ty() {
+                ExternType::Function(ty) => ty,
+                _ => continue,
+            };
+
+            // MPI functions are reported by name alone, they are what users look for
+            let name = if import.module() == IMPORT_NAMESPACE {
+                import.name().to_string()
+            } else {
+                format!("{}.{}", import.module(), import.name())
+            };
+
+            let stub_calls = calls.clone();
+            let stub_name = name.clone();
+            let stub = Function::new(store, ty, move |_| {
+                *stub_calls.lock().unwrap().entry(stub_name.clone()).or_insert(0) += 1;
+                Err(RuntimeError::new(format!("{} called but not implemented", stub_name)))
+            });
+
+            namespaces.entry(import.module().to_string()).or_insert_with(Exports::new).insert(import.name(), stub);
+            stubbed.push(name);
+        }
+
+        let mut imports = ImportObject::new();
+        for (namespace, exports) in namespaces {
+            imports.register(namespace, exports);
+        }
+
+        ImportStubs{ imports, stubbed, calls }
+    }
+
+    /// Returns how often each stub was called
+    pub fn calls(&self) -> BTreeMap<String, u64> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Returns whether an issue was resolved by a stub
+    pub fn covers(issue: &ImportIssue) -> bool {
+        match issue {
+            ImportIssue::Unresolved(import) => matches!(import.ty(), ExternType::Function(_)),
+            _ => false,
+        }
+    }
+}
@@ -1,17 +1,41 @@
-use std::collections::HashMap;
-use std::ptr::null_mut;
-use std::sync::{Arc, RwLock};
+use std::ffi::CString;
+use std::marker::PhantomData;
+use std::ptr::{null_mut, slice_from_raw_parts_mut};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 
 use anyhow::Result;
 use mpi_sys;
 use sys_info;
-use wasmer::{LazyInit, Memory, NativeFunc, WasmerEnv, WasmPtr, Array};
+use wasmer::{LazyInit, Memory, NativeFunc, Table, WasmerEnv, WasmPtr, Array};
 
+pub use attributes::*;
+pub use calls::*;
+pub use checks::*;
 pub use consts::*;
+pub use exceptions::*;
+pub use frames::*;
 pub use funcs::*;
-
+pub use imports::*;
+pub use profile::*;
+pub use replay::*;
+pub use signatures::*;
+pub use tools::*;
+pub use trace::*;
+
+pub mod attributes;
+pub mod calls;
+pub mod checks;
 pub mod consts;
+pub mod exceptions;
+pub mod frames;
 pub mod funcs;
+pub mod imports;
+pub mod profile;
+pub mod replay;
+pub mod signatures;
+pub mod tools;
+pub mod trace;
 
 #[allow(non_snake_case)]
 pub fn gethostname(env: &Env, name: WasmPtr<u8, Array>, len: i32) -> i32 {
@@ -32,19 +56,73 @@ pub fn gethostname(env: &Env, name: WasmPtr<u8, Array>, len: i32) -> i32 {
 }
 
 
-#[allow(non_snake_case)]
-pub fn wasm__cxa_allocate_exception(env: &Env, size: i32) -> i32 {
-    panic!("__cxa_allocate_exception called, aborting");
+/// Reads a NUL-terminated string from guest memory. Returns `None` if the string is not terminated
+/// before the end of the memory.
+pub fn read_c_string(memory: &Memory, ptr: WasmPtr<u8, Array>) -> Option<CString> {
+    let view = memory.view::<u8>();
+    let start = ptr.offset() as usize;
+    if start >= view.len() {
+        return None;
+    }
+
+    let mut bytes: Vec<u8> = Vec::new();
+    for cell in view[start..].iter() {
+        let byte = cell.get();
+        if byte == 0 {
+            return Some(CString::new(bytes).expect("missing NUL byte in C string"));
+        }
+        bytes.push(byte);
+    }
+    None
 }
 
 
-#[allow(non_snake_case)]
-pub fn wasm__cxa_throw(env: &Env, _ptr: i32, _ty: i32, _destructor: i32) {
-    panic!("__cxa_throw called, aborting");
+/// Returns the host address of `len` bytes of guest memory at `ptr`, `None` if they are out of
+/// bounds. Buffers that are handed to MPI are resolved with this, as `WasmPtr::deref` with a length
+/// builds a cell per byte whose slice is not the guest memory itself.
+pub fn guest_bytes(memory: &Memory, ptr: WasmPtr<u8, Array>, len: u64) -> Option<*mut u8> {
+    if ptr.offset() as u64 + len > memory.data_size() {
+        return None;
+    }
+    Some(unsafe { memory.data_ptr().add(ptr.offset() as usize) })
+}
+
+
+/// Initializes the host MPI with `MPI_THREAD_MULTIPLE`. Embedders abort the job from watchdog threads
+/// while the module may be blocked in an MPI call, which is only allowed at that level.
+pub fn init_host_mpi() -> i32 {
+    let mut provided = 0;
+    unsafe {
+        // OpenMPI does not use argc and argv so just pass nullptr to them
+        mpi_sys::MPI_Init_thread(null_mut(), null_mut(), mpi_sys::MPI_THREAD_MULTIPLE as i32, &mut provided)
+    }
+}
+
+
+
+/// Host MPI handles are either pointers (OpenMPI) or integers (MVAPICH). Both fit into a `usize`,
+/// which lets the translation tables keep them in atomic slots that can be read without locking.
+pub trait HostHandle: Copy + Default {
+    fn to_bits(self) -> usize;
+    fn from_bits(bits: usize) -> Self;
+}
+
+macro_rules! impl_host_handle {
+    ($handle:ident, $host_type:ty) => {
+        impl HostHandle for $handle {
+            fn to_bits(self) -> usize {
+                self.0 as usize
+            }
+
+            fn from_bits(bits: usize) -> Self {
+                Self(bits as $host_type)
+            }
+        }
+    };
 }
 
-#[derive(Clone)]
-pub struct MpiComm(mpi_sys::MPI_Comm);
+#[derive(Clone, Copy)]
+pub struct MpiComm(pub mpi_sys::MPI_Comm);
 impl Default for MpiComm {
     #[cfg(feature = "mvapich")]
     fn default() -> Self {
@@ -57,9 +135,10 @@ impl Default for MpiComm {
 }
 unsafe impl Send for MpiComm {}
 unsafe impl Sync for MpiComm {}
+impl_host_handle!(MpiComm, mpi_sys::MPI_Comm);
 
-#[derive(Clone)]
-pub struct MpiDatatype(mpi_sys::MPI_Datatype);
+#[derive(Clone, Copy)]
+pub struct MpiDatatype(pub mpi_sys::MPI_Datatype);
 impl Default for MpiDatatype {
     #[cfg(feature = "mvapich")]
     fn default() -> Self {
@@ -72,10 +151,11 @@ impl Default for MpiDatatype {
 }
 unsafe impl Send for MpiDatatype {}
 unsafe impl Sync for MpiDatatype {}
+impl_host_handle!(MpiDatatype, mpi_sys::MPI_Datatype);
 
 
-#[derive(Clone)]
-pub struct MpiGroup(mpi_sys::MPI_Group);
+#[derive(Clone, Copy)]
+pub struct MpiGroup(pub mpi_sys::MPI_Group);
 impl Default for MpiGroup {
     #[cfg(feature = "mvapich")]
     fn default() -> Self {
@@ -88,10 +168,28 @@ impl Default for MpiGroup {
 }
 unsafe impl Send for MpiGroup {}
 unsafe impl Sync for MpiGroup {}
+impl_host_handle!(MpiGroup, mpi_sys::MPI_Group);
 
 
-#[derive(Clone)]
-pub struct MpiOp(mpi_sys::MPI_Op);
+#[derive(Clone, Copy)]
+pub struct MpiInfo(pub mpi_sys::MPI_Info);
+impl Default for MpiInfo {
+    #[cfg(feature = "mvapich")]
+    fn default() -> Self {
+        Self(0)
+    }
+    #[cfg(feature = "openmpi")]
+    fn default() -> Self {
+        Self(null_mut())
+    }
+}
+unsafe impl Send for MpiInfo {}
+unsafe impl Sync for MpiInfo {}
+impl_host_handle!(MpiInfo, mpi_sys::MPI_Info);
+
+
+#[derive(Clone, Copy)]
+pub struct MpiOp(pub mpi_sys::MPI_Op);
 impl Default for MpiOp {
     #[cfg(feature = "mvapich")]
     fn default() -> Self {
@@ -104,10 +202,11 @@ impl Default for MpiOp {
 }
 unsafe impl Send for MpiOp {}
 unsafe impl Sync for MpiOp {}
+impl_host_handle!(MpiOp, mpi_sys::MPI_Op);
 
 
-#[derive(Clone)]
-pub struct MpiRequest(mpi_sys::MPI_Request);
+#[derive(Clone, Copy)]
+pub struct MpiRequest(pub mpi_sys::MPI_Request);
 impl Default for MpiRequest {
     #[cfg(feature = "mvapich")]
     fn default() -> Self {
@@ -120,117 +219,234 @@ impl Default for MpiRequest {
 }
 unsafe impl Send for MpiRequest {}
 unsafe impl Sync for MpiRequest {}
+impl_host_handle!(MpiRequest, mpi_sys::MPI_Request);
 
 
-#[derive(Clone)]
-pub struct MpiTranslation<HostType> {
+const TRANSLATION_CHUNK_SIZE: usize = 1024;
+const TRANSLATION_MAX_CHUNKS: usize = 4096;
+
+#[derive(Default)]
+struct TranslationSlot {
+    occupied: AtomicBool,
+    bits: AtomicUsize,
+}
+
+struct TranslationAllocator {
     next_id: i32,
-    instances: HashMap<i32, HostType>,
+    free_ids: Vec<i32>,
+}
+
+/// Maps the integer handles seen by the guest to host MPI handles.
+///
+/// Handles are stored in a dense table of fixed-size chunks that are allocated on demand and never
+/// moved, so `get` is two atomic loads and an index computation. Only allocating and freeing
+/// handles takes a (normally uncontended) lock.
+pub struct MpiTranslation<HostType> {
+    base: i32,
+    first_id: i32,
+    chunks: Box<[AtomicPtr<TranslationSlot>]>,
+    allocator: Mutex<TranslationAllocator>,
+    _host_type: PhantomData<HostType>,
 }
-impl<HostType> MpiTranslation<HostType> where HostType : Default {
+impl<HostType> MpiTranslation<HostType> where HostType : HostHandle {
     pub fn new() -> MpiTranslation<HostType> {
-        let instances: HashMap<i32, HostType> = HashMap::new();
+        Self::with_base(0, 0)
+    }
+
+    /// Creates an empty table whose smallest valid handle is `base` (e.g. -1 for tables containing
+    /// `MPI_DATATYPE_NULL`) and whose first allocated handle is `next_id`.
+    fn with_base(base: i32, next_id: i32) -> MpiTranslation<HostType> {
+        let chunks: Vec<AtomicPtr<TranslationSlot>> = (0..TRANSLATION_MAX_CHUNKS)
+            .map(|_| AtomicPtr::new(null_mut()))
+            .collect();
 
         MpiTranslation{
-            next_id: 0,
-            instances: instances,
+            base,
+            first_id: next_id,
+            chunks: chunks.into_boxed_slice(),
+            allocator: Mutex::new(TranslationAllocator{
+                next_id,
+                free_ids: Vec::new(),
+            }),
+            _host_type: PhantomData,
         }
     }
 
-    pub fn get(&self, instance_id: i32) -> Option<&HostType> {
-        self.instances.get(&instance_id)
+    fn slot(&self, instance_id: i32) -> Option<&TranslationSlot> {
+        let index = instance_id.checked_sub(self.base)?;
+        if index < 0 {
+            return None;
+        }
+        let index = index as usize;
+
+        let chunk = self.chunks.get(index / TRANSLATION_CHUNK_SIZE)?.load(Ordering::Acquire);
+        if chunk.is_null() {
+            return None;
+        }
+        // Chunks are only freed when the table is dropped
+        unsafe { Some(&*chunk.add(index % TRANSLATION_CHUNK_SIZE)) }
     }
 
-    pub fn get_mut(&mut self, instance_id: i32) -> Option<&mut HostType> {
-        self.instances.get_mut(&instance_id)
+    /// Returns the slot for `instance_id`, allocating its chunk if necessary. The allocator lock must
+    /// be held by the caller.
+    fn slot_or_alloc(&self, instance_id: i32) -> &TranslationSlot {
+        let index = (instance_id - self.base) as usize;
+        let chunk_ref = self.chunks.get(index / TRANSLATION_CHUNK_SIZE).expect("translation table is full");
+
+        if chunk_ref.load(Ordering::Acquire).is_null() {
+            let chunk: Box<[TranslationSlot]> = (0..TRANSLATION_CHUNK_SIZE)
+                .map(|_| TranslationSlot::default())
+                .collect();
+            chunk_ref.store(Box::into_raw(chunk) as *mut TranslationSlot, Ordering::Release);
+        }
+
+        self.slot(instance_id).expect("missing chunk directly after allocation")
     }
 
-    pub fn alloc_instance(&mut self) -> (i32, &mut HostType) {
-        let instance = HostType::default();
+    pub fn get(&self, instance_id: i32) -> Option<HostType> {
+        let slot = self.slot(instance_id)?;
+        if slot.occupied.load(Ordering::Acquire) {
+            Some(HostType::from_bits(slot.bits.load(Ordering::Relaxed)))
+        } else {
+            None
+        }
+    }
 
-        let instance_id = self.next_id;
-        self.instances.insert(instance_id, instance);
-        self.next_id += 1;
+    /// Makes `instance` available under the fixed handle `instance_id`. Used for predefined handles.
+    pub fn insert(&self, instance_id: i32, instance: HostType) -> () {
+        let _allocator = self.allocator.lock().unwrap();
+        let slot = self.slot_or_alloc(instance_id);
+        slot.bits.store(instance.to_bits(), Ordering::Relaxed);
+        slot.occupied.store(true, Ordering::Release);
+    }
 
-        let instance_ref = self.instances.get_mut(&instance_id).expect("missing key directly after insert");
-        (instance_id, instance_ref)
+    pub fn alloc_instance(&self, instance: HostType) -> i32 {
+        let mut allocator = self.allocator.lock().unwrap();
+        let instance_id = match allocator.free_ids.pop() {
+            Some(instance_id) => instance_id,
+            None => {
+                let instance_id = allocator.next_id;
+                allocator.next_id += 1;
+                instance_id
+            }
+        };
+
+        let slot = self.slot_or_alloc(instance_id);
+        slot.bits.store(instance.to_bits(), Ordering::Relaxed);
+        slot.occupied.store(true, Ordering::Release);
+        instance_id
     }
 
-    pub fn free_instance(&mut self, instance_id: i32) -> () {
-        self.instances.remove(&instance_id);
+    /// Whether `instance_id` is below the first allocated handle, i.e. a predefined handle like
+    /// `MPI_COMM_WORLD` or `MPI_INT`
+    pub fn is_predefined(&self, instance_id: i32) -> bool {
+        instance_id < self.first_id
+    }
+
+    /// Returns the allocated handles that were not freed yet, in ascending order
+    pub fn allocated(&self) -> Vec<i32> {
+        let next_id = self.allocator.lock().unwrap().next_id;
+        (self.first_id..next_id).filter(|&instance_id| self.get(instance_id).is_some()).collect()
+    }
+
+    pub fn free_instance(&self, instance_id: i32) -> Option<HostType> {
+        let mut allocator = self.allocator.lock().unwrap();
+        let slot = self.slot(instance_id)?;
+
+        if slot.occupied.swap(false, Ordering::AcqRel) {
+            allocator.free_ids.push(instance_id);
+            Some(HostType::from_bits(slot.bits.load(Ordering::Relaxed)))
+        } else {
+            None
+        }
+    }
+}
+impl<HostType> Drop for MpiTranslation<HostType> {
+    fn drop(&mut self) {
+        for chunk_ref in self.chunks.iter() {
+            let chunk = chunk_ref.load(Ordering::Acquire);
+            if !chunk.is_null() {
+                unsafe {
+                    drop(Box::from_raw(slice_from_raw_parts_mut(chunk, TRANSLATION_CHUNK_SIZE)));
+                }
+            }
+        }
     }
 }
 impl Default for MpiTranslation<MpiComm> {
     fn default() -> Self {
-        let mut instances: HashMap<i32, MpiComm> = HashMap::new();
+        let translation = Self::with_base(0, 3);
 
         unsafe {
-            instances.insert(self::MPI_COMM_WORLD, MpiComm(mpi_sys::RSMPI_COMM_WORLD));
-            instances.insert(self::MPI_COMM_SELF, MpiComm(mpi_sys::RSMPI_COMM_SELF));
-            instances.insert(self::MPI_COMM_NULL, MpiComm(mpi_sys::RSMPI_COMM_NULL));
+            translation.insert(self::MPI_COMM_WORLD, MpiComm(mpi_sys::RSMPI_COMM_WORLD));
+            translation.insert(self::MPI_COMM_SELF, MpiComm(mpi_sys::RSMPI_COMM_SELF));
+            translation.insert(self::MPI_COMM_NULL, MpiComm(mpi_sys::RSMPI_COMM_NULL));
         }
 
-        Self{
-            next_id: instances.len() as i32,
-            instances: instances,
-        }
+        translation
     }
 }
 impl Default for MpiTranslation<MpiDatatype> {
     fn default() -> Self {
-        let mut instances: HashMap<i32, MpiDatatype> = HashMap::new();
+        let translation = Self::with_base(self::MPI_DATATYPE_NULL, 19);
 
         unsafe {
-            instances.insert(self::MPI_INT8_T, MpiDatatype(mpi_sys::RSMPI_INT8_T));
-            instances.insert(self::MPI_INT16_T, MpiDatatype(mpi_sys::RSMPI_INT16_T));
-            instances.insert(self::MPI_INT32_T, MpiDatatype(mpi_sys::RSMPI_INT32_T));
-            instances.insert(self::MPI_INT, MpiDatatype(mpi_sys::RSMPI_INT32_T));
-            instances.insert(self::MPI_INT64_T, MpiDatatype(mpi_sys::RSMPI_INT64_T));
-            instances.insert(self::MPI_UINT8_T, MpiDatatype(mpi_sys::RSMPI_UINT8_T));
-            instances.insert(self::MPI_UINT16_T, MpiDatatype(mpi_sys::RSMPI_UINT16_T));
-            instances.insert(self::MPI_UINT32_T, MpiDatatype(mpi_sys::RSMPI_UINT32_T));
-            instances.insert(self::MPI_UINT_T, MpiDatatype(mpi_sys::RSMPI_UINT32_T));
-            instances.insert(self::MPI_UINT64_T, MpiDatatype(mpi_sys::RSMPI_UINT64_T));
-            instances.insert(self::MPI_LONG, MpiDatatype(mpi_sys::RSMPI_INT64_T));
-            instances.insert(self::MPI_LONG_LONG, MpiDatatype(mpi_sys::RSMPI_INT64_T));
-            instances.insert(self::MPI_LONG_LONG_INT, MpiDatatype(mpi_sys::RSMPI_INT64_T));
-            instances.insert(self::MPI_FLOAT, MpiDatatype(mpi_sys::RSMPI_FLOAT));
-            instances.insert(self::MPI_DOUBLE, MpiDatatype(mpi_sys::RSMPI_DOUBLE));
-            instances.insert(self::MPI_DOUBLE_INT, MpiDatatype(mpi_sys::RSMPI_DOUBLE));
-            instances.insert(self::MPI_CHAR, MpiDatatype(mpi_sys::RSMPI_UINT8_T));
-            instances.insert(self::MPI_C_BOOL, MpiDatatype(mpi_sys::RSMPI_UINT8_T));
-            instances.insert(self::MPI_BYTE, MpiDatatype(mpi_sys::RSMPI_UINT8_T));
-            instances.insert(self::MPI_DATATYPE_NULL, MpiDatatype(mpi_sys::RSMPI_DATATYPE_NULL));
+            translation.insert(self::MPI_INT8_T, MpiDatatype(mpi_sys::RSMPI_INT8_T));
+            translation.insert(self::MPI_INT16_T, MpiDatatype(mpi_sys::RSMPI_INT16_T));
+            translation.insert(self::MPI_INT32_T, MpiDatatype(mpi_sys::RSMPI_INT32_T));
+            translation.insert(self::MPI_INT, MpiDatatype(mpi_sys::RSMPI_INT32_T));
+            translation.insert(self::MPI_INT64_T, MpiDatatype(mpi_sys::RSMPI_INT64_T));
+            translation.insert(self::MPI_UINT8_T, MpiDatatype(mpi_sys::RSMPI_UINT8_T));
+            translation.insert(self::MPI_UINT16_T, MpiDatatype(mpi_sys::RSMPI_UINT16_T));
+            translation.insert(self::MPI_UINT32_T, MpiDatatype(mpi_sys::RSMPI_UINT32_T));
+            translation.insert(self::MPI_UINT_T, MpiDatatype(mpi_sys::RSMPI_UINT32_T));
+            translation.insert(self::MPI_UINT64_T, MpiDatatype(mpi_sys::RSMPI_UINT64_T));
+            translation.insert(self::MPI_LONG, MpiDatatype(mpi_sys::RSMPI_INT64_T));
+            translation.insert(self::MPI_LONG_LONG, MpiDatatype(mpi_sys::RSMPI_INT64_T));
+            translation.insert(self::MPI_LONG_LONG_INT, MpiDatatype(mpi_sys::RSMPI_INT64_T));
+            translation.insert(self::MPI_FLOAT, MpiDatatype(mpi_sys::RSMPI_FLOAT));
+            translation.insert(self::MPI_DOUBLE, MpiDatatype(mpi_sys::RSMPI_DOUBLE));
+            translation.insert(self::MPI_DOUBLE_INT, MpiDatatype(mpi_sys::RSMPI_DOUBLE));
+            translation.insert(self::MPI_CHAR, MpiDatatype(mpi_sys::RSMPI_UINT8_T));
+            translation.insert(self::MPI_C_BOOL, MpiDatatype(mpi_sys::RSMPI_UINT8_T));
+            translation.insert(self::MPI_BYTE, MpiDatatype(mpi_sys::RSMPI_UINT8_T));
+            translation.insert(self::MPI_DATATYPE_NULL, MpiDatatype(mpi_sys::RSMPI_DATATYPE_NULL));
         }
 
-        Self {
-            next_id: (instances.len() - 1) as i32,
-            instances,
+        translation
+    }
+}
+impl Default for MpiTranslation<MpiInfo> {
+    fn default() -> Self {
+        let translation = Self::with_base(0, 1);
+
+        unsafe {
+            translation.insert(self::MPI_INFO_NULL, MpiInfo(mpi_sys::RSMPI_INFO_NULL));
         }
+
+        translation
     }
 }
 impl Default for MpiTranslation<MpiOp> {
     fn default() -> Self {
-        let mut instances: HashMap<i32, MpiOp> = HashMap::new();
+        // MPI_MAXLOC and MPI_MINLOC are reserved even though they are not supported yet
+        let translation = Self::with_base(self::MPI_OP_NULL, 10);
 
         unsafe {
-            instances.insert(self::MPI_MAX, MpiOp(mpi_sys::RSMPI_MAX));
-            instances.insert(self::MPI_MIN, MpiOp(mpi_sys::RSMPI_MIN));
-            instances.insert(self::MPI_SUM, MpiOp(mpi_sys::RSMPI_SUM));
-            instances.insert(self::MPI_PROD, MpiOp(mpi_sys::RSMPI_PROD));
-            instances.insert(self::MPI_LAND, MpiOp(mpi_sys::RSMPI_LAND));
-            instances.insert(self::MPI_LOR, MpiOp(mpi_sys::RSMPI_LOR));
-            instances.insert(self::MPI_BAND, MpiOp(mpi_sys::RSMPI_BAND));
-            instances.insert(self::MPI_BOR, MpiOp(mpi_sys::RSMPI_BOR));
-            // instances.insert(self::MPI_MAXLOC, MpiOp(mpi_sys::MPI_MAXLOC));
-            // instances.insert(self::MPI_MINLOC, MpiOp(mpi_sys::MPI_MINLOC));
-            // instances.insert(self::MPI_OP_NULL, MpiOp(mpi_sys::RSMPI_OP_NULL));
+            translation.insert(self::MPI_MAX, MpiOp(mpi_sys::RSMPI_MAX));
+            translation.insert(self::MPI_MIN, MpiOp(mpi_sys::RSMPI_MIN));
+            translation.insert(self::MPI_SUM, MpiOp(mpi_sys::RSMPI_SUM));
+            translation.insert(self::MPI_PROD, MpiOp(mpi_sys::RSMPI_PROD));
+            translation.insert(self::MPI_LAND, MpiOp(mpi_sys::RSMPI_LAND));
+            translation.insert(self::MPI_LOR, MpiOp(mpi_sys::RSMPI_LOR));
+            translation.insert(self::MPI_BAND, MpiOp(mpi_sys::RSMPI_BAND));
+            translation.insert(self::MPI_BOR, MpiOp(mpi_sys::RSMPI_BOR));
+            // translation.insert(self::MPI_MAXLOC, MpiOp(mpi_sys::MPI_MAXLOC));
+            // translation.insert(self::MPI_MINLOC, MpiOp(mpi_sys::MPI_MINLOC));
+            // translation.insert(self::MPI_OP_NULL, MpiOp(mpi_sys::RSMPI_OP_NULL));
         }
 
-        Self {
-            next_id: (instances.len() + 2) as i32,
-            instances,
-        }
+        translation
     }
 }
 
@@ -243,11 +459,28 @@ pub struct Env {
     pub malloc: LazyInit<NativeFunc<i32, WasmPtr<u8>>>,
     #[wasmer(export(optional = true))]
     pub free: LazyInit<NativeFunc<WasmPtr<u8>, ()>>,
-    pub mpi_comms: Arc<RwLock<MpiTranslation<MpiComm>>>,
-    pub mpi_datatypes: Arc<RwLock<MpiTranslation<MpiDatatype>>>,
-    pub mpi_groups: Arc<RwLock<MpiTranslation<MpiGroup>>>,
-    pub mpi_ops: Arc<RwLock<MpiTranslation<MpiOp>>>,
-    pub mpi_requests: Arc<RwLock<MpiTranslation<MpiRequest>>>,
+    #[wasmer(export(name = "__indirect_function_table", optional = true))]
+    pub function_table: LazyInit<Table>,
+    #[wasmer(export(name = "setThrew", optional = true))]
+    pub set_threw: LazyInit<NativeFunc<(i32, i32), ()>>,
+    #[wasmer(export(name = "__wasi_mpi_can_catch", optional = true))]
+    pub can_catch: LazyInit<NativeFunc<(i32, i32, i32), i32>>,
+    #[wasmer(export(name = "stackSave", optional = true))]
+    pub stack_save: LazyInit<NativeFunc<(), i32>>,
+    #[wasmer(export(name = "stackRestore", optional = true))]
+    pub stack_restore: LazyInit<NativeFunc<i32, ()>>,
+    pub mpi_comms: Arc<MpiTranslation<MpiComm>>,
+    pub mpi_datatypes: Arc<MpiTranslation<MpiDatatype>>,
+    pub mpi_groups: Arc<MpiTranslation<MpiGroup>>,
+    pub mpi_infos: Arc<MpiTranslation<MpiInfo>>,
+    pub mpi_ops: Arc<MpiTranslation<MpiOp>>,
+    pub mpi_requests: Arc<MpiTranslation<MpiRequest>>,
+    pub mpi_attributes: Arc<RwLock<MpiAttributes>>,
+    pub calls: Arc<CallTracker>,
+    pub cxx_exceptions: Arc<Mutex<CxxExceptions>>,
+    /// Tools that intercept the MPI calls of the module, calls go straight to MPI without any
+    /// overhead when no tool is registered
+    pub tools: ToolChain,
 }
 impl Env {
     pub fn new() -> Env {
@@ -255,11 +488,47 @@ impl Env {
             memory: LazyInit::new(),
             malloc: LazyInit::new(),
             free: LazyInit::new(),
-            mpi_comms: Arc::new(RwLock::new(MpiTranslation::default())),
-            mpi_datatypes: Arc::new(RwLock::new(MpiTranslation::default())),
-            mpi_groups: Arc::new(RwLock::new(MpiTranslation::new())),
-            mpi_ops: Arc::new(RwLock::new(MpiTranslation::default())),
-            mpi_requests: Arc::new(RwLock::new(MpiTranslation::new())),
+            function_table: LazyInit::new(),
+            set_threw: LazyInit::new(),
+            can_catch: LazyInit::new(),
+            stack_save: LazyInit::new(),
+            stack_restore: LazyInit::new(),
+            mpi_comms: Arc::new(MpiTranslation::default()),
+            mpi_datatypes: Arc::new(MpiTranslation::default()),
+            mpi_groups: Arc::new(MpiTranslation::new()),
+            mpi_infos: Arc::new(MpiTranslation::default()),
+            mpi_ops: Arc::new(MpiTranslation::default()),
+            mpi_requests: Arc::new(MpiTranslation::new()),
+            mpi_attributes: Arc::new(RwLock::new(MpiAttributes::new())),
+            calls: Arc::new(CallTracker::new()),
+            cxx_exceptions: Arc::new(Mutex::new(CxxExceptions::new())),
+            tools: ToolChain::default(),
+        }
+    }
+}
+
+
+#[cfg(all(test, feature = "universal"))]
+mod tests {
+    use wasmer::{Memory, MemoryType, Store, Universal, WasmPtr};
+
+    use super::guest_bytes;
+
+    #[test]
+    fn guest_bytes_points_into_guest_memory() {
+        let store = Store::new(&Universal::headless().engine());
+        let memory = Memory::new(&store, MemoryType::new(1, None, false)).unwrap();
+
+        let ptr = guest_bytes(&memory, WasmPtr::new(16), 4).unwrap();
+        unsafe {
+            ptr.copy_from([1u8, 2, 3, 4].as_ptr(), 4);
         }
+        let written: Vec<u8> = memory.view::<u8>()[16..20].iter().map(|cell| cell.get()).collect();
+        assert_eq!(written, [1, 2, 3, 4]);
+
+        let page = memory.data_size() as u32;
+        assert!(guest_bytes(&memory, WasmPtr::new(page - 4), 4).is_some());
+        assert!(guest_bytes(&memory, WasmPtr::new(page - 4), 5).is_none());
+        assert!(guest_bytes(&memory, WasmPtr::new(page), 0).is_some());
     }
 }
@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::PathBuf;
+use std::ptr::null_mut;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use libc::c_void;
+use serde::{Deserialize, Serialize};
+
+use crate::frames::call_site;
+use crate::{CallAction, MpiCall, ReturnValue, Tool, MPI_ANY_SOURCE};
+
+/// Rows of the per-peer table in the text report
+const TOP_PEERS: usize = 20;
+
+
+/// Where the report of a profiled run is written by rank 0
+#[derive(Clone, Debug)]
+pub enum ProfileOutput {
+    /// A table on the embedder's stderr, like the report of mpiP
+    Text,
+    Json(PathBuf),
+}
+
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+pub struct CallStats {
+    pub calls: u64,
+    pub bytes: u64,
+    /// Seconds spent in the calls
+    pub time: f64,
+}
+impl CallStats {
+    fn add(&mut self, other: &CallStats) {
+        self.calls += other.calls;
+        self.bytes += other.bytes;
+        self.time += other.time;
+    }
+}
+
+
+#[derive(Default)]
+struct ProfileState {
+    /// By host function and the guest function that called it
+    sites: HashMap<(&'static str, String), CallStats>,
+    /// By guest communicator handle
+    comms: HashMap<i32, CallStats>,
+    /// By guest communicator handle and rank of the peer in it
+    peers: HashMap<(i32, i32), CallStats>,
+    mpi_time: f64,
+    reported: bool,
+    current: Option<ProfiledCall>,
+}
+
+
+/// A call that is in progress
+struct ProfiledCall {
+    site: String,
+    start: Instant,
+}
+
+
+/// Profile of one rank, sent to rank 0 at `MPI_Finalize`
+#[derive(Deserialize, Serialize)]
+struct RankProfile {
+    rank: i32,
+    app_time: f64,
+    mpi_time: f64,
+    sites: Vec<(String, String, CallStats)>,
+    comms: Vec<(i32, CallStats)>,
+    peers: Vec<(i32, i32, CallStats)>,
+}
+
+
+#[derive(Clone, Copy, Serialize)]
+pub struct MinAvgMax {
+    pub min: f64,
+    pub avg: f64,
+    pub max: f64,
+}
+impl MinAvgMax {
+    fn of(values: &[f64]) -> MinAvgMax {
+        MinAvgMax{
+            min: values.iter().cloned().fold(f64::INFINITY, f64::min),
+            avg: values.iter().sum::<f64>() / values.len() as f64,
+            max: values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+
+#[derive(Serialize)]
+pub struct RankSummary {
+    pub rank: i32,
+    pub app_time: f64,
+    pub mpi_time: f64,
+    pub mpi_percent: f64,
+}
+
+
+/// Statistics of a call site over the ranks that called it
+#[derive(Serialize)]
+pub struct SiteSummary {
+    pub function: String,
+    pub site: String,
+    pub ranks: usize,
+    pub calls: MinAvgMax,
+    pub time: MinAvgMax,
+    pub bytes: MinAvgMax,
+}
+
+
+#[derive(Serialize)]
+pub struct CommSummary {
+    pub comm: i32,
+    pub stats: CallStats,
+}
+
+
+#[derive(Serialize)]
+pub struct PeerSummary {
+    pub rank: i32,
+    pub comm: i32,
+    pub peer: i32,
+    pub stats: CallStats,
+}
+
+
+/// The profile of all ranks of a job
+#[derive(Serialize)]
+pub struct ProfileReport {
+    pub ranks: Vec<RankSummary>,
+    pub sites: Vec<SiteSummary>,
+    pub comms: Vec<CommSummary>,
+    pub peers: Vec<PeerSummary>,
+}
+impl ProfileReport {
+    fn new(mut profiles: Vec<RankProfile>) -> ProfileReport {
+        profiles.sort_by_key(|profile| profile.rank);
+
+        let ranks = profiles.iter().map(|profile| RankSummary{
+            rank: profile.rank,
+            app_time: profile.app_time,
+            mpi_time: profile.mpi_time,
+            mpi_percent: if profile.app_time > 0.0 { 100.0 * profile.mpi_time / profile.app_time } else { 0.0 },
+        }).collect();
+
+        let mut site_stats: HashMap<(String, String), Vec<CallStats>> = HashMap::new();
+        let mut comms: HashMap<i32, CallStats> = HashMap::new();
+        let mut peers = Vec::new();
+        for profile in profiles {
+            for (function, site, stats) in profile.sites {
+                site_stats.entry((function, site)).or_default().push(stats);
+            }
+            for (comm, stats) in profile.comms {
+                comms.entry(comm).or_default().add(&stats);
+            }
+            for (comm, peer, stats) in profile.peers {
+                peers.push(PeerSummary{ rank: profile.rank, comm, peer, stats });
+            }
+        }
+
+        let mut sites: Vec<SiteSummary> = site_stats.into_iter().map(|((function, site), stats)| SiteSummary{
+            function,
+            site,
+            ranks: stats.len(),
+            calls: MinAvgMax::of(&stats.iter().map(|stats| stats.calls as f64).collect::<Vec<_>>()),
+            time: MinAvgMax::of(&stats.iter().map(|stats| stats.time).collect::<Vec<_>>()),
+            bytes: MinAvgMax::of(&stats.iter().map(|stats| stats.bytes as f64).collect::<Vec<_>>()),
+        }).collect();
+        sites.sort_by(|a, b| b.time.avg.partial_cmp(&a.time.avg).unwrap());
+
+        let mut comms: Vec<CommSummary> = comms.into_iter().map(|(comm, stats)| CommSummary{ comm, stats }).collect();
+        comms.sort_by_key(|summary| summary.comm);
+        peers.sort_by(|a, b| b.stats.bytes.cmp(&a.stats.bytes));
+
+        ProfileReport{ ranks, sites, comms, peers }
+    }
+
+    /// Formats the report as tables in the style of mpiP
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "@ WASI MPI profile of {} ranks", self.ranks.len());
+
+        let _ = writeln!(text, "@--- MPI time (seconds) ---");
+        let _ = writeln!(text, "{:>6} {:>12} {:>12} {:>8}", "Rank", "AppTime", "MPITime", "MPI%");
+        for rank in &self.ranks {
+            let _ = writeln!(text, "{:>6} {:>12.4} {:>12.4} {:>8.2}", rank.rank, rank.app_time, rank.mpi_time, rank.mpi_percent);
+        }
+
+        let _ = writeln!(text, "@--- Call sites, min/avg/max over the ranks that called them ---");
+        let _ = writeln!(text, "{:<20} {:<32} {:>5} {:>26} {:>32} {:>38}", "Call", "Site", "Ranks", "Calls", "Time (ms)", "Bytes");
+        for site in &self.sites {
+            let _ = writeln!(
+                text, "{:<20} {:<32} {:>5} {:>8.0} {:>8.1} {:>8.0} {:>10.3} {:>10.3} {:>10.3} {:>12.0} {:>12.0} {:>12.0}",
+                site.function, site.site, site.ranks,
+                site.calls.min, site.calls.avg, site.calls.max,
+                site.time.min * 1e3, site.time.avg * 1e3, site.time.max * 1e3,
+                site.bytes.min, site.bytes.avg, site.bytes.max,
+            );
+        }
+
+        let _ = writeln!(text, "@--- Communicators, summed over all ranks ---");
+        let _ = writeln!(text, "{:>6} {:>12} {:>16} {:>12}", "Comm", "Calls", "Bytes", "Time (s)");
+        for comm in &self.comms {
+            let _ = writeln!(text, "{:>6} {:>12} {:>16} {:>12.4}", comm.comm, comm.stats.calls, comm.stats.bytes, comm.stats.time);
+        }
+
+        let _ = writeln!(text, "@--- Point-to-point peers, top {} by bytes ---", TOP_PEERS);
+        let _ = writeln!(text, "{:>6} {:>6} {:>6} {:>12} {:>16} {:>12}", "Rank", "Comm", "Peer", "Calls", "Bytes", "Time (s)");
+        for peer in self.peers.iter().take(TOP_PEERS) {
+            let _ = writeln!(text, "{:>6} {:>6} {:>6} {:>12} {:>16} {:>12.4}", peer.rank, peer.comm, peer.peer, peer.stats.calls, peer.stats.bytes, peer.stats.time);
+        }
+        text
+    }
+}
+
+
+/// Records the MPI calls of a rank by call site, communicator and peer. The profiles of all ranks
+/// are gathered on rank 0 when the module calls `MPI_Finalize`.
+pub struct Profiler {
+    output: ProfileOutput,
+    start: Instant,
+    state: Mutex<ProfileState>,
+}
+impl Profiler {
+    pub fn new(output: ProfileOutput) -> Profiler {
+        Profiler{
+            output,
+            start: Instant::now(),
+            state: Mutex::new(ProfileState::default()),
+        }
+    }
+
+    fn profile(&self, rank: i32) -> RankProfile {
+        let state = self.state.lock().unwrap();
+        RankProfile{
+            rank,
+            app_time: self.start.elapsed().as_secs_f64(),
+            mpi_time: state.mpi_time,
+            sites: state.sites.iter().map(|((name, site), stats)| (name.to_string(), site.clone(), *stats)).collect(),
+            comms: state.comms.iter().map(|(comm, stats)| (*comm, *stats)).collect(),
+            peers: state.peers.iter().map(|((comm, peer), stats)| (*comm, *peer, *stats)).collect(),
+        }
+    }
+
+    /// Gathers the profiles of all ranks and writes the report on rank 0. Called when the module
+    /// finalizes MPI, or by the embedder for modules that don't. Must be called by all ranks.
+    pub fn report(&self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.reported {
+                return;
+            }
+            state.reported = true;
+        }
+
+        let mut initialized = 0;
+        let mut finalized = 0;
+        unsafe {
+            mpi_sys::MPI_Initialized(&mut initialized);
+            mpi_sys::MPI_Finalized(&mut finalized);
+        }
+        if initialized == 0 || finalized != 0 {
+            return;
+        }
+
+        let mut rank = 0;
+        unsafe {
+            mpi_sys::MPI_Comm_rank(mpi_sys::RSMPI_COMM_WORLD, &mut rank);
+        }
+        let profile = serde_json::to_vec(&self.profile(rank)).expect("failed to serialize the profile");
+        let profiles = match gather(&profile) {
+            Some(profiles) => profiles,
+            None => return,
+        };
+        let profiles = profiles.iter()
+            .map(|profile| serde_json::from_slice::<RankProfile>(profile).expect("invalid profile of a rank"))
+            .collect();
+
+        let report = ProfileReport::new(profiles);
+        match &self.output {
+            ProfileOutput::Text => eprint!("{}", report.to_text()),
+            ProfileOutput::Json(path) => {
+                let json = serde_json::to_string_pretty(&report).expect("failed to serialize the profile report");
+                if let Err(e) = fs::write(path, json) {
+                    eprintln!("Could not write the profile to {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+
+impl Tool for Profiler {
+    fn before(&self, call: &mut MpiCall) -> CallAction {
+        // MPI can't be used to gather the profiles after the module finalized it
+        if call.name() == "MPI_Finalize" {
+            self.report();
+        }
+        self.state.lock().unwrap().current = Some(ProfiledCall{ site: call_site(), start: Instant::now() });
+        CallAction::Proceed
+    }
+
+    fn after(&self, call: &MpiCall, _ret: &mut ReturnValue) {
+        let mut state = self.state.lock().unwrap();
+        let ProfiledCall{ site, start } = match state.current.take() {
+            Some(current) => current,
+            None => return,
+        };
+        let time = start.elapsed().as_secs_f64();
+
+        let data = call.signature().send.or(call.signature().recv);
+        let bytes = data.map_or(0, |data| call.bytes(data));
+        let stats = CallStats{ calls: 1, bytes, time };
+
+        // The actual source of wildcard receives is only known from their status
+        let peer = match (call.dest(), call.source()) {
+            (Some(dest), _) => Some(dest),
+            (None, Some(MPI_ANY_SOURCE)) => call.status().map(|status| status.source),
+            (None, source) => source,
+        };
+
+        state.mpi_time += time;
+        state.sites.entry((call.name(), site)).or_default().add(&stats);
+        if let Some(comm) = call.comm() {
+            state.comms.entry(comm).or_default().add(&stats);
+            if let Some(peer) = peer {
+                state.peers.entry((comm, peer)).or_default().add(&stats);
+            }
+        }
+    }
+}
+
+
+/// Gathers a message of every rank on rank 0 of `MPI_COMM_WORLD`. Returns `None` on other ranks.
+pub(crate) fn gather(message: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut rank = 0;
+    let mut size = 0;
+    unsafe {
+        mpi_sys::MPI_Comm_rank(mpi_sys::RSMPI_COMM_WORLD, &mut rank);
+        mpi_sys::MPI_Comm_size(mpi_sys::RSMPI_COMM_WORLD, &mut size);
+    }
+
+    let mut len = message.len() as i32;
+    let mut lens = vec![0i32; if rank == 0 { size as usize } else { 0 }];
+    unsafe {
+        mpi_sys::MPI_Gather(
+            &mut len as *mut i32 as *mut c_void, 1, mpi_sys::RSMPI_INT32_T,
+            lens.as_mut_ptr() as *mut c_void, 1, mpi_sys::RSMPI_INT32_T,
+            0, mpi_sys::RSMPI_COMM_WORLD,
+        );
+    }
+
+    let mut displs = Vec::with_capacity(lens.len());
+    let mut total = 0;
+    for len in &lens {
+        displs.push(total);
+        total += len;
+    }
+    let mut messages = vec![0u8; total as usize];
+    unsafe {
+        mpi_sys::MPI_Gatherv(
+            message.as_ptr() as *mut c_void, len, mpi_sys::RSMPI_UINT8_T,
+            if rank == 0 { messages.as_mut_ptr() as *mut c_void } else { null_mut() },
+            lens.as_ptr(), displs.as_ptr(), mpi_sys::RSMPI_UINT8_T,
+            0, mpi_sys::RSMPI_COMM_WORLD,
+        );
+    }
+
+    if rank != 0 {
+        return None;
+    }
+    Some(lens.iter().zip(displs).map(|(len, displ)| messages[displ as usize..(displ + len) as usize].to_vec()).collect())
+}
@@ -0,0 +1,465 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use wasmer::{Array, WasmPtr};
+
+use crate::checks::world_rank;
+use crate::{CallAction, MpiCall, ReturnValue, Tool, MPI_ANY_SOURCE, MPI_ANY_TAG, MPI_REQUEST_NULL, MPI_SUCCESS, MPI_UNDEFINED};
+
+
+/// The nondeterministic outcomes of a run, each by its position among the events of its kind. A
+/// wildcard receive counts when it is posted, so nonblocking receives keep their position no matter
+/// when they complete.
+#[derive(Default)]
+struct Recording {
+    /// Source and tag of the message matched by a receive from `MPI_ANY_SOURCE` or with `MPI_ANY_TAG`
+    receives: HashMap<u64, (i32, i32)>,
+    /// Index of the request completed by `MPI_Waitany`
+    waitany: HashMap<u64, i32>,
+    /// Flag and index of `MPI_Testany`
+    testany: HashMap<u64, (i32, i32)>,
+    wtime: HashMap<u64, f64>,
+}
+impl Recording {
+    fn load(path: &Path) -> Result<Recording> {
+        let text = fs::read_to_string(path)?;
+        let mut recording = Recording::default();
+        for (number, line) in text.lines().enumerate() {
+            let invalid = || anyhow!("invalid line {} in {}: {}", number + 1, path.display(), line);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let int = |index: usize| fields.get(index).and_then(|field| field.parse::<i32>().ok()).ok_or_else(invalid);
+            let sequence = fields.get(1).and_then(|field| field.parse::<u64>().ok()).ok_or_else(invalid)?;
+            match fields[0] {
+                "recv" => {
+                    recording.receives.insert(sequence, (int(2)?, int(3)?));
+                }
+                "waitany" => {
+                    recording.waitany.insert(sequence, int(2)?);
+                }
+                "testany" => {
+                    recording.testany.insert(sequence, (int(2)?, int(3)?));
+                }
+                "wtime" => {
+                    let value = fields.get(2).and_then(|field| field.parse::<f64>().ok()).ok_or_else(invalid)?;
+                    recording.wtime.insert(sequence, value);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(recording)
+    }
+}
+
+
+#[derive(Clone, Copy, Default)]
+struct Sequences {
+    receives: u64,
+    waitany: u64,
+    testany: u64,
+    wtime: u64,
+}
+
+
+/// A call that is in progress
+#[derive(Default)]
+struct RecordedCall {
+    /// The wildcard receive that is posted or completed by the call
+    receive: Option<u64>,
+    /// The requests passed to `MPI_Waitany` and `MPI_Testany`
+    any_requests: Vec<i32>,
+}
+
+
+struct ReplayState {
+    /// `None` when replaying
+    log: Option<BufWriter<File>>,
+    recording: Recording,
+    next: Sequences,
+    /// Wildcard receives that were started by the module but not completed yet, by guest request
+    receives: HashMap<i32, u64>,
+    /// Guest memory that takes the status of receives whose status the module ignores
+    scratch_status: Option<i32>,
+    /// Allocating the status failed, such receives are left out of the recording
+    scratch_failed: bool,
+    diverged: bool,
+    current: RecordedCall,
+}
+impl ReplayState {
+    fn log(&mut self, line: std::fmt::Arguments) {
+        let written = match &mut self.log {
+            Some(log) => writeln!(log, "{}", line),
+            None => return,
+        };
+        if let Err(e) = written {
+            eprintln!("[rank {}] record: could not write the recording: {}", world_rank(), e);
+            self.log = None;
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(log) = &mut self.log {
+            if let Err(e) = log.flush() {
+                eprintln!("[rank {}] record: could not write the recording: {}", world_rank(), e);
+            }
+        }
+    }
+
+    /// Warns once that the run took a path that wasn't recorded, from then on calls are made as is
+    fn diverge(&mut self, event: &str, sequence: u64) {
+        if !self.diverged {
+            eprintln!("[rank {}] replay: the run diverged from the recording at {} {}, the rest is not replayed", world_rank(), event, sequence);
+            self.diverged = true;
+        }
+    }
+}
+
+
+/// Records the matching order of a rank, or forces a recorded one on a rerun: the source and tag
+/// matched by wildcard receives, the requests completed by `MPI_Waitany` and `MPI_Testany`, and
+/// the values of `MPI_Wtime`. Has to be registered after the other tools, so they see the calls it
+/// vetoes when replaying.
+pub struct Replayer {
+    state: Mutex<ReplayState>,
+}
+impl Replayer {
+    fn with(log: Option<BufWriter<File>>, recording: Recording) -> Replayer {
+        Replayer{
+            state: Mutex::new(ReplayState{
+                log,
+                recording,
+                next: Sequences::default(),
+                receives: HashMap::new(),
+                scratch_status: None,
+                scratch_failed: false,
+                diverged: false,
+                current: RecordedCall::default(),
+            }),
+        }
+    }
+
+    /// Records the run of this rank to a file
+    pub fn record(path: &Path) -> Result<Replayer> {
+        Ok(Replayer::with(Some(BufWriter::new(File::create(path)?)), Recording::default()))
+    }
+
+    /// Replays the recording of this rank from a file
+    pub fn replay(path: &Path) -> Result<Replayer> {
+        Ok(Replayer::with(None, Recording::load(path)?))
+    }
+
+    fn recording(&self) -> bool {
+        self.state.lock().unwrap().log.is_some()
+    }
+
+    /// Writes what was recorded so far, e.g. after a trap
+    pub fn finish(&self) {
+        self.state.lock().unwrap().flush();
+    }
+
+    /// Lets a receive whose status the module ignores write it to guest memory of the recorder. The
+    /// embedder makes sure that the module exports `malloc` before recording.
+    fn substitute_status(&self, call: &mut MpiCall) {
+        let index = match call.signature().status {
+            Some(index) if call.arg(index) == 0 => index,
+            _ => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.scratch_status.is_none() && !state.scratch_failed {
+            let status = call.env().malloc.get_ref()
+                .and_then(|malloc| malloc.call(size_of::<mpi_sys::MPI_Status>() as i32).ok());
+            match status {
+                Some(status) => state.scratch_status = Some(status.offset() as i32),
+                None => {
+                    eprintln!("[rank {}] record: could not allocate a status, receives with MPI_STATUS_IGNORE are not recorded", world_rank());
+                    state.scratch_failed = true;
+                }
+            }
+        }
+        if let Some(status) = state.scratch_status {
+            call.set_arg(index, status as i128);
+        }
+    }
+
+    fn before_record(&self, call: &mut MpiCall) {
+        let receive = self.state.lock().unwrap().current.receive;
+        let completes_receive = match call.name() {
+            "MPI_Wait" => receive.is_some(),
+            "MPI_Waitany" | "MPI_Testany" => {
+                let state = self.state.lock().unwrap();
+                state.current.any_requests.iter().any(|request| state.receives.contains_key(request))
+            }
+            _ => receive.is_some() && call.signature().request.is_none(),
+        };
+        if completes_receive {
+            self.substitute_status(call);
+        }
+    }
+
+    fn before_replay(&self, call: &mut MpiCall) -> CallAction {
+        let mut state = self.state.lock().unwrap();
+        if state.diverged {
+            return CallAction::Proceed;
+        }
+
+        if let Some(sequence) = state.current.receive {
+            match state.recording.receives.get(&sequence) {
+                Some(&(source, tag)) => {
+                    let signature = call.signature();
+                    let (source_index, tag_index) = (signature.source.unwrap(), signature.recv_tag.unwrap());
+                    call.set_arg(source_index, source as i128);
+                    call.set_arg(tag_index, tag as i128);
+                }
+                None => state.diverge("receive", sequence),
+            }
+        }
+
+        match call.name() {
+            "MPI_Wtime" => {
+                let sequence = state.next.wtime - 1;
+                match state.recording.wtime.get(&sequence) {
+                    Some(&value) => return CallAction::Return(ReturnValue::from_f64(value)),
+                    None => state.diverge("MPI_Wtime", sequence),
+                }
+            }
+            "MPI_Waitany" => {
+                let sequence = state.next.waitany - 1;
+                match state.recording.waitany.get(&sequence) {
+                    Some(&index) => return self.complete_recorded(&mut state, call, "MPI_Waitany", sequence, index),
+                    None => state.diverge("MPI_Waitany", sequence),
+                }
+            }
+            "MPI_Testany" => {
+                let sequence = state.next.testany - 1;
+                match state.recording.testany.get(&sequence) {
+                    // Nothing had completed yet, so nothing completes now either
+                    Some(&(0, _)) => {
+                        write_guest(call, 2, MPI_UNDEFINED);
+                        write_guest(call, 3, 0);
+                        return CallAction::Return(ReturnValue::from_code(MPI_SUCCESS));
+                    }
+                    Some(&(_, index)) => {
+                        let action = self.complete_recorded(&mut state, call, "MPI_Testany", sequence, index);
+                        if action != CallAction::Proceed {
+                            write_guest(call, 3, 1);
+                        }
+                        return action;
+                    }
+                    None => state.diverge("MPI_Testany", sequence),
+                }
+            }
+            _ => {}
+        }
+        CallAction::Proceed
+    }
+
+    /// Waits for the request that `MPI_Waitany` or `MPI_Testany` completed in the recording
+    fn complete_recorded(&self, state: &mut ReplayState, call: &MpiCall, name: &str, sequence: u64, index: i32) -> CallAction {
+        // No active requests, which the call finds out by itself
+        if index == MPI_UNDEFINED {
+            return CallAction::Proceed;
+        }
+        let requests = call.pointees(1, call.arg(0).max(0) as usize).unwrap_or_default();
+        if requests.get(index as usize).map_or(true, |&request| request == MPI_REQUEST_NULL) {
+            state.diverge(name, sequence);
+            return CallAction::Proceed;
+        }
+        let request = WasmPtr::<i32, Array>::new(call.arg(1) as u32 + 4 * index as u32);
+        let ret = crate::MPI_Waitany(call.env(), 1, request, WasmPtr::new(call.arg(2) as u32), WasmPtr::new(call.arg(call.signature().status.unwrap()) as u32));
+        write_guest(call, 2, index);
+        CallAction::Return(ReturnValue::from_code(ret))
+    }
+
+    fn after_record(&self, call: &MpiCall, ret: &ReturnValue) {
+        let mut state = self.state.lock().unwrap();
+        let current = std::mem::take(&mut state.current);
+        let name = call.name();
+
+        // Nonblocking receives are logged once their request completes
+        let mut completed = match (current.receive, name, call.signature().request) {
+            (Some(sequence), "MPI_Wait", _) | (Some(sequence), _, None) => Some(sequence),
+            (Some(sequence), _, Some(_)) => {
+                if let Some(request) = call.request() {
+                    state.receives.insert(request, sequence);
+                }
+                None
+            }
+            (None, _, _) => None,
+        };
+
+        match name {
+            "MPI_Wtime" => {
+                let sequence = state.next.wtime - 1;
+                state.log(format_args!("wtime {} {}", sequence, ret.to_f64()));
+            }
+            "MPI_Waitany" => {
+                let sequence = state.next.waitany - 1;
+                let index = call.pointee(2).unwrap_or(MPI_UNDEFINED);
+                state.log(format_args!("waitany {} {}", sequence, index));
+            }
+            "MPI_Testany" => {
+                let sequence = state.next.testany - 1;
+                let (index, flag) = (call.pointee(2).unwrap_or(MPI_UNDEFINED), call.pointee(3).unwrap_or(0));
+                state.log(format_args!("testany {} {} {}", sequence, flag, index));
+            }
+            _ => {}
+        }
+        if let Some(&request) = call.completed_index().and_then(|index| current.any_requests.get(index)) {
+            completed = state.receives.remove(&request);
+        }
+
+        if let (Some(sequence), Some(status)) = (completed, call.status()) {
+            state.log(format_args!("recv {} {} {}", sequence, status.source, status.tag));
+        }
+    }
+}
+
+
+impl Tool for Replayer {
+    fn before(&self, call: &mut MpiCall) -> CallAction {
+        let recording = self.recording();
+        {
+            let mut state = self.state.lock().unwrap();
+            let is_wildcard = call.signature().recv.is_some()
+                && (call.source() == Some(MPI_ANY_SOURCE) || call.recv_tag() == Some(MPI_ANY_TAG));
+            let receive = match call.name() {
+                "MPI_Wait" if recording => call.request().and_then(|request| state.receives.remove(&request)),
+                _ if is_wildcard => {
+                    state.next.receives += 1;
+                    Some(state.next.receives - 1)
+                }
+                _ => None,
+            };
+            let any_requests = call.any_requests().unwrap_or_default();
+            match call.name() {
+                "MPI_Wtime" => state.next.wtime += 1,
+                "MPI_Waitany" => state.next.waitany += 1,
+                "MPI_Testany" => state.next.testany += 1,
+                "MPI_Finalize" | "MPI_Abort" => state.flush(),
+                _ => {}
+            }
+            state.current = RecordedCall{ receive, any_requests };
+        }
+
+        match recording {
+            true => {
+                self.before_record(call);
+                CallAction::Proceed
+            }
+            false => self.before_replay(call),
+        }
+    }
+
+    fn after(&self, call: &MpiCall, ret: &mut ReturnValue) {
+        match self.recording() {
+            true => self.after_record(call, ret),
+            false => self.state.lock().unwrap().current = RecordedCall::default(),
+        }
+    }
+}
+
+
+/// Writes an integer that a pointer argument points to
+fn write_guest(call: &MpiCall, index: usize, value: i32) {
+    if let Some(memory) = call.env().memory.get_ref() {
+        if let Some(cell) = WasmPtr::<i32>::new(call.arg(index) as u32).deref(memory) {
+            cell.set(value);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::*;
+    use crate::Env;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("wasi-mpi-replay-{}-{}", process::id(), name))
+    }
+
+    fn recv_args(source: i32, tag: i32) -> [i128; 7] {
+        [64, 1, 0, source as i128, tag as i128, 0, 0]
+    }
+
+    #[test]
+    fn load_reads_every_kind_of_event() {
+        let path = temp_path("load");
+        fs::write(&path, "recv 0 3 7\nwaitany 0 2\ntestany 0 1 4\ntestany 1 0 -32766\nwtime 0 1.5\nrecv 1 2 0\n").unwrap();
+        let recording = Recording::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(recording.receives, HashMap::from([(0, (3, 7)), (1, (2, 0))]));
+        assert_eq!(recording.waitany, HashMap::from([(0, 2)]));
+        assert_eq!(recording.testany, HashMap::from([(0, (1, 4)), (1, (0, MPI_UNDEFINED))]));
+        assert_eq!(recording.wtime, HashMap::from([(0, 1.5)]));
+    }
+
+    #[test]
+    fn load_reports_the_invalid_line() {
+        let path = temp_path("invalid");
+        fs::write(&path, "wtime 0 1.5\nrecv 1 3\n").unwrap();
+        let error = Recording::load(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(error.to_string().starts_with("invalid line 2 in "), "{}", error);
+    }
+
+    #[test]
+    fn replayed_wtime_returns_the_recorded_values() {
+        let env = Env::new();
+        let path = temp_path("wtime");
+
+        let recorder = Replayer::record(&path).unwrap();
+        for value in [1.5, 2.25] {
+            let mut call = MpiCall::new(&env, "MPI_Wtime", &mut []);
+            assert_eq!(recorder.before(&mut call), CallAction::Proceed);
+            recorder.after(&call, &mut ReturnValue::from_f64(value));
+        }
+        recorder.finish();
+
+        let replayer = Replayer::replay(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        for value in [1.5, 2.25] {
+            let mut call = MpiCall::new(&env, "MPI_Wtime", &mut []);
+            assert_eq!(replayer.before(&mut call), CallAction::Return(ReturnValue::from_f64(value)));
+            replayer.after(&call, &mut ReturnValue::from_f64(0.0));
+        }
+    }
+
+    #[test]
+    fn replayed_wildcard_receives_take_the_recorded_source_and_tag() {
+        let env = Env::new();
+        let path = temp_path("receives");
+        fs::write(&path, "recv 0 3 7\nrecv 1 2 MPI_ANY_TAG\n".replace("MPI_ANY_TAG", "5")).unwrap();
+        let replayer = Replayer::replay(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut args = recv_args(MPI_ANY_SOURCE, MPI_ANY_TAG);
+        let mut call = MpiCall::new(&env, "MPI_Recv", &mut args);
+        assert_eq!(replayer.before(&mut call), CallAction::Proceed);
+        replayer.after(&call, &mut ReturnValue::from_code(MPI_SUCCESS));
+        assert_eq!((args[3], args[4]), (3, 7));
+
+        // Receives without wildcards are not part of the sequence and are left alone
+        let mut args = recv_args(1, 9);
+        let mut call = MpiCall::new(&env, "MPI_Recv", &mut args);
+        assert_eq!(replayer.before(&mut call), CallAction::Proceed);
+        replayer.after(&call, &mut ReturnValue::from_code(MPI_SUCCESS));
+        assert_eq!((args[3], args[4]), (1, 9));
+
+        let mut args = recv_args(2, MPI_ANY_TAG);
+        let mut call = MpiCall::new(&env, "MPI_Recv", &mut args);
+        assert_eq!(replayer.before(&mut call), CallAction::Proceed);
+        replayer.after(&call, &mut ReturnValue::from_code(MPI_SUCCESS));
+        assert_eq!((args[3], args[4]), (2, 5));
+    }
+}
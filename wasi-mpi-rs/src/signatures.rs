@@ -0,0 +1,131 @@
+use std::mem::size_of;
+
+use wasmer::WasmPtr;
+
+use crate::{guest_bytes, Env};
+
+/// Argument positions of a message buffer, its element count and its datatype
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DataArgs {
+    pub buf: usize,
+    pub count: usize,
+    pub datatype: usize,
+}
+
+
+/// Where the arguments of an MPI host function that tools care about are, e.g. to attribute the
+/// bytes of a call to a communicator and peer. Positions are indices into the guest arguments.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CallSignature {
+    /// All ranks of the communicator have to make the call
+    pub collective: bool,
+    pub send: Option<DataArgs>,
+    pub recv: Option<DataArgs>,
+    pub dest: Option<usize>,
+    pub send_tag: Option<usize>,
+    pub source: Option<usize>,
+    pub recv_tag: Option<usize>,
+    pub root: Option<usize>,
+    pub op: Option<usize>,
+    pub comm: Option<usize>,
+    /// Pointer to the request that is started or completed
+    pub request: Option<usize>,
+    /// Pointer to the status that is written on completion
+    pub status: Option<usize>,
+    /// Pointer to the communicator that is created
+    pub new_comm: Option<usize>,
+    /// Pointer to the group that is created
+    pub new_group: Option<usize>,
+}
+
+
+const fn data(buf: usize, count: usize, datatype: usize) -> Option<DataArgs> {
+    Some(DataArgs{ buf, count, datatype })
+}
+
+
+const NONE: CallSignature = CallSignature{
+    collective: false,
+    send: None,
+    recv: None,
+    dest: None,
+    send_tag: None,
+    source: None,
+    recv_tag: None,
+    root: None,
+    op: None,
+    comm: None,
+    request: None,
+    status: None,
+    new_comm: None,
+    new_group: None,
+};
+
+
+/// Returns the signature of a host function by its import name. Functions that don't move data
+/// only have their communicator described, if any.
+pub fn call_signature(name: &str) -> CallSignature {
+    match name {
+        "MPI_Allgather" | "MPI_Alltoall" => CallSignature{ collective: true, send: data(0, 1, 2), recv: data(3, 4, 5), comm: Some(6), ..NONE },
+        "MPI_Allreduce" | "MPI_Allreduce_c" => CallSignature{ collective: true, send: data(0, 2, 3), recv: data(1, 2, 3), op: Some(4), comm: Some(5), ..NONE },
+        "MPI_Alltoallv" => CallSignature{ collective: true, comm: Some(8), ..NONE },
+        "MPI_Barrier" => CallSignature{ collective: true, comm: Some(0), ..NONE },
+        "MPI_Bcast" | "MPI_Bcast_c" => CallSignature{ collective: true, send: data(0, 1, 2), recv: data(0, 1, 2), root: Some(3), comm: Some(4), ..NONE },
+        "MPI_Gather" | "MPI_Scatter" => CallSignature{ collective: true, send: data(0, 1, 2), recv: data(3, 4, 5), root: Some(6), comm: Some(7), ..NONE },
+        "MPI_Irecv" | "MPI_Irecv_c" => CallSignature{ recv: data(0, 1, 2), source: Some(3), recv_tag: Some(4), comm: Some(5), request: Some(6), ..NONE },
+        "MPI_Isend" | "MPI_Isend_c" => CallSignature{ send: data(0, 1, 2), dest: Some(3), send_tag: Some(4), comm: Some(5), request: Some(6), ..NONE },
+        "MPI_Recv" | "MPI_Recv_c" => CallSignature{ recv: data(0, 1, 2), source: Some(3), recv_tag: Some(4), comm: Some(5), status: Some(6), ..NONE },
+        "MPI_Reduce" | "MPI_Reduce_c" => CallSignature{ collective: true, send: data(0, 2, 3), recv: data(1, 2, 3), op: Some(4), root: Some(5), comm: Some(6), ..NONE },
+        "MPI_Send" | "MPI_Send_c" => CallSignature{ send: data(0, 1, 2), dest: Some(3), send_tag: Some(4), comm: Some(5), ..NONE },
+        "MPI_Sendrecv" => CallSignature{
+            send: data(0, 1, 2),
+            dest: Some(3),
+            send_tag: Some(4),
+            recv: data(5, 6, 7),
+            source: Some(8),
+            recv_tag: Some(9),
+            comm: Some(10),
+            status: Some(11),
+            ..NONE
+        },
+        "MPI_Testany" => CallSignature{ status: Some(4), ..NONE },
+        "MPI_Wait" => CallSignature{ request: Some(0), status: Some(1), ..NONE },
+        "MPI_Waitany" => CallSignature{ status: Some(3), ..NONE },
+        "MPI_Comm_create" => CallSignature{ collective: true, comm: Some(0), new_comm: Some(2), ..NONE },
+        "MPI_Comm_dup" => CallSignature{ collective: true, comm: Some(0), new_comm: Some(1), ..NONE },
+        "MPI_Comm_split" => CallSignature{ collective: true, comm: Some(0), new_comm: Some(3), ..NONE },
+        "MPI_Comm_split_type" => CallSignature{ collective: true, comm: Some(0), new_comm: Some(4), ..NONE },
+        "MPI_Comm_group" => CallSignature{ comm: Some(0), new_group: Some(1), ..NONE },
+        "MPI_Group_range_incl" => CallSignature{ new_group: Some(3), ..NONE },
+        "MPI_Abort" | "MPI_Comm_compare" | "MPI_Comm_delete_attr" | "MPI_Comm_get_attr" | "MPI_Comm_rank"
+        | "MPI_Comm_set_attr" | "MPI_Comm_size" => CallSignature{ comm: Some(0), ..NONE },
+        _ => NONE,
+    }
+}
+
+
+/// Source, tag and size of a received message
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MessageStatus {
+    pub source: i32,
+    pub tag: i32,
+    pub bytes: u64,
+}
+
+
+/// Reads a status that was written to guest memory by the host MPI, `None` for `MPI_STATUS_IGNORE`
+pub fn read_status(env: &Env, status: i32) -> Option<MessageStatus> {
+    if status == 0 {
+        return None;
+    }
+    let memory = env.memory.get_ref()?;
+    let status_ptr = guest_bytes(memory, WasmPtr::new(status as u32), size_of::<mpi_sys::MPI_Status>() as u64)? as *const mpi_sys::MPI_Status;
+
+    let mut bytes = 0;
+    // Guest pointers are not necessarily aligned for the host's status, so it is copied first
+    let host_status = unsafe { status_ptr.read_unaligned() };
+    unsafe {
+        mpi_sys::MPI_Get_count(&host_status, mpi_sys::RSMPI_UINT8_T, &mut bytes);
+    }
+    Some(MessageStatus{ source: host_status.MPI_SOURCE, tag: host_status.MPI_TAG, bytes: bytes.max(0) as u64 })
+}
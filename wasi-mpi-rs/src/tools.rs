@@ -0,0 +1,285 @@
+use std::sync::Arc;
+
+use wasmer::{Array, WasmCell, WasmPtr};
+
+use crate::{call_signature, read_status, CallSignature, DataArgs, Env, MessageStatus, MpiComm, MpiDatatype, MpiOp};
+
+
+/// The return value of an MPI call as Wasmer passes it. All calls return an error code except
+/// `MPI_Wtime` and `MPI_Wtick`, which return a time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReturnValue(pub i128);
+impl ReturnValue {
+    pub fn from_code(code: i32) -> ReturnValue {
+        ReturnValue(code as i128)
+    }
+
+    pub fn from_f64(value: f64) -> ReturnValue {
+        ReturnValue(value.to_bits() as i128)
+    }
+
+    pub fn code(self) -> i32 {
+        self.0 as i32
+    }
+
+    pub fn to_f64(self) -> f64 {
+        f64::from_bits(self.0 as u64)
+    }
+}
+
+
+/// What a tool decides before an MPI call is made
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CallAction {
+    /// Make the call with the (possibly modified) arguments
+    Proceed,
+    /// Skip the call and return this value to the module instead
+    Return(ReturnValue),
+}
+
+
+/// Interposes on the MPI calls of a module, like a PMPI tool. `before` is called in the order the
+/// tools were registered and `after` in the reverse order. A rank calls into MPI from one thread
+/// at a time, so state between `before` and `after` of a call can be kept in the tool.
+pub trait Tool: Send + Sync {
+    /// Can modify the arguments of the call, or veto it. The tools after one that vetoes a call
+    /// don't see it.
+    fn before(&self, _call: &mut MpiCall) -> CallAction {
+        CallAction::Proceed
+    }
+
+    /// Called after the call was made or vetoed, can modify its return value
+    fn after(&self, _call: &MpiCall, _ret: &mut ReturnValue) {}
+}
+
+
+/// The tools registered in an `Env`
+#[derive(Clone, Default)]
+pub struct ToolChain {
+    tools: Vec<Arc<dyn Tool>>,
+}
+impl ToolChain {
+    /// Tools have to be registered before the imports are created from the `Env`
+    pub fn push(&mut self, tool: Arc<dyn Tool>) {
+        self.tools.push(tool);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// Runs the `before` callbacks, then the call unless it is vetoed, then the `after` callbacks of
+    /// the tools that saw the call
+    pub(crate) fn intercept(&self, call: &mut MpiCall, make_call: impl FnOnce(&[i128]) -> ReturnValue) -> ReturnValue {
+        let mut seen = 0;
+        let mut action = CallAction::Proceed;
+        for tool in &self.tools {
+            seen += 1;
+            action = tool.before(call);
+            if action != CallAction::Proceed {
+                break;
+            }
+        }
+
+        let mut ret = match action {
+            CallAction::Proceed => make_call(&*call.args),
+            CallAction::Return(ret) => ret,
+        };
+        for tool in self.tools[..seen].iter().rev() {
+            tool.after(call, &mut ret);
+        }
+        ret
+    }
+}
+
+
+/// An MPI call of the module with its arguments decoded by the call's `CallSignature`. Positions
+/// are indices into the guest arguments.
+pub struct MpiCall<'a> {
+    env: &'a Env,
+    name: &'static str,
+    signature: CallSignature,
+    args: &'a mut [i128],
+}
+impl<'a> MpiCall<'a> {
+    pub(crate) fn new(env: &'a Env, name: &'static str, args: &'a mut [i128]) -> MpiCall<'a> {
+        MpiCall{ env, name, signature: call_signature(name), args }
+    }
+
+    pub fn env(&self) -> &'a Env {
+        self.env
+    }
+
+    /// The import name, e.g. `MPI_Send`
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn signature(&self) -> &CallSignature {
+        &self.signature
+    }
+
+    /// The guest arguments as passed by the module, pointers are offsets into its memory
+    pub fn args(&self) -> &[i128] {
+        self.args
+    }
+
+    pub fn arg(&self, index: usize) -> i32 {
+        self.args[index] as i32
+    }
+
+    /// Replaces an argument before the call is made
+    pub fn set_arg(&mut self, index: usize, value: i128) {
+        self.args[index] = value;
+    }
+
+    fn optional_arg(&self, index: Option<usize>) -> Option<i32> {
+        index.map(|index| self.arg(index))
+    }
+
+    /// The guest communicator handle
+    pub fn comm(&self) -> Option<i32> {
+        self.optional_arg(self.signature.comm)
+    }
+
+    pub fn host_comm(&self) -> Option<MpiComm> {
+        self.env.mpi_comms.get(self.comm()?)
+    }
+
+    pub fn dest(&self) -> Option<i32> {
+        self.optional_arg(self.signature.dest)
+    }
+
+    pub fn source(&self) -> Option<i32> {
+        self.optional_arg(self.signature.source)
+    }
+
+    pub fn send_tag(&self) -> Option<i32> {
+        self.optional_arg(self.signature.send_tag)
+    }
+
+    pub fn recv_tag(&self) -> Option<i32> {
+        self.optional_arg(self.signature.recv_tag)
+    }
+
+    pub fn root(&self) -> Option<i32> {
+        self.optional_arg(self.signature.root)
+    }
+
+    /// The guest op handle
+    pub fn op(&self) -> Option<i32> {
+        self.optional_arg(self.signature.op)
+    }
+
+    pub fn host_op(&self) -> Option<MpiOp> {
+        self.env.mpi_ops.get(self.op()?)
+    }
+
+    pub fn count(&self, data: DataArgs) -> i64 {
+        self.args[data.count] as i64
+    }
+
+    /// The guest datatype handle
+    pub fn datatype(&self, data: DataArgs) -> i32 {
+        self.arg(data.datatype)
+    }
+
+    pub fn host_datatype(&self, data: DataArgs) -> Option<MpiDatatype> {
+        self.env.mpi_datatypes.get(self.datatype(data))
+    }
+
+    /// Size of the data in bytes, as given by the count and the size of the datatype
+    pub fn bytes(&self, data: DataArgs) -> u64 {
+        let MpiDatatype(host_datatype) = match self.host_datatype(data) {
+            Some(host_datatype) => host_datatype,
+            None => return 0,
+        };
+        let mut size = 0;
+        unsafe {
+            mpi_sys::MPI_Type_size(host_datatype, &mut size);
+        }
+        self.count(data).max(0) as u64 * size.max(0) as u64
+    }
+
+    /// The guest memory of a buffer, `None` if it is out of bounds. Only contiguous datatypes cover
+    /// the whole slice.
+    pub fn buffer(&self, data: DataArgs) -> Option<Vec<WasmCell<'a, u8>>> {
+        let memory = self.env.memory.get_ref()?;
+        WasmPtr::<u8, Array>::new(self.arg(data.buf) as u32).deref(memory, 0, self.bytes(data) as u32)
+    }
+
+    pub fn send_buffer(&self) -> Option<Vec<WasmCell<'a, u8>>> {
+        self.buffer(self.signature.send?)
+    }
+
+    pub fn recv_buffer(&self) -> Option<Vec<WasmCell<'a, u8>>> {
+        self.buffer(self.signature.recv?)
+    }
+
+    /// Reads the integer that a pointer argument points to, e.g. a handle that is passed by pointer
+    pub fn pointee(&self, index: usize) -> Option<i32> {
+        let memory = self.env.memory.get_ref()?;
+        Some(WasmPtr::<i32>::new(self.arg(index) as u32).deref(memory)?.get())
+    }
+
+    /// Reads the integers that a pointer argument points to, e.g. the requests of `MPI_Waitany`
+    pub fn pointees(&self, index: usize, len: usize) -> Option<Vec<i32>> {
+        let memory = self.env.memory.get_ref()?;
+        let cells = WasmPtr::<i32, Array>::new(self.arg(index) as u32).deref(memory, 0, len as u32)?;
+        Some(cells.iter().map(|cell| cell.get()).collect())
+    }
+
+    /// The guest handles of the requests of `MPI_Waitany` and `MPI_Testany`
+    pub fn any_requests(&self) -> Option<Vec<i32>> {
+        match self.name {
+            "MPI_Waitany" | "MPI_Testany" => self.pointees(1, self.arg(0).max(0) as usize),
+            _ => None,
+        }
+    }
+
+    /// The index of the request that `MPI_Waitany` or `MPI_Testany` completed, only valid after
+    /// the call. `None` if no request completed.
+    pub fn completed_index(&self) -> Option<usize> {
+        let completed = match self.name {
+            "MPI_Waitany" => true,
+            "MPI_Testany" => self.pointee(3)? != 0,
+            _ => false,
+        };
+        match self.pointee(2)? {
+            index if completed && index >= 0 => Some(index as usize),
+            _ => None,
+        }
+    }
+
+    /// The guest handle of the request that is started or completed by the call. The handle of a
+    /// started request is only known after the call.
+    pub fn request(&self) -> Option<i32> {
+        self.pointee(self.signature.request?)
+    }
+
+    /// The guest handle of the communicator created by the call, only valid after the call
+    pub fn new_comm(&self) -> Option<i32> {
+        self.pointee(self.signature.new_comm?)
+    }
+
+    /// The guest handle of the group created by the call, only valid after the call
+    pub fn new_group(&self) -> Option<i32> {
+        self.pointee(self.signature.new_group?)
+    }
+
+    /// The status written by a completed receive, only valid after the call. `None` for
+    /// `MPI_STATUS_IGNORE`.
+    pub fn status(&self) -> Option<MessageStatus> {
+        read_status(self.env, self.optional_arg(self.signature.status)?)
+    }
+
+    /// The rank of this process in the call's communicator
+    pub fn comm_rank(&self) -> Option<i32> {
+        let MpiComm(host_comm) = self.host_comm()?;
+        let mut rank = -1;
+        unsafe {
+            mpi_sys::MPI_Comm_rank(host_comm, &mut rank);
+        }
+        Some(rank)
+    }
+}
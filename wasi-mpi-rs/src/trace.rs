@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use libc::c_void;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::{CallAction, Env, MpiCall, MpiComm, ReturnValue, Tool, MPI_ANY_SOURCE, MPI_ANY_TAG};
+
+
+/// An event in Chrome's trace event format, as read by Perfetto and chrome://tracing
+#[derive(Serialize)]
+struct TraceEvent {
+    name: String,
+    cat: &'static str,
+    ph: &'static str,
+    /// Microseconds since the tracer was created, shifted onto the clock of rank 0 when written
+    ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<f64>,
+    pid: i32,
+    tid: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bp: Option<&'static str>,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    args: Value,
+}
+impl TraceEvent {
+    fn new(ph: &'static str, cat: &'static str, name: &str, ts: Duration, args: Value) -> TraceEvent {
+        TraceEvent{
+            name: name.to_string(),
+            cat,
+            ph,
+            ts: micros(ts),
+            dur: None,
+            pid: 0,
+            tid: 0,
+            id: None,
+            bp: None,
+            args,
+        }
+    }
+}
+
+
+/// Messages between two ranks with the same tag are matched in order, so the n-th send and the
+/// n-th receive on a channel are the same message
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+struct Channel {
+    comm: i32,
+    source: i32,
+    dest: i32,
+    tag: i32,
+}
+
+
+/// A nonblocking receive that is matched when its request is completed
+#[derive(Clone, Copy)]
+struct PendingReceive {
+    comm: i32,
+    source: i32,
+    tag: i32,
+    bytes: u64,
+}
+
+
+struct TraceState {
+    events: Vec<TraceEvent>,
+    rank: i32,
+    /// Seconds between the start of the tracer on this rank and on rank 0, by their `MPI_Wtime`
+    clock_shift: f64,
+    wtime_offset: f64,
+    receives: HashMap<i32, PendingReceive>,
+    sequences: HashMap<Channel, u64>,
+    current: Option<TracedCall>,
+}
+
+
+/// A call that is in progress
+struct TracedCall {
+    start: Duration,
+    /// The receive completed by `MPI_Wait`, whose request is freed by the call
+    receive: Option<PendingReceive>,
+    /// The requests passed to `MPI_Waitany` and `MPI_Testany`, of which one may be completed
+    any_requests: Vec<i32>,
+}
+
+
+/// Records the phases, MPI calls, messages and I/O of a rank as a Chrome trace. Timestamps are
+/// aligned with rank 0 by `synchronize`, so the traces of all ranks can be viewed together.
+pub struct Tracer {
+    start: Instant,
+    state: Mutex<TraceState>,
+}
+impl Tracer {
+    pub fn new() -> Tracer {
+        Tracer{
+            start: Instant::now(),
+            state: Mutex::new(TraceState{
+                events: Vec::new(),
+                rank: 0,
+                clock_shift: 0.0,
+                wtime_offset: 0.0,
+                receives: HashMap::new(),
+                sequences: HashMap::new(),
+                current: None,
+            }),
+        }
+    }
+
+    /// Estimates the offset of this rank's `MPI_Wtime` to that of rank 0 right after a barrier.
+    /// Must be called by all ranks once MPI is initialized.
+    pub fn synchronize(&self) {
+        let mut rank = 0;
+        let mut clocks = [0.0f64; 2];
+        unsafe {
+            mpi_sys::MPI_Comm_rank(mpi_sys::RSMPI_COMM_WORLD, &mut rank);
+            mpi_sys::MPI_Barrier(mpi_sys::RSMPI_COMM_WORLD);
+            clocks[0] = mpi_sys::MPI_Wtime();
+        }
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let wtime = clocks[0];
+        // `MPI_Wtime` when the tracer was created
+        clocks[1] = wtime - elapsed;
+        unsafe {
+            mpi_sys::MPI_Bcast(clocks.as_mut_ptr() as *mut c_void, 2, mpi_sys::RSMPI_DOUBLE, 0, mpi_sys::RSMPI_COMM_WORLD);
+        }
+        let [root_wtime, root_start] = clocks;
+
+        let mut state = self.state.lock().unwrap();
+        state.rank = rank;
+        state.wtime_offset = root_wtime - wtime;
+        state.clock_shift = (wtime - elapsed) + state.wtime_offset - root_start;
+    }
+
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn push(&self, event: TraceEvent) {
+        self.state.lock().unwrap().events.push(event);
+    }
+
+    /// Records a span of the embedder, e.g. compiling the module or a WASI call
+    pub fn complete(&self, cat: &'static str, name: &str, start: Instant, args: Value) {
+        let start = start.saturating_duration_since(self.start);
+        let mut event = TraceEvent::new("X", cat, name, start, args);
+        event.dur = Some(micros(self.now().saturating_sub(start)));
+        self.push(event);
+    }
+
+    /// Records a matched message and the start or end of the flow arrow between sender and receiver.
+    /// `peer` is the destination of sends and the source of receives.
+    fn message(&self, env: &Env, ts: Duration, comm: i32, send: bool, peer: i32, tag: i32, bytes: u64) {
+        let rank = comm_rank(env, comm);
+        let (source, dest) = if send { (rank, peer) } else { (peer, rank) };
+        let args = json!({ "comm": comm, "source": source, "dest": dest, "tag": tag, "bytes": bytes });
+
+        let mut state = self.state.lock().unwrap();
+        let mut event = TraceEvent::new("i", "match", if send { "send" } else { "recv" }, ts, args);
+        event.bp = Some("t");
+        state.events.push(event);
+
+        // The actual source and tag of wildcard receives are unknown without a status
+        if source == MPI_ANY_SOURCE || tag == MPI_ANY_TAG {
+            return;
+        }
+        let channel = Channel{ comm, source, dest, tag };
+        let sequence = state.sequences.entry(channel).or_insert(0);
+        let id = format!("{}:{}:{}:{}:{}", comm, source, dest, tag, sequence);
+        *sequence += 1;
+
+        let mut flow = TraceEvent::new(if send { "s" } else { "f" }, "message", "message", ts, Value::Null);
+        flow.id = Some(id);
+        flow.bp = Some("e");
+        state.events.push(flow);
+    }
+
+    /// Writes the trace in Chrome's JSON format, with timestamps on the clock of rank 0
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let rank = state.rank;
+        let shift = state.clock_shift * 1e6;
+
+        let mut events = vec![
+            TraceEvent::new("M", "__metadata", "process_name", Duration::from_secs(0), json!({ "name": format!("rank {}", rank) })),
+            TraceEvent::new("M", "__metadata", "process_sort_index", Duration::from_secs(0), json!({ "sort_index": rank })),
+        ];
+        events.append(&mut state.events);
+        for event in &mut events {
+            event.pid = rank;
+            if event.ph != "M" {
+                event.ts += shift;
+            }
+        }
+
+        let mut other_data = Map::new();
+        other_data.insert("rank".to_string(), json!(rank));
+        other_data.insert("wtime_offset".to_string(), json!(state.wtime_offset));
+        let trace = json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ms",
+            "otherData": other_data,
+        });
+        fs::write(path, serde_json::to_vec(&trace)?)?;
+        Ok(())
+    }
+}
+
+
+impl Tool for Tracer {
+    fn before(&self, call: &mut MpiCall) -> CallAction {
+        // The request is freed by the call, so the receive is looked up beforehand
+        let receive = match call.name() {
+            "MPI_Wait" => call.request().and_then(|request| self.state.lock().unwrap().receives.remove(&request)),
+            _ => None,
+        };
+
+        let any_requests = call.any_requests().unwrap_or_default();
+
+        let start = self.now();
+        let mut state = self.state.lock().unwrap();
+        state.events.push(TraceEvent::new("B", "mpi", call.name(), start, call_args(call)));
+        state.current = Some(TracedCall{ start, receive, any_requests });
+        CallAction::Proceed
+    }
+
+    fn after(&self, call: &MpiCall, ret: &mut ReturnValue) {
+        let end = self.now();
+        let traced = match self.state.lock().unwrap().current.take() {
+            Some(traced) => traced,
+            None => return,
+        };
+        // Flow events are bound to the enclosing call, so they are placed inside of it
+        let during = traced.start + (end - traced.start) / 2;
+        let env = call.env();
+        let signature = call.signature();
+
+        if let (Some(comm), Some(dest), Some(tag), Some(data)) = (call.comm(), call.dest(), call.send_tag(), signature.send) {
+            self.message(env, during, comm, true, dest, tag, call.bytes(data));
+        }
+
+        match (call.comm(), call.source(), signature.recv, signature.request) {
+            // Nonblocking receives are matched by `MPI_Wait`, `MPI_Waitany` or `MPI_Testany`
+            (Some(comm), Some(source), Some(data), Some(_)) => {
+                if let Some(request) = call.request() {
+                    let tag = call.recv_tag().unwrap_or(MPI_ANY_TAG);
+                    let receive = PendingReceive{ comm, source, tag, bytes: call.bytes(data) };
+                    self.state.lock().unwrap().receives.insert(request, receive);
+                }
+            }
+            (Some(comm), Some(source), Some(data), None) => {
+                let tag = call.recv_tag().unwrap_or(MPI_ANY_TAG);
+                let (source, tag, bytes) = match call.status() {
+                    Some(status) => (status.source, status.tag, status.bytes),
+                    None => (source, tag, call.bytes(data)),
+                };
+                self.message(env, during, comm, false, source, tag, bytes);
+            }
+            _ => {}
+        }
+
+        // The request completed by `MPI_Waitany` or `MPI_Testany` is only known after the call
+        let mut receive = traced.receive;
+        if let Some(&request) = call.completed_index().and_then(|index| traced.any_requests.get(index)) {
+            receive = self.state.lock().unwrap().receives.remove(&request);
+        }
+
+        if let Some(receive) = receive {
+            let (source, tag, bytes) = match call.status() {
+                Some(status) => (status.source, status.tag, status.bytes),
+                None => (receive.source, receive.tag, receive.bytes),
+            };
+            self.message(env, during, receive.comm, false, source, tag, bytes);
+        }
+
+        let ret = match call.name() {
+            "MPI_Wtime" | "MPI_Wtick" => json!(ret.to_f64()),
+            _ => json!(ret.code()),
+        };
+        self.push(TraceEvent::new("E", "mpi", call.name(), end, json!({ "return": ret })));
+    }
+}
+
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1e6
+}
+
+
+fn comm_rank(env: &Env, comm: i32) -> i32 {
+    let MpiComm(host_comm) = match env.mpi_comms.get(comm) {
+        Some(host_comm) => host_comm,
+        None => return -1,
+    };
+    let mut rank = -1;
+    unsafe {
+        mpi_sys::MPI_Comm_rank(host_comm, &mut rank);
+    }
+    rank
+}
+
+
+/// Names the arguments of a call that are described by its signature, the others are only listed
+fn call_args(call: &MpiCall) -> Value {
+    let mut named = Map::new();
+    let mut name = |key: &str, value: Option<i32>| {
+        if let Some(value) = value {
+            named.insert(key.to_string(), json!(value));
+        }
+    };
+    name("comm", call.comm());
+    name("dest", call.dest());
+    name("source", call.source());
+    name("sendtag", call.send_tag());
+    name("recvtag", call.recv_tag());
+    name("root", call.root());
+    name("op", call.op());
+    if let Some(data) = call.signature().send {
+        named.insert("sendcount".to_string(), json!(call.count(data)));
+        named.insert("sendbytes".to_string(), json!(call.bytes(data)));
+    }
+    if let Some(data) = call.signature().recv {
+        named.insert("recvcount".to_string(), json!(call.count(data)));
+        named.insert("recvbytes".to_string(), json!(call.bytes(data)));
+    }
+    named.insert("args".to_string(), json!(call.args().iter().map(|&arg| arg as i64).collect::<Vec<_>>()));
+    Value::Object(named)
+}
@@ -0,0 +1,66 @@
+#include <cassert>
+#include <mpi.h>
+
+static int deleted = 0;
+
+int copy_attr(MPI_Comm comm, int keyval, void *extra_state, void *attribute_val_in, void *attribute_val_out, int *flag) {
+    *(void **) attribute_val_out = attribute_val_in;
+    *flag = 1;
+    return MPI_SUCCESS;
+}
+
+int failing_copy_attr(MPI_Comm comm, int keyval, void *extra_state, void *attribute_val_in, void *attribute_val_out, int *flag) {
+    return MPI_ERR_OTHER;
+}
+
+int delete_attr(MPI_Comm comm, int keyval, void *attribute_val, void *extra_state) {
+    deleted++;
+    return MPI_SUCCESS;
+}
+
+int main(int argc, char *argv[]) {
+    MPI_Init(&argc, &argv);
+
+    int *tag_ub;
+    int flag;
+    MPI_Comm_get_attr(MPI_COMM_WORLD, MPI_TAG_UB, &tag_ub, &flag);
+    assert(flag);
+    assert(*tag_ub >= 32767);
+
+    int keyval;
+    int value = 42;
+    MPI_Comm_create_keyval(copy_attr, delete_attr, &keyval, nullptr);
+    MPI_Comm_set_attr(MPI_COMM_WORLD, keyval, &value);
+
+    MPI_Comm dup;
+    MPI_Comm_dup(MPI_COMM_WORLD, &dup);
+
+    int *attr;
+    MPI_Comm_get_attr(dup, keyval, &attr, &flag);
+    assert(flag);
+    assert(*attr == 42);
+
+    MPI_Comm_free(&dup);
+    assert(deleted == 1);
+
+    MPI_Comm_delete_attr(MPI_COMM_WORLD, keyval);
+    assert(deleted == 2);
+    MPI_Comm_get_attr(MPI_COMM_WORLD, keyval, &attr, &flag);
+    assert(!flag);
+
+    MPI_Comm_free_keyval(&keyval);
+    assert(keyval == MPI_KEYVAL_INVALID);
+
+    // A failing copy callback fails the duplication with its error code
+    int failing_keyval;
+    MPI_Comm_create_keyval(failing_copy_attr, MPI_COMM_NULL_DELETE_FN, &failing_keyval, nullptr);
+    MPI_Comm_set_attr(MPI_COMM_WORLD, failing_keyval, &value);
+    MPI_Comm failed;
+    assert(MPI_Comm_dup(MPI_COMM_WORLD, &failed) == MPI_ERR_OTHER);
+    assert(failed == MPI_COMM_NULL);
+    MPI_Comm_delete_attr(MPI_COMM_WORLD, failing_keyval);
+    MPI_Comm_free_keyval(&failing_keyval);
+
+    MPI_Finalize();
+    return 0;
+}
@@ -0,0 +1,80 @@
+#include <cassert>
+#include <exception>
+#include <stdexcept>
+#include <mpi.h>
+
+static int destructed = 0;
+
+struct Guard {
+    ~Guard() {
+        destructed++;
+    }
+};
+
+struct RankError : std::runtime_error {
+    int rank;
+
+    RankError(int rank) : std::runtime_error("rank error"), rank(rank) {}
+};
+
+static void throw_from_rank(int rank) {
+    Guard guard;
+    throw RankError(rank);
+}
+
+static void rethrow_from_rank(int rank) {
+    try {
+        throw_from_rank(rank);
+    } catch (const RankError&) {
+        throw;
+    }
+}
+
+int main(int argc, char *argv[]) {
+    MPI_Init(&argc, &argv);
+
+    int rank;
+    MPI_Comm_rank(MPI_COMM_WORLD, &rank);
+
+    // Exact type
+    try {
+        throw_from_rank(rank);
+        assert(false);
+    } catch (const RankError& e) {
+        assert(e.rank == rank);
+    }
+    assert(destructed == 1);
+
+    // Base class, after a rethrow
+    try {
+        rethrow_from_rank(rank);
+        assert(false);
+    } catch (const std::exception& e) {
+        assert(dynamic_cast<const RankError*>(&e) != nullptr);
+    }
+    assert(destructed == 2);
+
+    // Pointer and catch-all clauses
+    int value = 42;
+    try {
+        throw &value;
+    } catch (const char*) {
+        assert(false);
+    } catch (int* ptr) {
+        assert(*ptr == 42);
+    }
+
+    try {
+        throw 1.0;
+    } catch (int) {
+        assert(false);
+    } catch (...) {
+    }
+
+    // MPI calls keep working after an exception was handled
+    int sum;
+    MPI_Allreduce(&rank, &sum, 1, MPI_INT, MPI_SUM, MPI_COMM_WORLD);
+
+    MPI_Finalize();
+    return 0;
+}
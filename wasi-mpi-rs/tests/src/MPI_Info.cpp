@@ -0,0 +1,41 @@
+#include <cassert>
+#include <cstring>
+#include <mpi.h>
+
+int main(int argc, char *argv[]) {
+    MPI_Init(&argc, &argv);
+
+    MPI_Info info;
+    MPI_Info_create(&info);
+    MPI_Info_set(info, "wasi_mpi_rs_key", "value");
+
+    MPI_Info dup;
+    MPI_Info_dup(info, &dup);
+    MPI_Info_free(&info);
+    assert(info == MPI_INFO_NULL);
+
+    int nkeys;
+    MPI_Info_get_nkeys(dup, &nkeys);
+    assert(nkeys >= 1);
+
+    bool found = false;
+    char key[MPI_MAX_INFO_KEY + 1];
+    for (int i = 0; i < nkeys; i++) {
+        MPI_Info_get_nthkey(dup, i, key);
+        found |= strcmp(key, "wasi_mpi_rs_key") == 0;
+    }
+    assert(found);
+
+    char value[MPI_MAX_INFO_VAL + 1];
+    int flag;
+    MPI_Info_get(dup, "wasi_mpi_rs_key", MPI_MAX_INFO_VAL, value, &flag);
+    assert(flag);
+    assert(strcmp(value, "value") == 0);
+
+    MPI_Info_get(dup, "missing_key", MPI_MAX_INFO_VAL, value, &flag);
+    assert(!flag);
+
+    MPI_Info_free(&dup);
+    MPI_Finalize();
+    return 0;
+}
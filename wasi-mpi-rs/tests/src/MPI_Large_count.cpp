@@ -0,0 +1,76 @@
+#include <array>
+#include <cassert>
+#include <mpi.h>
+
+const int SEND_RANK = 0;
+const int RECV_RANK = 1;
+
+int main(int argc, char *argv[]) {
+    MPI_Init(&argc, &argv);
+
+    int rank, size;
+    MPI_Comm_rank(MPI_COMM_WORLD, &rank);
+    MPI_Comm_size(MPI_COMM_WORLD, &size);
+
+    MPI_Count type_size;
+    MPI_Type_size_c(MPI_DOUBLE, &type_size);
+    assert(type_size == sizeof(double));
+
+    if (rank == SEND_RANK) {
+        std::array<double, 4> data{0.5, 1.5, 2.5, 3.5};
+        MPI_Send_c(data.data(), data.size(), MPI_DOUBLE, RECV_RANK, 0, MPI_COMM_WORLD);
+
+        MPI_Request request;
+        MPI_Status status;
+        MPI_Isend_c(data.data(), 2, MPI_DOUBLE, RECV_RANK, 1, MPI_COMM_WORLD, &request);
+        MPI_Wait(&request, &status);
+    } else if (rank == RECV_RANK) {
+        std::array<double, 4> data{};
+        MPI_Status status;
+        MPI_Recv_c(data.data(), data.size(), MPI_DOUBLE, SEND_RANK, 0, MPI_COMM_WORLD, &status);
+        assert((data == std::array<double, 4>{0.5, 1.5, 2.5, 3.5}));
+
+        MPI_Count count;
+        MPI_Get_count_c(&status, MPI_DOUBLE, &count);
+        assert(count == 4);
+
+        MPI_Request request;
+        data = {};
+        MPI_Irecv_c(data.data(), data.size(), MPI_DOUBLE, SEND_RANK, 1, MPI_COMM_WORLD, &request);
+        MPI_Wait(&request, &status);
+        MPI_Get_count_c(&status, MPI_DOUBLE, &count);
+        assert(count == 2);
+        assert((data == std::array<double, 4>{0.5, 1.5, 0, 0}));
+    }
+
+    std::array<int, 3> values{};
+    if (rank == 0) {
+        values = {1, 2, 3};
+    }
+    MPI_Bcast_c(values.data(), values.size(), MPI_INT, 0, MPI_COMM_WORLD);
+    assert((values == std::array<int, 3>{1, 2, 3}));
+
+    std::array<int, 3> sums{};
+    MPI_Allreduce_c(values.data(), sums.data(), values.size(), MPI_INT, MPI_SUM, MPI_COMM_WORLD);
+    assert((sums == std::array<int, 3>{size, 2 * size, 3 * size}));
+
+    sums = {};
+    MPI_Reduce_c(values.data(), sums.data(), values.size(), MPI_INT, MPI_SUM, 0, MPI_COMM_WORLD);
+    if (rank == 0) {
+        assert((sums == std::array<int, 3>{size, 2 * size, 3 * size}));
+    }
+
+    sums = values;
+    MPI_Allreduce_c(MPI_IN_PLACE, sums.data(), sums.size(), MPI_INT, MPI_SUM, MPI_COMM_WORLD);
+    assert((sums == std::array<int, 3>{size, 2 * size, 3 * size}));
+
+    // Only the root may reduce in place
+    sums = values;
+    MPI_Reduce_c(rank == 0 ? MPI_IN_PLACE : sums.data(), sums.data(), sums.size(), MPI_INT, MPI_SUM, 0, MPI_COMM_WORLD);
+    if (rank == 0) {
+        assert((sums == std::array<int, 3>{size, 2 * size, 3 * size}));
+    }
+
+    MPI_Finalize();
+    return 0;
+}
@@ -0,0 +1,61 @@
+#include <array>
+#include <cassert>
+#include <mpi.h>
+
+const int SEND_RANK = 0;
+const int RECV_RANK = 1;
+
+int main(int argc, char *argv[]) {
+    MPI_Init(&argc, &argv);
+
+    int rank;
+    MPI_Comm_rank(MPI_COMM_WORLD, &rank);
+
+    int int_size, double_size;
+    MPI_Pack_size(4, MPI_INT, MPI_COMM_WORLD, &int_size);
+    MPI_Pack_size(2, MPI_DOUBLE, MPI_COMM_WORLD, &double_size);
+    const int buf_size = int_size + double_size;
+    std::array<char, 256> buf{};
+    assert(buf_size <= (int) buf.size());
+
+    if (rank == SEND_RANK) {
+        std::array<int, 4> ints{0, 1, 2, 3};
+        std::array<double, 2> doubles{0.5, 1.5};
+
+        int position = 0;
+        MPI_Pack(ints.data(), ints.size(), MPI_INT, buf.data(), buf_size, &position, MPI_COMM_WORLD);
+        MPI_Pack(doubles.data(), doubles.size(), MPI_DOUBLE, buf.data(), buf_size, &position, MPI_COMM_WORLD);
+        MPI_Send(buf.data(), position, MPI_BYTE, RECV_RANK, 0, MPI_COMM_WORLD);
+    } else if (rank == RECV_RANK) {
+        std::array<int, 4> ints{};
+        std::array<double, 2> doubles{};
+        MPI_Status status;
+
+        MPI_Recv(buf.data(), buf_size, MPI_BYTE, SEND_RANK, 0, MPI_COMM_WORLD, &status);
+        int position = 0;
+        MPI_Unpack(buf.data(), buf_size, &position, ints.data(), ints.size(), MPI_INT, MPI_COMM_WORLD);
+        MPI_Unpack(buf.data(), buf_size, &position, doubles.data(), doubles.size(), MPI_DOUBLE, MPI_COMM_WORLD);
+        assert((ints == std::array<int, 4>{0, 1, 2, 3}));
+        assert((doubles == std::array<double, 2>{0.5, 1.5}));
+    }
+
+    // external32 is big-endian
+    int value = 0x01020304;
+    MPI_Aint external_size;
+    MPI_Pack_external_size("external32", 1, MPI_INT, &external_size);
+    assert(external_size == 4);
+
+    std::array<unsigned char, 4> external{};
+    MPI_Aint position = 0;
+    MPI_Pack_external("external32", &value, 1, MPI_INT, external.data(), external.size(), &position);
+    assert(position == 4);
+    assert((external == std::array<unsigned char, 4>{1, 2, 3, 4}));
+
+    int unpacked = 0;
+    position = 0;
+    MPI_Unpack_external("external32", external.data(), external.size(), &position, &unpacked, 1, MPI_INT);
+    assert(unpacked == value);
+
+    MPI_Finalize();
+    return 0;
+}